@@ -8,7 +8,7 @@ import 'package:nadekodon/utils/helper.dart';
 import 'package:rinf/rinf.dart';
 import 'package:nadekodon/src/bindings/bindings.dart';
 
-enum DownloadStatus { queued, running, paused, completed, cancelled, failed }
+enum DownloadStatus { queued, scheduled, blocked, running, recording, paused, completed, cancelled, failed }
 
 DownloadStatus parseDownloadStatus(String state) {
   final s = state.toLowerCase();
@@ -16,8 +16,14 @@ DownloadStatus parseDownloadStatus(String state) {
   switch (s) {
     case 'queued':
       return DownloadStatus.queued;
+    case 'scheduled':
+      return DownloadStatus.scheduled;
+    case 'blocked':
+      return DownloadStatus.blocked;
     case 'running':
       return DownloadStatus.running;
+    case 'recording':
+      return DownloadStatus.recording;
     case 'paused':
       return DownloadStatus.paused;
     case 'completed':
@@ -38,6 +44,10 @@ class DownloadItem {
   final int? total;
   final DownloadStatus status;
   final double speed;
+  final int? partsDone;
+  final int? partsTotal;
+  final String queue;
+  final String? errorKind;
 
   const DownloadItem({
     required this.id,
@@ -46,10 +56,17 @@ class DownloadItem {
     required this.total,
     required this.status,
     required this.speed,
+    this.partsDone,
+    this.partsTotal,
+    this.queue = 'default',
+    this.errorKind,
   });
 
-  double get progress =>
-      (total != null && total! > 0) ? downloaded / total! : 0.0;
+  double get progress {
+    if (total != null && total! > 0) return downloaded / total!;
+    if (partsTotal != null && partsTotal! > 0) return (partsDone ?? 0) / partsTotal!;
+    return 0.0;
+  }
 }
 
 class DownloadPage extends StatelessWidget {
@@ -57,7 +74,10 @@ class DownloadPage extends StatelessWidget {
 
   static const activeStatuses = {
     DownloadStatus.queued,
+    DownloadStatus.scheduled,
+    DownloadStatus.blocked,
     DownloadStatus.running,
+    DownloadStatus.recording,
     DownloadStatus.paused,
   };
 
@@ -113,6 +133,10 @@ class DownloadPage extends StatelessWidget {
                 total: d.totalSize?.toInt(),
                 status: status,
                 speed:d.speed,
+                partsDone: d.partsDone?.toInt(),
+                partsTotal: d.partsTotal?.toInt(),
+                queue: d.queue,
+                errorKind: d.errorKind,
               );
             }).toList();
 
@@ -163,6 +187,7 @@ class DownloadPage extends StatelessWidget {
           item: items[index],
           onPauseResume: () {
             if (items[index].status == DownloadStatus.running ||
+                items[index].status == DownloadStatus.recording ||
                 items[index].status == DownloadStatus.queued) {
                 PauseDownload(id: items[index].id).sendSignalToRust();
             } else {
@@ -173,7 +198,14 @@ class DownloadPage extends StatelessWidget {
             if ( activeStatuses.contains(items[index].status)) {
               CancelDownload(id: items[index].id).sendSignalToRust();
             }
-          }
+          },
+          onStopRecording: () {
+            StopRecording(id: items[index].id).sendSignalToRust();
+          },
+          onMove: (direction) {
+            MoveDownload(id: items[index].id, direction: direction)
+                .sendSignalToRust();
+          },
         );
       },
     );
@@ -184,21 +216,29 @@ class DownloadTile extends StatelessWidget {
   final DownloadItem item;
   final VoidCallback onPauseResume;
   final VoidCallback onCancel;
+  final VoidCallback onStopRecording;
+  final ValueChanged<String> onMove;
 
   const DownloadTile({
     super.key,
     required this.item,
     required this.onPauseResume,
     required this.onCancel,
+    required this.onStopRecording,
+    required this.onMove,
   });
 
   Color _progressColor(BuildContext context) {
     final colors = Theme.of(context).colorScheme;
     switch (item.status) {
       case DownloadStatus.queued:
+      case DownloadStatus.scheduled:
+      case DownloadStatus.blocked:
         return colors.tertiary;
       case DownloadStatus.running:
         return colors.primary;
+      case DownloadStatus.recording:
+        return colors.error;
       case DownloadStatus.paused:
         return colors.secondary;
       case DownloadStatus.completed:
@@ -236,8 +276,15 @@ class DownloadTile extends StatelessWidget {
                   child: Text(item.name,
                       style: textTheme.bodyMedium),
                 ),
+                if (item.queue != 'default')
+                  Padding(
+                    padding: const EdgeInsets.only(right: AppTheme.spaceSM),
+                    child: Text(item.queue, style: textTheme.bodySmall),
+                  ),
                 Text(
-                  item.status.name.toUpperCase(),
+                  item.errorKind != null
+                      ? "${item.status.name.toUpperCase()} · ${item.errorKind!.toUpperCase()}"
+                      : item.status.name.toUpperCase(),
                   style: textTheme.bodyMedium?.copyWith(
                     fontWeight: FontWeight.bold,
                     color: _progressColor(context),
@@ -262,7 +309,9 @@ class DownloadTile extends StatelessWidget {
                     child: Text(
                       item.total != null
                           ? "${formatBytes(item.downloaded)} / ${formatBytes(item.total!)}"
-                          : formatBytes(item.downloaded),
+                          : item.partsTotal != null
+                              ? "${formatBytes(item.downloaded)} · ${item.partsDone ?? 0} / ${item.partsTotal} segments"
+                              : formatBytes(item.downloaded),
                       style: textTheme.bodySmall,
                     ),
                   ),
@@ -282,6 +331,12 @@ class DownloadTile extends StatelessWidget {
                     child: Row(
                       mainAxisSize: MainAxisSize.min,
                       children: [
+                        if (item.status == DownloadStatus.recording)
+                          IconButton(
+                            icon: Icon(Icons.stop_circle),
+                            iconSize: AppTheme.iconMD * AppTheme.iconScale(context),
+                            onPressed: onStopRecording,
+                          ),
                         IconButton(
                           icon: Icon(Icons.stop),
                           iconSize: AppTheme.iconMD * AppTheme.iconScale(context),
@@ -290,6 +345,7 @@ class DownloadTile extends StatelessWidget {
                         IconButton(
                           icon: Icon(
                             (item.status == DownloadStatus.running ||
+                                    item.status == DownloadStatus.recording ||
                                     item.status == DownloadStatus.queued)
                                 ? Icons.pause
                                 : Icons.play_arrow,
@@ -297,6 +353,18 @@ class DownloadTile extends StatelessWidget {
                           iconSize: AppTheme.iconMD * AppTheme.iconScale(context),
                           onPressed: onPauseResume,
                         ),
+                        if (DownloadPage.activeStatuses.contains(item.status))
+                          PopupMenuButton<String>(
+                            icon: Icon(Icons.swap_vert),
+                            iconSize: AppTheme.iconMD * AppTheme.iconScale(context),
+                            onSelected: onMove,
+                            itemBuilder: (context) => const [
+                              PopupMenuItem(value: 'Top', child: Text('Move to top')),
+                              PopupMenuItem(value: 'Up', child: Text('Move up')),
+                              PopupMenuItem(value: 'Down', child: Text('Move down')),
+                              PopupMenuItem(value: 'Bottom', child: Text('Move to bottom')),
+                            ],
+                          ),
                       ]
                     )
                   ),
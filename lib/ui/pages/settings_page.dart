@@ -4,6 +4,7 @@ import 'package:flutter/material.dart';
 import '../../theme/app_theme.dart';
 import '../widgets/settings/download_folder_tile.dart';
 import '../widgets/settings/server_port_spinbox.dart';
+import '../widgets/settings/server_token_tile.dart';
 import '../widgets/settings/speed_limit_spinbox.dart';
 import '../widgets/settings/download_thread_spinbox.dart';
 import '../widgets/settings/concurrency_limit_spinbox.dart';
@@ -32,6 +33,7 @@ class SettingsPage extends StatelessWidget {
               children: [
                 DownloadFolderTile(),
                 PortSpinBox(),
+                ServerTokenTile(),
                 SpeedLimitSpinBox(),
                 DownloadThreadBox(),
                 ConcurrencyLimitBox(),
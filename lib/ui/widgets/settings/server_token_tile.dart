@@ -0,0 +1,56 @@
+// lib/ui/widgets/settings/server_token_tile.dart
+import 'package:flutter/material.dart';
+import 'package:flutter/services.dart';
+
+import '../../../theme/app_theme.dart';
+import '../../../utils/settings.dart';
+
+class ServerTokenTile extends StatelessWidget {
+  const ServerTokenTile({super.key});
+
+  @override
+  Widget build(BuildContext context) {
+    return ValueListenableBuilder<String>(
+      valueListenable: SettingsManager.serverToken,
+      builder: (context, value, _) {
+        final colors = Theme.of(context).colorScheme;
+        final textTheme = Theme.of(context).textTheme;
+        final padding = EdgeInsets.symmetric(
+          horizontal: AppTheme.spaceSM * AppTheme.spaceScale(context),
+          vertical: AppTheme.spaceSM * AppTheme.spaceScale(context),
+        );
+        return ListTile(
+          title: Text(
+            "Browser Token",
+            style: textTheme.bodyMedium,
+          ),
+          subtitle: Text(
+            "Paste into the browser extension to let it add downloads",
+            style: textTheme.bodySmall?.copyWith(color: colors.onSurfaceVariant),
+          ),
+          trailing: Row(
+            mainAxisSize: MainAxisSize.min,
+            children: [
+              IconButton(
+                padding: padding,
+                icon: const Icon(Icons.copy),
+                iconSize: AppTheme.iconMD * AppTheme.spaceScale(context),
+                tooltip: "Copy token",
+                onPressed: () =>
+                    Clipboard.setData(ClipboardData(text: value)),
+              ),
+              IconButton(
+                padding: padding,
+                icon: const Icon(Icons.refresh),
+                iconSize: AppTheme.iconMD * AppTheme.spaceScale(context),
+                tooltip: "Generate a new token",
+                onPressed: () => SettingsManager.serverToken.value =
+                    SettingsManager.generateServerToken(),
+              ),
+            ],
+          ),
+        );
+      },
+    );
+  }
+}
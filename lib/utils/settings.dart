@@ -1,5 +1,6 @@
 import 'dart:convert';
 import 'dart:io';
+import 'dart:math';
 import 'package:flutter/foundation.dart';
 import 'package:path_provider/path_provider.dart';
 
@@ -21,6 +22,8 @@ class SettingsManager {
   );
   static final downloadFolder = ValueNotifier<String>('');
   static final serverPort = ValueNotifier<int>(DefaultSettings.serverPort);
+  // shared secret the browser extension sends with captured downloads
+  static final serverToken = ValueNotifier<String>('');
   static final speedLimit = ValueNotifier<double>(DefaultSettings.speedLimit);
   static final downloadThreads = ValueNotifier<int>(
     DefaultSettings.downloadThreads,
@@ -34,6 +37,22 @@ class SettingsManager {
   static final downloadRetries = ValueNotifier<int>(
     DefaultSettings.downloadRetries,
   );
+  // entries: name, days (0 = Monday), start_minute, end_minute,
+  // speed_limit (MB/s) and optional concurrency_limit
+  static final bandwidthSchedule = ValueNotifier<List<dynamic>>([]);
+  // 'Restart' or 'Fail' when a resumed download finds its remote file changed
+  static final onRemoteChange = ValueNotifier<String>('Restart');
+  // named queues: name, concurrency_limit, speed_limit (MB/s, 0 for none)
+  // and a schedule made of bandwidth schedule entries
+  static final queues = ValueNotifier<List<dynamic>>([]);
+  // enabled, start_minute and stop_minute of the daily queue window
+  static final queueWindow = ValueNotifier<Map<String, dynamic>>({
+    'enabled': false,
+    'start_minute': 0,
+    'stop_minute': 0,
+  });
+  // name of the schedule window in effect, null when the settings above apply
+  static final scheduleProfile = ValueNotifier<String?>(null);
 
   /// Init config system (call at app startup)
   static Future<void> init() async {
@@ -47,14 +66,20 @@ class SettingsManager {
     if (await _file.exists()) {
       final data = jsonDecode(await _file.readAsString());
       _applyFromJson(data);
+      // keep the generated token, the extension is paired with it
+      if (data['server_token'] == null) await _saveAll();
       log(configPath);
     } else {
       log("Initial Config");
       downloadFolder.value = '';
+      serverToken.value = generateServerToken();
       await _saveAll();
     }
 
     _attachAutoSave();
+    ScheduleStatus.rustSignalStream.listen((signalPack) {
+      scheduleProfile.value = signalPack.message.profile;
+    });
   }
 
   static void _applyFromJson(Map<String, dynamic> json) {
@@ -63,6 +88,7 @@ class SettingsManager {
     downloadFolder.value =
         json['download_folder'] ?? DefaultSettings.downloadFolder;
     serverPort.value = json['server_port'] ?? DefaultSettings.serverPort;
+    serverToken.value = json['server_token'] ?? generateServerToken();
     speedLimit.value = (json['speed_limit'] ?? DefaultSettings.speedLimit)
         .toDouble();
     downloadThreads.value =
@@ -73,17 +99,26 @@ class SettingsManager {
         json['download_timeout'] ?? DefaultSettings.downloadTimeout;
     downloadRetries.value =
         json['download_retries'] ?? DefaultSettings.downloadRetries;
+    bandwidthSchedule.value = json['bandwidth_schedule'] ?? [];
+    queueWindow.value = json['queue_window'] ?? queueWindow.value;
+    queues.value = json['queues'] ?? [];
+    onRemoteChange.value = json['on_remote_change'] ?? 'Restart';
   }
 
   static Map<String, dynamic> _toJson() => {
     'retreat_to_tray': retreatToTray.value,
     'download_folder': downloadFolder.value,
     'server_port': serverPort.value,
+    'server_token': serverToken.value,
     'speed_limit': speedLimit.value,
     'download_threads': downloadThreads.value,
     'concurrency_limit': concurrencyLimit.value,
     'download_timeout': downloadTimeout.value,
     'download_retries': downloadRetries.value,
+    'bandwidth_schedule': bandwidthSchedule.value,
+    'queue_window': queueWindow.value,
+    'queues': queues.value,
+    'on_remote_change': onRemoteChange.value,
   };
 
   /// Save entire config (initial only)
@@ -115,6 +150,9 @@ class SettingsManager {
       () => _saveChanged('download_folder', downloadFolder.value),
     );
     serverPort.addListener(() => _saveChanged('server_port', serverPort.value));
+    serverToken.addListener(
+      () => _saveChanged('server_token', serverToken.value),
+    );
     speedLimit.addListener(() => _saveChanged('speed_limit', speedLimit.value));
     downloadThreads.addListener(
       () => _saveChanged('download_threads', downloadThreads.value),
@@ -128,10 +166,78 @@ class SettingsManager {
     downloadRetries.addListener(
       () => _saveChanged('download_retries', downloadRetries.value),
     );
+    bandwidthSchedule.addListener(
+      () => _saveChanged('bandwidth_schedule', bandwidthSchedule.value),
+    );
+    queueWindow.addListener(
+      () => _saveChanged('queue_window', queueWindow.value),
+    );
+    queues.addListener(() => _saveChanged('queues', queues.value));
+    onRemoteChange.addListener(
+      () => _saveChanged('on_remote_change', onRemoteChange.value),
+    );
+  }
+
+  /// Random 32 byte hex secret for the capture server.
+  static String generateServerToken() {
+    final random = Random.secure();
+    return List.generate(
+      32,
+      (_) => random.nextInt(256).toRadixString(16).padLeft(2, '0'),
+    ).join();
+  }
+
+  static List<QueueConfig> _queuesToSignal(List<dynamic> entries) {
+    return entries
+        .map(
+          (q) => QueueConfig(
+            name: q['name'] ?? '',
+            concurrencyLimit: q['concurrency_limit'] ?? 1,
+            speedLimit: Uint64.fromBigInt(
+              BigInt.from(((q['speed_limit'] ?? 0) * 1024 * 1024).round()),
+            ),
+            schedule: _scheduleToSignal(q['schedule'] ?? []),
+          ),
+        )
+        .toList();
+  }
+
+  static QueueWindowSettings _queueWindowToSignal(Map<String, dynamic> w) {
+    return QueueWindowSettings(
+      enabled: w['enabled'] ?? false,
+      startMinute: w['start_minute'] ?? 0,
+      stopMinute: w['stop_minute'] ?? 0,
+    );
+  }
+
+  static List<BandwidthSchedule> _scheduleToSignal(List<dynamic> entries) {
+    return entries
+        .map(
+          (e) => BandwidthSchedule(
+            name: e['name'] ?? '',
+            days: List<int>.from(e['days'] ?? []),
+            startMinute: e['start_minute'] ?? 0,
+            endMinute: e['end_minute'] ?? 0,
+            speedLimit: Uint64.fromBigInt(
+              BigInt.from(((e['speed_limit'] ?? 0) * 1024 * 1024).round()),
+            ),
+            concurrencyLimit: e['concurrency_limit'],
+          ),
+        )
+        .toList();
   }
 
   static void _sendSettings(String key, dynamic value) {
     switch (key) {
+      case 'server_port':
+        UpdateSettings(serverPort: value).sendSignalToRust();
+        break;
+      case 'server_token':
+        UpdateSettings(serverToken: value).sendSignalToRust();
+        break;
+      case 'download_folder':
+        UpdateSettings(downloadFolder: value).sendSignalToRust();
+        break;
       case 'speed_limit':
         UpdateSettings(
           speedLimit: Uint64.fromBigInt(
@@ -153,11 +259,28 @@ class SettingsManager {
           downloadTimeout: Uint64.fromBigInt(BigInt.from(value)),
         ).sendSignalToRust();
         break;
+      case 'bandwidth_schedule':
+        UpdateSettings(schedule: _scheduleToSignal(value)).sendSignalToRust();
+        break;
+      case 'on_remote_change':
+        UpdateSettings(onRemoteChange: value).sendSignalToRust();
+        break;
+      case 'queues':
+        UpdateSettings(queues: _queuesToSignal(value)).sendSignalToRust();
+        break;
+      case 'queue_window':
+        UpdateSettings(
+          queueWindow: _queueWindowToSignal(value),
+        ).sendSignalToRust();
+        break;
     }
   }
 
   static Future<void> sendAllSettings() async {
     UpdateSettings(
+      serverPort: serverPort.value,
+      serverToken: serverToken.value,
+      downloadFolder: downloadFolder.value,
       speedLimit: Uint64.fromBigInt(
         BigInt.from((speedLimit.value * 1024 * 1024).round()),
       ),
@@ -165,6 +288,10 @@ class SettingsManager {
       concurrencyLimit: concurrencyLimit.value,
       downloadRetries: downloadRetries.value,
       downloadTimeout: Uint64.fromBigInt(BigInt.from(downloadTimeout.value)),
+      schedule: _scheduleToSignal(bandwidthSchedule.value),
+      queueWindow: _queueWindowToSignal(queueWindow.value),
+      queues: _queuesToSignal(queues.value),
+      onRemoteChange: onRemoteChange.value,
     ).sendSignalToRust();
   }
 }
@@ -0,0 +1,653 @@
+//! MPD manifest parsing for MPEG-DASH downloads. Representations are turned
+//! into the same segment lists the HLS path uses, so downloading and muxing is shared.
+use anyhow::Result;
+use reqwest::{RequestBuilder, Url};
+use roxmltree::{Document, Node};
+
+use super::hls::{ByteRange, InitSection, MediaSegment};
+
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    // `type="dynamic"`, a live presentation
+    pub dynamic: bool,
+    pub periods: Vec<Period>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Period {
+    pub adaptation_sets: Vec<AdaptationSet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Video,
+    Audio,
+    Text,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdaptationSet {
+    pub kind: ContentKind,
+    pub lang: Option<String>,
+    pub representations: Vec<Representation>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Representation {
+    pub id: String,
+    pub bandwidth: u64,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub codecs: Option<String>,
+    pub segments: Segments,
+}
+
+/// Where the media of a representation lives.
+#[derive(Debug, Clone)]
+pub enum Segments {
+    // SegmentTemplate or SegmentList, every segment known up front
+    List {
+        init: Option<InitSection>,
+        media: Vec<MediaSegment>,
+    },
+    // SegmentBase: one file whose `sidx` box at `index_range` lists the segments
+    Indexed {
+        uri: Url,
+        index_range: ByteRange,
+    },
+    // a plain file without segment information
+    Single { uri: Url },
+}
+
+/// Fetches and parses a manifest, resolving URLs against the final (post-redirect) URL.
+pub async fn fetch_manifest(request: RequestBuilder) -> Result<Manifest> {
+    let resp = request.send().await?.error_for_status()?;
+    let base = resp.url().clone();
+    let content = resp.text().await?;
+    parse_manifest(&base, &content)
+}
+
+pub fn parse_manifest(base: &Url, content: &str) -> Result<Manifest> {
+    let doc = Document::parse(content)?;
+    let mpd = doc.root_element();
+    if mpd.tag_name().name() != "MPD" {
+        return Err(anyhow::anyhow!("Not an MPD manifest"));
+    }
+    let base = base_url(base, mpd)?;
+    let dynamic = mpd.attribute("type") == Some("dynamic");
+    let total = mpd.attribute("mediaPresentationDuration").and_then(parse_duration);
+
+    let period_nodes = children(mpd, "Period").collect::<Vec<_>>();
+    let single = period_nodes.len() == 1;
+    let mut periods = Vec::with_capacity(period_nodes.len());
+    for node in period_nodes {
+        // A lone period without a duration lasts the whole presentation
+        let duration = node
+            .attribute("duration")
+            .and_then(parse_duration)
+            .or(if single { total } else { None });
+        periods.push(parse_period(&base, node, duration)?);
+    }
+    if periods.is_empty() {
+        return Err(anyhow::anyhow!("MPD manifest has no periods"));
+    }
+    Ok(Manifest { dynamic, periods })
+}
+
+fn parse_period(base: &Url, node: Node, duration: Option<f64>) -> Result<Period> {
+    let base = base_url(base, node)?;
+    let mut adaptation_sets = Vec::new();
+    for set in children(node, "AdaptationSet") {
+        let set_base = base_url(&base, set)?;
+        let mut representations = Vec::new();
+        for rep in children(set, "Representation") {
+            let rep_base = base_url(&set_base, rep)?;
+            let id = rep.attribute("id").unwrap_or_default().to_string();
+            let bandwidth = rep.attribute("bandwidth").and_then(|v| v.parse().ok()).unwrap_or(0);
+            // SegmentTemplate and friends may sit on any level, the innermost wins
+            let scopes = [rep, set, node];
+            let segments = parse_segments(&rep_base, &scopes, &id, bandwidth, duration)?;
+            representations.push(Representation {
+                id,
+                bandwidth,
+                width: rep.attribute("width").and_then(|v| v.parse().ok()),
+                height: rep.attribute("height").and_then(|v| v.parse().ok()),
+                codecs: inherited(&[rep, set], "codecs").map(str::to_string),
+                segments,
+            });
+        }
+        adaptation_sets.push(AdaptationSet {
+            kind: content_kind(set),
+            lang: set.attribute("lang").map(str::to_string),
+            representations,
+        });
+    }
+    Ok(Period { adaptation_sets })
+}
+
+fn content_kind(set: Node) -> ContentKind {
+    let mime = inherited(&[set], "contentType")
+        .or_else(|| inherited(&[set], "mimeType"))
+        .or_else(|| children(set, "Representation").find_map(|r| r.attribute("mimeType")))
+        .unwrap_or_default();
+    if mime.starts_with("video") {
+        ContentKind::Video
+    } else if mime.starts_with("audio") {
+        ContentKind::Audio
+    } else if mime.starts_with("text") || mime.contains("ttml") || mime.contains("vtt") {
+        ContentKind::Text
+    } else {
+        ContentKind::Other
+    }
+}
+
+fn parse_segments(base: &Url, scopes: &[Node], id: &str, bandwidth: u64, period: Option<f64>) -> Result<Segments> {
+    let templates = scopes.iter().filter_map(|n| child(*n, "SegmentTemplate")).collect::<Vec<_>>();
+    if !templates.is_empty() {
+        return parse_template(base, &templates, id, bandwidth, period);
+    }
+    if let Some(list) = scopes.iter().find_map(|n| child(*n, "SegmentList")) {
+        return parse_list(base, list);
+    }
+    if let Some(seg_base) = scopes.iter().find_map(|n| child(*n, "SegmentBase"))
+        && let Some(range) = seg_base.attribute("indexRange")
+    {
+        return Ok(Segments::Indexed {
+            uri: base.clone(),
+            index_range: parse_range(range)?,
+        });
+    }
+    Ok(Segments::Single { uri: base.clone() })
+}
+
+/// `templates` goes from the innermost element outwards, attributes fall back along it.
+fn parse_template(base: &Url, templates: &[Node], id: &str, bandwidth: u64, period: Option<f64>) -> Result<Segments> {
+    let attr = |name: &str| inherited(templates, name);
+    let timescale = attr("timescale").and_then(|v| v.parse().ok()).unwrap_or(1u64).max(1) as f64;
+    let start_number = attr("startNumber").and_then(|v| v.parse().ok()).unwrap_or(1u64);
+    let media = attr("media").ok_or_else(|| anyhow::anyhow!("SegmentTemplate without media"))?;
+
+    let init = attr("initialization")
+        .map(|tpl| -> Result<InitSection> {
+            Ok(InitSection {
+                uri: base.join(&expand_template(tpl, id, bandwidth, None, None))?,
+                byte_range: None,
+                key: None,
+            })
+        })
+        .transpose()?;
+
+    // (start time, duration) of every segment, in timescale units
+    let mut times: Vec<(u64, u64)> = Vec::new();
+    if let Some(timeline) = templates.iter().find_map(|n| child(*n, "SegmentTimeline")) {
+        let end = period.map(|p| (p * timescale) as u64);
+        let mut t = 0u64;
+        let entries = children(timeline, "S").collect::<Vec<_>>();
+        for (i, s) in entries.iter().enumerate() {
+            if let Some(start) = s.attribute("t").and_then(|v| v.parse().ok()) {
+                t = start;
+            }
+            let d: u64 = s.attribute("d").and_then(|v| v.parse().ok()).unwrap_or(0);
+            if d == 0 {
+                continue;
+            }
+            let r: i64 = s.attribute("r").and_then(|v| v.parse().ok()).unwrap_or(0);
+            // r = -1 repeats until the next S, or the end of the period
+            let limit = entries
+                .get(i + 1)
+                .and_then(|next| next.attribute("t"))
+                .and_then(|v| v.parse().ok())
+                .or(end);
+            let count = if r < 0 {
+                limit.map_or(1, |limit: u64| limit.saturating_sub(t).div_ceil(d))
+            } else {
+                r as u64 + 1
+            };
+            for _ in 0..count {
+                times.push((t, d));
+                t += d;
+            }
+        }
+    } else {
+        let d: u64 = attr("duration")
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("SegmentTemplate without duration or timeline"))?;
+        let period = period.ok_or_else(|| anyhow::anyhow!("Cannot count segments of a period without duration"))?;
+        let count = ((period * timescale) / d as f64).ceil() as u64;
+        times.extend((0..count).map(|i| (i * d, d)));
+    }
+
+    let media = times
+        .iter()
+        .enumerate()
+        .map(|(i, (t, d))| {
+            let uri = expand_template(media, id, bandwidth, Some(start_number + i as u64), Some(*t));
+            Ok(segment(base.join(&uri)?, *d as f64 / timescale, None))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Segments::List { init, media })
+}
+
+fn parse_list(base: &Url, list: Node) -> Result<Segments> {
+    let timescale = list.attribute("timescale").and_then(|v| v.parse().ok()).unwrap_or(1u64).max(1) as f64;
+    let duration = list.attribute("duration").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0) as f64 / timescale;
+
+    let init = child(list, "Initialization")
+        .map(|node| -> Result<InitSection> {
+            Ok(InitSection {
+                uri: node.attribute("sourceURL").map_or(Ok(base.clone()), |u| base.join(u))?,
+                byte_range: node.attribute("range").map(parse_range).transpose()?,
+                key: None,
+            })
+        })
+        .transpose()?;
+    let media = children(list, "SegmentURL")
+        .map(|node| {
+            let uri = node.attribute("media").map_or(Ok(base.clone()), |u| base.join(u))?;
+            let range = node.attribute("mediaRange").map(parse_range).transpose()?;
+            Ok(segment(uri, duration, range))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Segments::List { init, media })
+}
+
+fn segment(uri: Url, duration: f64, byte_range: Option<ByteRange>) -> MediaSegment {
+    MediaSegment {
+        uri,
+        duration,
+        sequence: 0,
+        key: None,
+        byte_range,
+        map: None,
+        discontinuity: false,
+    }
+}
+
+/// Reads the `sidx` box of a SegmentBase file. Returns the byte range of everything
+/// before the first segment (the init section) and each segment with its duration.
+pub fn parse_sidx(data: &[u8], index_range: ByteRange) -> Result<(ByteRange, Vec<(ByteRange, f64)>)> {
+    let invalid = || anyhow::anyhow!("Invalid sidx box");
+    let read = |at: usize, len: usize| -> Result<u64> {
+        let bytes = data.get(at..at + len).ok_or_else(invalid)?;
+        Ok(bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+    };
+
+    // Skip boxes until the sidx, the index range may start with others (e.g. `styp`)
+    let mut at = 0;
+    loop {
+        let size = read(at, 4)? as usize;
+        if data.get(at + 4..at + 8) == Some(b"sidx".as_slice()) {
+            break;
+        }
+        if size < 8 {
+            return Err(invalid());
+        }
+        at += size;
+    }
+    let box_end = index_range.offset + at as u64 + read(at, 4)?;
+    let version = read(at + 8, 1)?;
+    let timescale = read(at + 16, 4)?.max(1) as f64;
+    let (first_offset, mut at) = if version == 0 {
+        (read(at + 24, 4)?, at + 28)
+    } else {
+        (read(at + 28, 8)?, at + 36)
+    };
+    let count = read(at + 2, 2)? as usize;
+    at += 4;
+
+    let first = box_end + first_offset;
+    let mut offset = first;
+    let mut segments = Vec::with_capacity(count);
+    for _ in 0..count {
+        let reference = read(at, 4)?;
+        let duration = read(at + 4, 4)?;
+        at += 12;
+        // Hierarchical indexes point at further sidx boxes, which we do not follow
+        if reference >> 31 == 1 {
+            return Err(anyhow::anyhow!("Nested sidx boxes are not supported"));
+        }
+        let length = reference & 0x7fff_ffff;
+        segments.push((ByteRange { length, offset }, duration as f64 / timescale));
+        offset += length;
+    }
+    Ok((ByteRange { length: first, offset: 0 }, segments))
+}
+
+/// Picks the representation with the id `preferred`, or the highest bandwidth one.
+pub fn select_representation<'a>(
+    representations: &[&'a Representation],
+    preferred: Option<&str>,
+) -> Option<&'a Representation> {
+    preferred
+        .and_then(|id| representations.iter().find(|r| r.id == id))
+        .or_else(|| representations.iter().max_by_key(|r| r.bandwidth))
+        .copied()
+}
+
+impl Period {
+    pub fn representations(&self, kind: ContentKind) -> Vec<&Representation> {
+        self.adaptation_sets
+            .iter()
+            .filter(|s| s.kind == kind)
+            .flat_map(|s| &s.representations)
+            .collect()
+    }
+}
+
+/// Expands `$RepresentationID$`, `$Bandwidth$`, `$Number$` and `$Time$`, the
+/// latter two with an optional printf width such as `$Number%05d$`.
+fn expand_template(template: &str, id: &str, bandwidth: u64, number: Option<u64>, time: Option<u64>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('$') else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+        let token = &after[..end];
+        let (name, format) = token.split_once('%').unwrap_or((token, ""));
+        let width = format
+            .trim_start_matches('0')
+            .trim_end_matches('d')
+            .parse::<usize>()
+            .unwrap_or(0);
+        let value = match name {
+            "" => Some("$".to_string()),
+            "RepresentationID" => Some(id.to_string()),
+            "Bandwidth" => Some(format!("{:0width$}", bandwidth, width = width)),
+            "Number" => number.map(|n| format!("{:0width$}", n, width = width)),
+            "Time" => time.map(|t| format!("{:0width$}", t, width = width)),
+            _ => None,
+        };
+        match value {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Parses the `PnDTnHnMn.nS` subset of ISO 8601 durations used by manifests, in seconds.
+/// Years and months have no fixed length, durations using them are rejected.
+fn parse_duration(value: &str) -> Option<f64> {
+    let value = value.strip_prefix('P')?;
+    let (date, time) = value.split_once('T').unwrap_or((value, ""));
+    let mut seconds = 0.0;
+    for (part, units) in [(date, &[('D', 86400.0)][..]), (time, &[('H', 3600.0), ('M', 60.0), ('S', 1.0)][..])] {
+        let mut rest = part;
+        for (unit, scale) in units {
+            if let Some((n, after)) = rest.split_once(*unit) {
+                seconds += n.parse::<f64>().ok()? * scale;
+                rest = after;
+            }
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+    }
+    Some(seconds)
+}
+
+/// Parses an inclusive `first-last` byte range.
+fn parse_range(value: &str) -> Result<ByteRange> {
+    let invalid = || anyhow::anyhow!("Invalid byte range {}", value);
+    let (first, last) = value.split_once('-').ok_or_else(invalid)?;
+    let first: u64 = first.trim().parse().map_err(|_| invalid())?;
+    let last: u64 = last.trim().parse().map_err(|_| invalid())?;
+    Ok(ByteRange { length: last.saturating_sub(first) + 1, offset: first })
+}
+
+fn base_url(parent: &Url, node: Node) -> Result<Url> {
+    match child(node, "BaseURL").and_then(|n| n.text()) {
+        Some(text) => Ok(parent.join(text.trim())?),
+        None => Ok(parent.clone()),
+    }
+}
+
+fn inherited<'a, 'input>(nodes: &[Node<'a, 'input>], name: &str) -> Option<&'a str> {
+    nodes.iter().find_map(|n| n.attribute(name))
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &'static str) -> Option<Node<'a, 'input>> {
+    children(node, name).next()
+}
+
+fn children<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'static str) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://cdn.example/show/manifest.mpd").unwrap()
+    }
+
+    fn uris(segments: &[MediaSegment]) -> Vec<&str> {
+        segments.iter().map(|s| s.uri.as_str()).collect()
+    }
+
+    fn list(rep: &Representation) -> (&Option<InitSection>, &Vec<MediaSegment>) {
+        match &rep.segments {
+            Segments::List { init, media } => (init, media),
+            other => panic!("expected a segment list, got {:?}", other),
+        }
+    }
+
+    const TEMPLATED: &str = r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT10S">
+  <BaseURL>media/</BaseURL>
+  <Period>
+    <AdaptationSet contentType="video" codecs="avc1.64001f">
+      <SegmentTemplate initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/$Number%05d$.m4s" startNumber="5" duration="4" />
+      <Representation id="720p" bandwidth="3000000" width="1280" height="720" />
+      <Representation id="360p" bandwidth="800000" width="640" height="360" codecs="avc1.4d401e" />
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4" lang="ja">
+      <Representation id="aac" bandwidth="128000">
+        <SegmentTemplate timescale="1000" initialization="a/init.mp4" media="a/$Time$.m4s">
+          <SegmentTimeline>
+            <S t="0" d="2000" r="2" />
+            <S d="1000" r="-1" />
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    #[test]
+    fn numbered_template_covers_the_whole_period() {
+        let manifest = parse_manifest(&base(), TEMPLATED).unwrap();
+        assert!(!manifest.dynamic);
+        let period = &manifest.periods[0];
+        let video = period.representations(ContentKind::Video);
+        assert_eq!(video.len(), 2);
+        assert_eq!((video[0].width, video[0].height), (Some(1280), Some(720)));
+        assert_eq!(video[0].codecs.as_deref(), Some("avc1.64001f"));
+        assert_eq!(video[1].codecs.as_deref(), Some("avc1.4d401e"));
+
+        let (init, media) = list(video[0]);
+        assert_eq!(init.as_ref().map(|i| i.uri.as_str()), Some("https://cdn.example/show/media/720p/init.mp4"));
+        // 10 s in 4 s segments, the last one partial
+        assert_eq!(uris(media), vec![
+            "https://cdn.example/show/media/720p/00005.m4s",
+            "https://cdn.example/show/media/720p/00006.m4s",
+            "https://cdn.example/show/media/720p/00007.m4s",
+        ]);
+        assert!(media.iter().all(|s| s.duration == 4.0));
+    }
+
+    #[test]
+    fn timeline_repeats_until_the_end_of_the_period() {
+        let manifest = parse_manifest(&base(), TEMPLATED).unwrap();
+        let audio = manifest.periods[0].representations(ContentKind::Audio);
+        assert_eq!(manifest.periods[0].adaptation_sets[1].lang.as_deref(), Some("ja"));
+
+        let (_, media) = list(audio[0]);
+        // three 2 s segments from r="2", then 1 s ones up to 10 s
+        assert_eq!(uris(media), vec![
+            "https://cdn.example/show/media/a/0.m4s",
+            "https://cdn.example/show/media/a/2000.m4s",
+            "https://cdn.example/show/media/a/4000.m4s",
+            "https://cdn.example/show/media/a/6000.m4s",
+            "https://cdn.example/show/media/a/7000.m4s",
+            "https://cdn.example/show/media/a/8000.m4s",
+            "https://cdn.example/show/media/a/9000.m4s",
+        ]);
+        assert_eq!(media.iter().map(|s| s.duration).sum::<f64>(), 10.0);
+    }
+
+    #[test]
+    fn segment_list_base_and_single_file_representations() {
+        let manifest = parse_manifest(&base(), r#"<MPD type="dynamic">
+  <Period duration="PT1M">
+    <AdaptationSet mimeType="video/mp4">
+      <Representation id="list" bandwidth="1">
+        <SegmentList timescale="10" duration="25">
+          <Initialization sourceURL="init.mp4" range="0-99" />
+          <SegmentURL media="one.m4s" />
+          <SegmentURL mediaRange="100-199" />
+        </SegmentList>
+      </Representation>
+      <Representation id="indexed" bandwidth="2">
+        <BaseURL>https://files.example/indexed.mp4</BaseURL>
+        <SegmentBase indexRange="800-855" />
+      </Representation>
+      <Representation id="single" bandwidth="3">
+        <BaseURL>single.mp4</BaseURL>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet mimeType="application/ttml+xml" />
+  </Period>
+</MPD>"#).unwrap();
+        assert!(manifest.dynamic);
+        let period = &manifest.periods[0];
+        assert_eq!(period.adaptation_sets[1].kind, ContentKind::Text);
+        let reps = period.representations(ContentKind::Video);
+
+        let (init, media) = list(reps[0]);
+        let init = init.as_ref().unwrap();
+        assert_eq!(init.uri.as_str(), "https://cdn.example/show/init.mp4");
+        assert_eq!(init.byte_range, Some(ByteRange { length: 100, offset: 0 }));
+        assert_eq!(uris(media), vec!["https://cdn.example/show/one.m4s", "https://cdn.example/show/manifest.mpd"]);
+        assert_eq!(media[1].byte_range, Some(ByteRange { length: 100, offset: 100 }));
+        assert_eq!(media[0].duration, 2.5);
+
+        match &reps[1].segments {
+            Segments::Indexed { uri, index_range } => {
+                assert_eq!(uri.as_str(), "https://files.example/indexed.mp4");
+                assert_eq!(*index_range, ByteRange { length: 56, offset: 800 });
+            }
+            other => panic!("expected an indexed representation, got {:?}", other),
+        }
+        match &reps[2].segments {
+            Segments::Single { uri } => assert_eq!(uri.as_str(), "https://cdn.example/show/single.mp4"),
+            other => panic!("expected a single file, got {:?}", other),
+        }
+        assert_eq!(select_representation(&reps, None).map(|r| r.id.as_str()), Some("single"));
+        assert_eq!(select_representation(&reps, Some("list")).map(|r| r.id.as_str()), Some("list"));
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_usable_manifest() {
+        assert!(parse_manifest(&base(), "<html/>").is_err());
+        assert!(parse_manifest(&base(), "<MPD/>").is_err());
+        assert!(parse_manifest(&base(), "not xml").is_err());
+        // a template without a duration needs a timeline
+        let no_duration = r#"<MPD><Period duration="PT4S"><AdaptationSet>
+            <Representation id="r" bandwidth="1"><SegmentTemplate media="$Number$.m4s" /></Representation>
+            </AdaptationSet></Period></MPD>"#;
+        assert!(parse_manifest(&base(), no_duration).is_err());
+    }
+
+    #[test]
+    fn expand_template_fills_in_identifiers() {
+        let expand = |tpl| expand_template(tpl, "v1", 250_000, Some(42), Some(90_000));
+        assert_eq!(expand("$RepresentationID$_$Bandwidth$/$Number$.m4s"), "v1_250000/42.m4s");
+        assert_eq!(expand("seg-$Number%05d$.m4s"), "seg-00042.m4s");
+        assert_eq!(expand("t$Time%010d$.m4s"), "t0000090000.m4s");
+        assert_eq!(expand("cost$$.m4s"), "cost$.m4s");
+        assert_eq!(expand("$Unknown$/$Number$"), "$Unknown$/42");
+        assert_eq!(expand("dangling$Number"), "dangling$Number");
+        assert_eq!(expand_template("$Number$", "v1", 1, None, None), "$Number$");
+    }
+
+    #[test]
+    fn parse_duration_reads_days_and_times() {
+        assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+        assert_eq!(parse_duration("P1DT1S"), Some(86401.0));
+        assert_eq!(parse_duration("PT0S"), Some(0.0));
+        assert_eq!(parse_duration("PT90M"), Some(5400.0));
+    }
+
+    #[test]
+    fn parse_duration_rejects_calendar_units_and_garbage() {
+        assert_eq!(parse_duration("P1Y"), None);
+        assert_eq!(parse_duration("P2M"), None);
+        assert_eq!(parse_duration("P1Y2DT3S"), None);
+        assert_eq!(parse_duration("PT1X"), None);
+        assert_eq!(parse_duration("PTxS"), None);
+        assert_eq!(parse_duration("10S"), None);
+    }
+
+    /// A version 0 `sidx` box with the given (size, duration) references.
+    fn sidx(timescale: u32, first_offset: u32, references: &[(u32, u32)]) -> Vec<u8> {
+        let size = 32 + 12 * references.len() as u32;
+        let mut data = Vec::new();
+        data.extend(size.to_be_bytes());
+        data.extend(b"sidx");
+        data.extend([0, 0, 0, 0]);
+        data.extend(1u32.to_be_bytes());
+        data.extend(timescale.to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        data.extend(first_offset.to_be_bytes());
+        data.extend(0u16.to_be_bytes());
+        data.extend((references.len() as u16).to_be_bytes());
+        for (size, duration) in references {
+            data.extend(size.to_be_bytes());
+            data.extend(duration.to_be_bytes());
+            data.extend(0u32.to_be_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parse_sidx_lists_segments_after_the_index() {
+        let data = sidx(1000, 0, &[(1000, 2000), (2000, 1500)]);
+        let index_range = ByteRange { length: data.len() as u64, offset: 800 };
+        let (init, segments) = parse_sidx(&data, index_range).unwrap();
+        assert_eq!(init, ByteRange { length: 856, offset: 0 });
+        assert_eq!(segments, vec![
+            (ByteRange { length: 1000, offset: 856 }, 2.0),
+            (ByteRange { length: 2000, offset: 1856 }, 1.5),
+        ]);
+    }
+
+    #[test]
+    fn parse_sidx_skips_leading_boxes_and_honours_first_offset() {
+        let mut data = vec![0, 0, 0, 12];
+        data.extend(b"styp");
+        data.extend(b"iso6");
+        data.extend(sidx(1, 10, &[(500, 4)]));
+        let (init, segments) = parse_sidx(&data, ByteRange { length: data.len() as u64, offset: 100 }).unwrap();
+        // 100 + 12 (styp) + 44 (sidx) + 10 (first_offset)
+        assert_eq!(init.length, 166);
+        assert_eq!(segments, vec![(ByteRange { length: 500, offset: 166 }, 4.0)]);
+    }
+
+    #[test]
+    fn parse_sidx_rejects_nested_and_truncated_indexes() {
+        let nested = sidx(1, 0, &[(0x8000_0000 | 500, 4)]);
+        assert!(parse_sidx(&nested, ByteRange { length: 44, offset: 0 }).is_err());
+        let truncated = &sidx(1, 0, &[(500, 4)])[..38];
+        assert!(parse_sidx(truncated, ByteRange { length: 38, offset: 0 }).is_err());
+        assert!(parse_sidx(&[0, 0, 0, 0, b'f', b'r', b'e', b'e'], ByteRange { length: 8, offset: 0 }).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher, time::Duration};
+use chrono::{DateTime, Utc};
+use reqwest::{header::{HeaderMap, RETRY_AFTER}, Response, StatusCode};
+use thiserror::Error;
+
+use crate::utils::types::ErrorKind;
+
+// the first retry waits up to this long, doubling with every further attempt...
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+// ...but never longer than this
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+// a longer `Retry-After` is cut down to this
+const RETRY_AFTER_MAX: Duration = Duration::from_secs(600);
+
+/// Why a request of a download failed.
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("network error: {0}")]
+    Network(String),
+    #[error("server answered {status}")]
+    Http { status: StatusCode, retry_after: Option<Duration> },
+    #[error("disk error: {0}")]
+    Disk(#[from] std::io::Error),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("verification failed: {0}")]
+    Verification(String),
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) => Self::Http { status, retry_after: None },
+            None => Self::Network(e.to_string()),
+        }
+    }
+}
+
+impl DownloadError {
+    /// Error for a response with a failure status, keeping the server's `Retry-After`.
+    pub fn from_response(resp: &Response) -> Self {
+        Self::Http { status: resp.status(), retry_after: retry_after(resp.headers()) }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Network(_) => ErrorKind::Network,
+            Self::Http { .. } => ErrorKind::Http,
+            Self::Disk(_) => ErrorKind::Disk,
+            Self::Protocol(_) => ErrorKind::Protocol,
+            Self::Verification(_) => ErrorKind::Verification,
+        }
+    }
+
+    /// Whether the same request may succeed later: network trouble, timeouts, rate limits
+    /// and the server errors that mean "not now". Everything else fails for good.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Network(_) => true,
+            Self::Http { status, .. } => matches!(status.as_u16(), 408 | 425 | 429 | 500 | 502 | 503 | 504),
+            _ => false,
+        }
+    }
+
+    /// How long to wait before retry number `attempt`, counting from 1: what the server
+    /// asked for, or exponential backoff with jitter.
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        match self {
+            Self::Http { retry_after: Some(after), .. } => (*after).min(RETRY_AFTER_MAX),
+            _ => backoff(attempt),
+        }
+    }
+}
+
+/// Kind of an error that may or may not have started out as a `DownloadError`.
+pub fn kind_of(e: &anyhow::Error) -> ErrorKind {
+    if let Some(e) = e.downcast_ref::<DownloadError>() {
+        e.kind()
+    } else if e.downcast_ref::<std::io::Error>().is_some() {
+        ErrorKind::Disk
+    } else if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        if e.status().is_some() { ErrorKind::Http } else { ErrorKind::Network }
+    } else {
+        ErrorKind::Other
+    }
+}
+
+/// Random wait between half and all of `BACKOFF_BASE * 2^(attempt - 1)`, so segments
+/// failing together do not all come back at once.
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = BACKOFF_BASE
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX);
+    // Randomly keyed, which is all the jitter needs
+    let random = RandomState::new().hash_one(attempt) as f64 / u64::MAX as f64;
+    ceiling.mul_f64(0.5 + random / 2.0)
+}
+
+/// `Retry-After` of a response, given either as seconds or as an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    // A date already past means right away
+    Some((at - Utc::now()).to_std().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn http(status: u16) -> DownloadError {
+        DownloadError::Http { status: StatusCode::from_u16(status).unwrap(), retry_after: None }
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retries_only_transient_failures() {
+        for status in [408, 425, 429, 500, 502, 503, 504] {
+            assert!(http(status).is_retryable(), "{} should be retried", status);
+        }
+        for status in [400, 401, 403, 404, 410, 416, 501] {
+            assert!(!http(status).is_retryable(), "{} should not be retried", status);
+        }
+        assert!(DownloadError::Network("reset".to_string()).is_retryable());
+        assert!(!DownloadError::Protocol("bad range".to_string()).is_retryable());
+        assert!(!DownloadError::Disk(std::io::Error::other("full")).is_retryable());
+    }
+
+    #[test]
+    fn backoff_grows_within_its_cap() {
+        for attempt in 1..=20u32 {
+            let ceiling = BACKOFF_BASE.saturating_mul(1 << (attempt - 1).min(16)).min(BACKOFF_MAX);
+            let delay = backoff(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "attempt {}: {:?}", attempt, delay);
+        }
+        // the lowest possible wait of a later attempt is above the highest of the first
+        assert!(backoff(4) > BACKOFF_BASE);
+        assert!(backoff(u32::MAX) <= BACKOFF_MAX);
+    }
+
+    #[test]
+    fn retry_after_takes_precedence_over_backoff() {
+        let error = DownloadError::Http { status: StatusCode::TOO_MANY_REQUESTS, retry_after: Some(Duration::from_secs(7)) };
+        assert_eq!(error.retry_delay(1), Duration::from_secs(7));
+        assert_eq!(error.retry_delay(10), Duration::from_secs(7));
+
+        let error = DownloadError::Http { status: StatusCode::SERVICE_UNAVAILABLE, retry_after: Some(Duration::from_secs(86400)) };
+        assert_eq!(error.retry_delay(1), RETRY_AFTER_MAX);
+
+        assert!(http(503).retry_delay(1) <= BACKOFF_BASE);
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&headers(" 5 ")), Some(Duration::from_secs(5)));
+
+        let at = (Utc::now() + chrono::Duration::seconds(90)).to_rfc2822();
+        let delay = retry_after(&headers(&at)).unwrap();
+        assert!(delay > Duration::from_secs(85) && delay <= Duration::from_secs(90), "{:?}", delay);
+        assert_eq!(retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")), Some(Duration::ZERO));
+
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&headers("-1")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+}
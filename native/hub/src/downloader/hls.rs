@@ -0,0 +1,687 @@
+//! M3U8 playlist parsing for HLS downloads.
+use std::collections::HashMap;
+use anyhow::Result;
+use openssl::symm::{Cipher, decrypt};
+use reqwest::{RequestBuilder, Url};
+
+#[derive(Debug, Clone)]
+pub enum Playlist {
+    Master(MasterPlaylist),
+    Media(MediaPlaylist),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MasterPlaylist {
+    pub variants: Vec<Variant>,
+    pub renditions: Vec<Rendition>,
+}
+
+/// One `#EXT-X-STREAM-INF` entry.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub uri: Url,
+    pub bandwidth: u64,
+    pub resolution: Option<String>,
+    pub codecs: Option<String>,
+    // GROUP-IDs of the renditions meant to play alongside this variant
+    pub audio: Option<String>,
+    pub subtitles: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenditionKind {
+    Audio,
+    Video,
+    Subtitles,
+    ClosedCaptions,
+}
+
+impl RenditionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RenditionKind::Audio => "audio",
+            RenditionKind::Video => "video",
+            RenditionKind::Subtitles => "subtitles",
+            RenditionKind::ClosedCaptions => "closed-captions",
+        }
+    }
+}
+
+/// One `#EXT-X-MEDIA` entry. Without a URI the rendition is muxed into the variant itself.
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    pub kind: RenditionKind,
+    pub group_id: String,
+    pub name: String,
+    pub language: Option<String>,
+    pub default: bool,
+    pub uri: Option<Url>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MediaPlaylist {
+    pub target_duration: f64,
+    // `#EXT-X-ENDLIST` seen, no more segments will be added
+    pub end_list: bool,
+    pub segments: Vec<MediaSegment>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaSegment {
+    pub uri: Url,
+    pub duration: f64,
+    // media sequence number of this segment
+    pub sequence: u64,
+    pub key: Option<Key>,
+    pub byte_range: Option<ByteRange>,
+    // `#EXT-X-MAP` initialization section the segment needs to be decodable
+    pub map: Option<InitSection>,
+    // an `#EXT-X-DISCONTINUITY` precedes this segment
+    pub discontinuity: bool,
+}
+
+/// `length` bytes starting at `offset` of a resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub length: u64,
+    pub offset: u64,
+}
+
+impl ByteRange {
+    /// Value for the `Range` request header.
+    pub fn header(&self) -> String {
+        format!("bytes={}-{}", self.offset, self.offset + self.length.max(1) - 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitSection {
+    pub uri: Url,
+    pub byte_range: Option<ByteRange>,
+    // the key in effect where the map appeared, AES-128 covers the init section too
+    pub key: Option<Key>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyMethod {
+    Aes128,
+    SampleAes,
+}
+
+/// The `#EXT-X-KEY` in effect for a segment. `METHOD=NONE` is represented by no key at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    pub method: KeyMethod,
+    pub uri: Url,
+    pub iv: Option<[u8; 16]>,
+    pub key_format: Option<String>,
+}
+
+impl Key {
+    /// Only keys served as raw bytes can be fetched and applied by us, not DRM key systems.
+    pub fn is_identity(&self) -> bool {
+        self.key_format.as_deref().is_none_or(|f| f == "identity")
+    }
+
+    /// The explicit IV, or the segment's media sequence number as a big-endian 128-bit value.
+    pub fn iv_for(&self, sequence: u64) -> [u8; 16] {
+        self.iv.unwrap_or_else(|| (sequence as u128).to_be_bytes())
+    }
+}
+
+/// Fetches and parses a playlist, resolving URIs against the final (post-redirect) URL.
+pub async fn fetch_playlist(request: RequestBuilder) -> Result<Playlist> {
+    let resp = request.send().await?.error_for_status()?;
+    let base = resp.url().clone();
+    let content = resp.text().await?;
+    parse_playlist(&base, &content)
+}
+
+pub fn parse_playlist(base: &Url, content: &str) -> Result<Playlist> {
+    let mut lines = content.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err(anyhow::anyhow!("Not an M3U8 playlist"));
+    }
+
+    if content.contains("#EXT-X-STREAM-INF") {
+        parse_master(base, lines).map(Playlist::Master)
+    } else {
+        parse_media(base, lines).map(Playlist::Media)
+    }
+}
+
+fn parse_master<'a>(base: &Url, lines: impl Iterator<Item = &'a str>) -> Result<MasterPlaylist> {
+    let mut playlist = MasterPlaylist::default();
+    let mut pending: Option<HashMap<String, String>> = None;
+
+    for line in lines {
+        if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            pending = Some(parse_attributes(attrs));
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MEDIA:") {
+            if let Some(rendition) = parse_rendition(base, attrs)? {
+                playlist.renditions.push(rendition);
+            }
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some(attrs) = pending.take() {
+            playlist.variants.push(Variant {
+                uri: base.join(line)?,
+                bandwidth: attrs.get("BANDWIDTH").and_then(|v| v.parse().ok()).unwrap_or(0),
+                resolution: attrs.get("RESOLUTION").cloned(),
+                codecs: attrs.get("CODECS").cloned(),
+                audio: attrs.get("AUDIO").cloned(),
+                subtitles: attrs.get("SUBTITLES").cloned(),
+            });
+        }
+    }
+
+    if playlist.variants.is_empty() {
+        return Err(anyhow::anyhow!("Master playlist has no variants"));
+    }
+    Ok(playlist)
+}
+
+fn parse_media<'a>(base: &Url, lines: impl Iterator<Item = &'a str>) -> Result<MediaPlaylist> {
+    let mut playlist = MediaPlaylist::default();
+    let mut sequence = 0;
+    let mut duration = 0.0;
+    let mut key = None;
+    let mut map = None;
+    let mut discontinuity = false;
+    // (length, explicit offset) of the next segment
+    let mut pending_range: Option<(u64, Option<u64>)> = None;
+    // where the previous sub-range ended, for ranges without an offset
+    let mut last_range_end: Option<(Url, u64)> = None;
+
+    for line in lines {
+        if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            playlist.target_duration = value.trim().parse().unwrap_or(0.0);
+        } else if line == "#EXT-X-ENDLIST" {
+            playlist.end_list = true;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            sequence = value.trim().parse().unwrap_or(0);
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-KEY:") {
+            key = parse_key(base, attrs)?;
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MAP:") {
+            map = Some(parse_map(base, attrs, key.clone())?);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            pending_range = Some(parse_byte_range(value)?);
+        } else if line == "#EXT-X-DISCONTINUITY" {
+            discontinuity = true;
+        } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+            let value = value.split(',').next().unwrap_or_default();
+            duration = value.trim().parse().unwrap_or(0.0);
+        } else if line.starts_with('#') {
+            continue;
+        } else {
+            let uri = base.join(line)?;
+            let byte_range = match pending_range.take() {
+                Some((length, offset)) => {
+                    let offset = match (offset, &last_range_end) {
+                        (Some(offset), _) => offset,
+                        (None, Some((last_uri, end))) if *last_uri == uri => *end,
+                        (None, _) => return Err(anyhow::anyhow!("EXT-X-BYTERANGE without offset for {}", uri)),
+                    };
+                    last_range_end = Some((uri.clone(), offset + length));
+                    Some(ByteRange { length, offset })
+                }
+                None => None,
+            };
+            playlist.segments.push(MediaSegment {
+                uri,
+                duration,
+                sequence,
+                key: key.clone(),
+                byte_range,
+                map: map.clone(),
+                discontinuity,
+            });
+            sequence += 1;
+            duration = 0.0;
+            discontinuity = false;
+        }
+    }
+
+    // A live playlist may briefly be empty before the first segment is published.
+    if playlist.segments.is_empty() && playlist.end_list {
+        return Err(anyhow::anyhow!("No segments found in HLS playlist"));
+    }
+    Ok(playlist)
+}
+
+fn parse_rendition(base: &Url, attrs: &str) -> Result<Option<Rendition>> {
+    let attrs = parse_attributes(attrs);
+    let kind = match attrs.get("TYPE").map(String::as_str) {
+        Some("AUDIO") => RenditionKind::Audio,
+        Some("VIDEO") => RenditionKind::Video,
+        Some("SUBTITLES") => RenditionKind::Subtitles,
+        Some("CLOSED-CAPTIONS") => RenditionKind::ClosedCaptions,
+        _ => return Ok(None),
+    };
+    Ok(Some(Rendition {
+        kind,
+        group_id: attrs.get("GROUP-ID").cloned().unwrap_or_default(),
+        name: attrs.get("NAME").cloned().unwrap_or_default(),
+        language: attrs.get("LANGUAGE").cloned(),
+        default: attrs.get("DEFAULT").is_some_and(|v| v == "YES"),
+        uri: attrs.get("URI").map(|uri| base.join(uri)).transpose()?,
+    }))
+}
+
+fn parse_map(base: &Url, attrs: &str, key: Option<Key>) -> Result<InitSection> {
+    let attrs = parse_attributes(attrs);
+    let uri = attrs
+        .get("URI")
+        .ok_or_else(|| anyhow::anyhow!("EXT-X-MAP without URI"))?;
+    let byte_range = attrs
+        .get("BYTERANGE")
+        .map(|v| parse_byte_range(v).map(|(length, offset)| ByteRange { length, offset: offset.unwrap_or(0) }))
+        .transpose()?;
+    Ok(InitSection {
+        uri: base.join(uri)?,
+        byte_range,
+        key: key.filter(|k| k.method == KeyMethod::Aes128),
+    })
+}
+
+/// Parses `<length>[@<offset>]`.
+fn parse_byte_range(value: &str) -> Result<(u64, Option<u64>)> {
+    let invalid = |_| anyhow::anyhow!("Invalid byte range {}", value);
+    match value.trim().split_once('@') {
+        Some((length, offset)) => Ok((length.parse().map_err(invalid)?, Some(offset.parse().map_err(invalid)?))),
+        None => Ok((value.trim().parse().map_err(invalid)?, None)),
+    }
+}
+
+fn parse_key(base: &Url, attrs: &str) -> Result<Option<Key>> {
+    let attrs = parse_attributes(attrs);
+    let method = match attrs.get("METHOD").map(String::as_str) {
+        None | Some("NONE") => return Ok(None),
+        Some("AES-128") => KeyMethod::Aes128,
+        Some("SAMPLE-AES") | Some("SAMPLE-AES-CTR") => KeyMethod::SampleAes,
+        Some(other) => return Err(anyhow::anyhow!("Unsupported HLS encryption method {}", other)),
+    };
+    let uri = attrs
+        .get("URI")
+        .ok_or_else(|| anyhow::anyhow!("EXT-X-KEY without URI"))?;
+    let iv = attrs.get("IV").map(|iv| parse_iv(iv)).transpose()?;
+
+    Ok(Some(Key {
+        method,
+        uri: base.join(uri)?,
+        iv,
+        key_format: attrs.get("KEYFORMAT").cloned(),
+    }))
+}
+
+/// Parses a `0x`-prefixed 128-bit hexadecimal IV.
+fn parse_iv(value: &str) -> Result<[u8; 16]> {
+    let hex = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    let iv = u128::from_str_radix(hex, 16).map_err(|e| anyhow::anyhow!("Invalid IV {}: {}", value, e))?;
+    Ok(iv.to_be_bytes())
+}
+
+/// Decrypts one AES-128 segment (CBC with PKCS#7 padding).
+pub fn decrypt_aes128(data: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>> {
+    decrypt(Cipher::aes_128_cbc(), key, Some(iv), data)
+        .map_err(|e| anyhow::anyhow!("Failed to decrypt segment: {}", e))
+}
+
+/// Parses an attribute list such as `BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2"`.
+pub fn parse_attributes(input: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = input.trim();
+
+    while !rest.is_empty() {
+        let Some((key, after)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, after) = if let Some(quoted) = after.strip_prefix('"') {
+            match quoted.split_once('"') {
+                Some((value, after)) => (value, after),
+                None => (quoted, ""),
+            }
+        } else {
+            after.split_once(',').map_or((after, ""), |(value, after)| (value, after))
+        };
+        attrs.insert(key.trim().to_string(), value.trim().to_string());
+        rest = after.trim_start_matches(',').trim();
+    }
+    attrs
+}
+
+/// Picks the variant whose URI equals `preferred`, or the highest bandwidth one.
+pub fn select_variant<'a>(variants: &'a [Variant], preferred: Option<&str>) -> Option<&'a Variant> {
+    preferred
+        .and_then(|uri| variants.iter().find(|v| v.uri.as_str() == uri))
+        .or_else(|| variants.iter().max_by_key(|v| v.bandwidth))
+}
+
+/// Picks a rendition of `kind` from `group` (any group when `None`): the one whose
+/// URI equals `preferred`, else the default, else the first. Muxed renditions are skipped.
+pub fn select_rendition<'a>(
+    renditions: &'a [Rendition],
+    kind: RenditionKind,
+    group: Option<&str>,
+    preferred: Option<&str>,
+) -> Option<&'a Rendition> {
+    let candidates = renditions
+        .iter()
+        .filter(|r| r.kind == kind && r.uri.is_some() && group.is_none_or(|g| r.group_id == g))
+        .collect::<Vec<_>>();
+    preferred
+        .and_then(|uri| candidates.iter().find(|r| r.uri.as_ref().is_some_and(|u| u.as_str() == uri)))
+        .or_else(|| candidates.iter().find(|r| r.default))
+        .or_else(|| candidates.first())
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://cdn.example/video/master.m3u8").unwrap()
+    }
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-VERSION:4
+#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"
+low/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2400000,RESOLUTION=1280x720
+https://other.example/high/index.m3u8
+";
+
+    fn master(content: &str) -> MasterPlaylist {
+        match parse_playlist(&base(), content).unwrap() {
+            Playlist::Master(master) => master,
+            Playlist::Media(_) => panic!("expected a master playlist"),
+        }
+    }
+
+    fn media(content: &str) -> MediaPlaylist {
+        match parse_playlist(&base(), content).unwrap() {
+            Playlist::Media(media) => media,
+            Playlist::Master(_) => panic!("expected a media playlist"),
+        }
+    }
+
+    #[test]
+    fn attributes_keep_commas_inside_quotes() {
+        let attrs = parse_attributes("BANDWIDTH=1280000, CODECS=\"avc1.4d401f,mp4a.40.2\",NAME=\"English\",DEFAULT=YES");
+        assert_eq!(attrs.get("BANDWIDTH").map(String::as_str), Some("1280000"));
+        assert_eq!(attrs.get("CODECS").map(String::as_str), Some("avc1.4d401f,mp4a.40.2"));
+        assert_eq!(attrs.get("NAME").map(String::as_str), Some("English"));
+        assert_eq!(attrs.get("DEFAULT").map(String::as_str), Some("YES"));
+    }
+
+    #[test]
+    fn master_playlist_resolves_variant_uris() {
+        let master = master(MASTER);
+        assert_eq!(master.variants.len(), 2);
+        let low = &master.variants[0];
+        assert_eq!(low.uri.as_str(), "https://cdn.example/video/low/index.m3u8");
+        assert_eq!(low.bandwidth, 800_000);
+        assert_eq!(low.resolution.as_deref(), Some("640x360"));
+        assert_eq!(low.codecs.as_deref(), Some("avc1.4d401e,mp4a.40.2"));
+        assert_eq!(master.variants[1].uri.as_str(), "https://other.example/high/index.m3u8");
+    }
+
+    #[test]
+    fn select_variant_prefers_the_requested_uri_then_the_best() {
+        let master = master(MASTER);
+        let best = select_variant(&master.variants, None).map(|v| v.bandwidth);
+        assert_eq!(best, Some(2_400_000));
+        let chosen = select_variant(&master.variants, Some("https://cdn.example/video/low/index.m3u8"));
+        assert_eq!(chosen.map(|v| v.bandwidth), Some(800_000));
+        let unknown = select_variant(&master.variants, Some("https://cdn.example/gone.m3u8"));
+        assert_eq!(unknown.map(|v| v.bandwidth), Some(2_400_000));
+    }
+
+    #[test]
+    fn media_playlist_reads_durations_and_sequence_numbers() {
+        let media = media("#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:41
+#EXTINF:5.5,
+seg41.ts
+#EXTINF:6.0,title
+seg42.ts
+#EXT-X-ENDLIST
+");
+        assert_eq!(media.target_duration, 6.0);
+        assert!(media.end_list);
+        let segments = media.segments.iter().map(|s| (s.uri.as_str(), s.duration, s.sequence)).collect::<Vec<_>>();
+        assert_eq!(segments, vec![
+            ("https://cdn.example/video/seg41.ts", 5.5, 41),
+            ("https://cdn.example/video/seg42.ts", 6.0, 42),
+        ]);
+    }
+
+    #[test]
+    fn parse_playlist_rejects_what_is_not_a_playlist() {
+        assert!(parse_playlist(&base(), "<html></html>").is_err());
+        assert!(parse_playlist(&base(), "#EXTM3U\n#EXT-X-ENDLIST\n").is_err());
+        // a live playlist may start out empty
+        assert!(media("#EXTM3U\n#EXT-X-TARGETDURATION:4\n").segments.is_empty());
+    }
+
+    const ENCRYPTED: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-MEDIA-SEQUENCE:7
+#EXT-X-KEY:METHOD=AES-128,URI=\"keys/a.key\"
+#EXTINF:4,
+s7.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"keys/b.key\",IV=0x000102030405060708090A0B0C0D0E0F
+#EXTINF:4,
+s8.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:4,
+s9.ts
+#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://drm\",KEYFORMAT=\"com.apple.streamingkeydelivery\"
+#EXTINF:4,
+s10.ts
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn keys_apply_to_the_segments_that_follow_them() {
+        let media = media(ENCRYPTED);
+        let keys = media.segments.iter().map(|s| s.key.clone()).collect::<Vec<_>>();
+
+        let first = keys[0].as_ref().unwrap();
+        assert_eq!(first.method, KeyMethod::Aes128);
+        assert_eq!(first.uri.as_str(), "https://cdn.example/video/keys/a.key");
+        assert!(first.iv.is_none());
+        assert!(first.is_identity());
+
+        let second = keys[1].as_ref().unwrap();
+        assert_eq!(second.iv, Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]));
+
+        assert!(keys[2].is_none());
+
+        let drm = keys[3].as_ref().unwrap();
+        assert_eq!(drm.method, KeyMethod::SampleAes);
+        assert!(!drm.is_identity());
+    }
+
+    #[test]
+    fn iv_defaults_to_the_media_sequence_number() {
+        let media = media(ENCRYPTED);
+        let first = &media.segments[0];
+        let key = first.key.as_ref().unwrap();
+        let mut expected = [0u8; 16];
+        expected[15] = 7;
+        assert_eq!(key.iv_for(first.sequence), expected);
+
+        let second = &media.segments[1];
+        assert_eq!(second.key.as_ref().unwrap().iv_for(second.sequence)[15], 15);
+    }
+
+    #[test]
+    fn unsupported_encryption_is_an_error() {
+        let content = "#EXTM3U\n#EXT-X-KEY:METHOD=AES-256,URI=\"k\"\n#EXTINF:4,\ns.ts\n";
+        assert!(parse_playlist(&base(), content).is_err());
+        let content = "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"k\",IV=0xZZ\n#EXTINF:4,\ns.ts\n";
+        assert!(parse_playlist(&base(), content).is_err());
+    }
+
+    #[test]
+    fn aes128_round_trip() {
+        let key = *b"0123456789abcdef";
+        let iv = (42u128).to_be_bytes();
+        let plain = b"not quite a transport stream, but close enough".to_vec();
+        let encrypted = openssl::symm::encrypt(Cipher::aes_128_cbc(), &key, Some(&iv), &plain).unwrap();
+        assert_ne!(encrypted, plain);
+        assert_eq!(decrypt_aes128(&encrypted, &key, &iv).unwrap(), plain);
+        // a wrong key usually fails to unpad, and never yields the plaintext
+        assert_ne!(decrypt_aes128(&encrypted, b"fedcba9876543210", &iv).ok(), Some(plain));
+    }
+
+    #[tokio::test]
+    async fn decrypts_a_locally_served_playlist() {
+        use axum::{Router, routing::get};
+
+        let key = *b"0123456789abcdef";
+        let plain = vec![0x47u8; 188 * 4];
+        let playlist = "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:3\n#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\n#EXTINF:2,\nseg.ts\n#EXT-X-ENDLIST\n";
+        // no IV in the playlist, so the segment is encrypted with its sequence number
+        let segment = openssl::symm::encrypt(Cipher::aes_128_cbc(), &key, Some(&3u128.to_be_bytes()), &plain).unwrap();
+
+        let app = Router::new()
+            .route("/live/index.m3u8", get(move || async move { playlist }))
+            .route("/live/key.bin", get(move || async move { key.to_vec() }))
+            .route("/live/seg.ts", get(move || async move { segment }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/live/index.m3u8", addr);
+        let Playlist::Media(media) = fetch_playlist(client.get(&url)).await.unwrap() else {
+            panic!("expected a media playlist");
+        };
+        let segment = &media.segments[0];
+        let key_info = segment.key.as_ref().unwrap();
+        let secret = client.get(key_info.uri.clone()).send().await.unwrap().bytes().await.unwrap();
+        let data = client.get(segment.uri.clone()).send().await.unwrap().bytes().await.unwrap();
+
+        let secret: [u8; 16] = secret.as_ref().try_into().unwrap();
+        let decrypted = decrypt_aes128(&data, &secret, &key_info.iv_for(segment.sequence)).unwrap();
+        assert_eq!(decrypted, plain);
+    }
+
+    #[test]
+    fn byte_ranges_continue_from_the_previous_sub_range() {
+        let media = media("#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXTINF:4,
+#EXT-X-BYTERANGE:1000@500
+all.ts
+#EXTINF:4,
+#EXT-X-BYTERANGE:2000
+all.ts
+#EXTINF:4,
+other.ts
+#EXT-X-ENDLIST
+");
+        let ranges = media.segments.iter().map(|s| s.byte_range).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![
+            Some(ByteRange { length: 1000, offset: 500 }),
+            Some(ByteRange { length: 2000, offset: 1500 }),
+            None,
+        ]);
+        assert_eq!(ranges[1].map(|r| r.header()), Some("bytes=1500-3499".to_string()));
+    }
+
+    #[test]
+    fn byte_range_without_offset_needs_a_previous_range_of_the_same_resource() {
+        let content = "#EXTM3U\n#EXTINF:4,\n#EXT-X-BYTERANGE:100@0\na.ts\n#EXTINF:4,\n#EXT-X-BYTERANGE:100\nb.ts\n";
+        assert!(parse_playlist(&base(), content).is_err());
+        assert!(parse_playlist(&base(), "#EXTM3U\n#EXTINF:4,\n#EXT-X-BYTERANGE:x@1\na.ts\n").is_err());
+    }
+
+    #[test]
+    fn init_sections_and_discontinuities_are_attached_to_segments() {
+        let media = media("#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-KEY:METHOD=AES-128,URI=\"k.key\"
+#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"
+#EXTINF:4,
+a.m4s
+#EXT-X-DISCONTINUITY
+#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"k2.key\"
+#EXT-X-MAP:URI=\"init2.mp4\"
+#EXTINF:4,
+b.m4s
+#EXTINF:4,
+c.m4s
+#EXT-X-ENDLIST
+");
+        let [a, b, c] = &media.segments[..] else {
+            panic!("expected three segments");
+        };
+        let map = a.map.as_ref().unwrap();
+        assert_eq!(map.uri.as_str(), "https://cdn.example/video/init.mp4");
+        assert_eq!(map.byte_range, Some(ByteRange { length: 720, offset: 0 }));
+        // an AES-128 key in effect covers the init section as well
+        assert_eq!(map.key.as_ref().map(|k| k.method), Some(KeyMethod::Aes128));
+
+        let map2 = b.map.as_ref().unwrap();
+        assert_eq!(map2.uri.as_str(), "https://cdn.example/video/init2.mp4");
+        assert!(map2.byte_range.is_none());
+        // SAMPLE-AES leaves the init section in the clear
+        assert!(map2.key.is_none());
+        assert_eq!(c.map.as_ref(), Some(map2));
+
+        assert!(!a.discontinuity);
+        assert!(b.discontinuity);
+        assert!(!c.discontinuity);
+    }
+
+    const WITH_RENDITIONS: &str = "#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"English\",LANGUAGE=\"en\",DEFAULT=YES,URI=\"audio/en.m3u8\"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"Deutsch\",LANGUAGE=\"de\",URI=\"audio/de.m3u8\"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"muxed\",NAME=\"Main\",DEFAULT=YES
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"English\",URI=\"subs/en.m3u8\"
+#EXT-X-MEDIA:TYPE=FOO,GROUP-ID=\"x\",NAME=\"ignored\"
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,AUDIO=\"aud\",SUBTITLES=\"subs\"
+video.m3u8
+";
+
+    #[test]
+    fn master_playlist_lists_alternate_renditions() {
+        let master = master(WITH_RENDITIONS);
+        assert_eq!(master.renditions.len(), 4);
+        let english = &master.renditions[0];
+        assert_eq!(english.kind, RenditionKind::Audio);
+        assert_eq!(english.group_id, "aud");
+        assert_eq!(english.language.as_deref(), Some("en"));
+        assert!(english.default);
+        assert_eq!(english.uri.as_ref().map(Url::as_str), Some("https://cdn.example/video/audio/en.m3u8"));
+        assert!(master.renditions[2].uri.is_none());
+
+        let variant = &master.variants[0];
+        assert_eq!(variant.audio.as_deref(), Some("aud"));
+        assert_eq!(variant.subtitles.as_deref(), Some("subs"));
+    }
+
+    #[test]
+    fn select_rendition_prefers_requested_then_default_then_first() {
+        let master = master(WITH_RENDITIONS);
+        let name = |r: Option<&Rendition>| r.map(|r| r.name.clone());
+        let pick = |group, preferred| select_rendition(&master.renditions, RenditionKind::Audio, group, preferred);
+
+        assert_eq!(name(pick(Some("aud"), None)), Some("English".to_string()));
+        assert_eq!(name(pick(Some("aud"), Some("https://cdn.example/video/audio/de.m3u8"))), Some("Deutsch".to_string()));
+        // muxed renditions have nothing to download
+        assert_eq!(name(pick(Some("muxed"), None)), None);
+        assert_eq!(
+            name(select_rendition(&master.renditions, RenditionKind::Subtitles, None, None)),
+            Some("English".to_string())
+        );
+    }
+}
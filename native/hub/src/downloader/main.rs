@@ -1,19 +1,23 @@
 use anyhow::Result;
+use chrono::{Datelike, Local, Timelike};
 use futures::StreamExt;
 use futures::future::join_all;
 use indexmap::IndexMap;
-use reqwest::header::{ACCEPT_RANGES, CONTENT_LENGTH, RANGE};
+use reqwest::{
+    StatusCode,
+    header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+};
 use std::{
-    collections::HashSet, path::PathBuf, sync::{
-        Arc, atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering}
-    }, time::{Duration, SystemTime, UNIX_EPOCH}
+    collections::{HashMap, HashSet, VecDeque}, path::PathBuf, sync::{
+        Arc, Mutex as StdMutex, RwLock as StdRwLock, atomic::{AtomicBool, AtomicU64, Ordering}
+    }, time::{Duration, Instant, SystemTime, UNIX_EPOCH}
 };
 use tokio::{
     fs::File as TokioFile,
-    io::{AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, SeekFrom},
     sync::{mpsc, Mutex, Notify, RwLock},
     task::JoinHandle,
-    time::{timeout, interval, Interval, Instant, sleep_until},
+    time::{timeout, interval, Interval},
 };
 use uuid::Uuid;
 use crate::utils::logger;
@@ -22,64 +26,166 @@ use rinf::RustSignal;
 use crate::utils::{
     types::{
         HeadData, DownloadState, DownloadInfo,
-        WorkerEvent, DMSettings,
+        WorkerEvent, DMSettings, SegmentInfo, DownloadOptions, BandwidthWeight, ScheduleRule, QueueMove, DependencyPolicy, DEFAULT_QUEUE,
+        RemoteChangePolicy, RemoteValidator, ErrorKind,
     },
-    helper::calc_speed,
-    url::is_hls_url,
+    helper::{calc_speed, now_unix},
+    url::{header_map, is_dash_url, is_hls_url, probe_url},
+    checksum::{hash_file, Hasher},
+};
+use crate::signals::{DownloadGlance, DownloadList, ScheduleStatus};
+use super::{
+    dash::{self, ContentKind, Segments},
+    error::{self, DownloadError},
+    hls::{self, ByteRange, InitSection, KeyMethod, MediaPlaylist, MediaSegment, Playlist, RenditionKind},
+    segment::{self, SharedSegment, StallDetector},
+    storage::Storage,
+    throttle::{allocate, Demand, Throttle},
 };
-use crate::signals::{DownloadGlance, DownloadList};
 
 const HISTORY_SAMPLE_INTERVAL_SECS: u64 = 1;
 const MAX_HISTORY: usize = 15;
+// segment progress is written to storage every this many samples
+const SEGMENT_FLUSH_SAMPLES: u32 = 2;
+// bandwidth shares are based on the speed over this many recent samples
+const SHARE_SPEED_SAMPLES: usize = 3;
+// a download using less than this fraction of its share gives the rest away...
+const SHARE_UNDERUSE_RATIO: f64 = 0.8;
+// ...keeping this much above its speed to be able to pick up again
+const SHARE_HEADROOM: f64 = 1.25;
+// no share goes below this many bytes per second
+const MIN_SHARE: u64 = 16 * 1024;
+// how often the bandwidth schedule is checked for a new window
+const SCHEDULE_CHECK_SECS: u64 = 20;
+// bytes before a resume offset fetched again to check the partial file...
+const OVERLAP_WINDOW: u64 = 64 * 1024;
+// ...doubling on a mismatch up to this, past which the segment starts over
+const MAX_OVERLAP_WINDOW: u64 = 4 * 1024 * 1024;
+// lines kept in the log of one download
+const MAX_LOG_LINES: usize = 200;
+
+/// HLS keys by URI, shared by the concurrent segment fetches of one download.
+type KeyCache = Mutex<HashMap<reqwest::Url, [u8; 16]>>;
+/// Stored HLS part numbers are `track * PART_TRACK_STRIDE + segment index`.
+const PART_TRACK_STRIDE: usize = 1_000_000;
+
+/// One media playlist to download: the video variant or an alternate rendition.
+struct HlsTrack {
+    kind: RenditionKind,
+    url: String,
+    playlist: MediaPlaylist,
+}
+
+/// State shared by every track and segment of one HLS download.
+struct HlsJob<'a> {
+    client: &'a reqwest::Client,
+    keys: &'a KeyCache,
+    temp_dir: &'a std::path::Path,
+    // parts finished by an earlier run, see `Storage::load_parts`
+    done: &'a HashMap<usize, (String, u64)>,
+}
+
+/// Why a segment stopped reading its response body.
+enum StreamEnd {
+    // the segment is complete, or the download was stopped
+    Done,
+    // the stall detector asked for a fresh connection
+    Stalled,
+    Failed(DownloadError),
+}
 
 #[derive(Debug)]
 pub struct DownloadWorker {
     info: Mutex<DownloadInfo>,
     client: reqwest::Client,
+    // extra headers from `DownloadOptions`, sent with every request
+    headers: HeaderMap,
     settings: Arc<RwLock<DMSettings>>,
     paused: AtomicBool,
     started: AtomicBool,
     cancel: AtomicBool,
     threads: u64,
-    speed_limit:AtomicU64,
+    // global byte budget, shared with every other download
+    throttle: Arc<Throttle>,
+    // this download's own limit from `DownloadOptions::speed_limit`
+    own_throttle: Throttle,
+    bypass_global_limit: AtomicBool,
+    // this download's weighted share of the global limit, set by the manager
+    share_throttle: Throttle,
+    // set when the server ignores or mangles a range request...
+    ranges_unreliable: AtomicBool,
+    // ...or answers one for a different file than the one started on
+    remote_changed: AtomicBool,
+    // limit of the named queue the download is in, if that queue has one
+    queue_throttle: StdRwLock<Option<Arc<Throttle>>>,
     notify_resume: Notify,
     downloaded: AtomicU64,
+    // finished playlist segments of a stream download
+    parts_done: AtomicU64,
+    parts_total: AtomicU64,
+    // following a live playlist; `stop_recording` ends it gracefully
+    recording: AtomicBool,
+    stop_recording: AtomicBool,
+    notify_stop: Notify,
     history: RwLock<Vec<(u128, u64)>>,
+    segments: RwLock<Vec<SharedSegment>>,
+    // digest of a single-stream transfer, fed as bytes arrive
+    hasher: StdMutex<Option<Hasher>>,
     handles: Mutex<Vec<JoinHandle<anyhow::Result<()>>>>,
+    // what happened to this download, shown with its details
+    log: StdMutex<VecDeque<String>>,
+    storage: Arc<Storage>,
     pub event_tx: mpsc::Sender<WorkerEvent>,
 }
 
 impl DownloadWorker {
+    /// Builds a worker around `info`, either freshly created or loaded from storage.
     pub async fn new(
-        id: Uuid,
+        info: DownloadInfo,
         client: reqwest::Client,
         settings: Arc<RwLock<DMSettings>>,
-        url: String,
-        dest: PathBuf,
+        storage: Arc<Storage>,
+        throttle: Arc<Throttle>,
         event_tx: mpsc::Sender<WorkerEvent>,
     ) -> Arc<Self> {
-        let speed_limit = settings.read().await.speed_limit;
+        let threads = settings.read().await.download_threads as u64;
+        let own_throttle = Throttle::new(info.options.speed_limit.unwrap_or(0));
+        let bypass_global_limit = AtomicBool::new(info.options.bypass_global_limit);
+        let downloaded = info.downloaded;
+        let segments = segment::share(info.segments.clone());
+        let headers = header_map(&info.options.headers).unwrap_or_else(|e| {
+            logger::error(&format!("Ignoring request headers of {}: {:?}", info.id, e));
+            HeaderMap::new()
+        });
         Arc::new(Self {
-            info: Mutex::new(DownloadInfo {
-                id,
-                url: url.clone(),
-                dest,
-                total_size: None,
-                downloaded: 0,
-                state: DownloadState::Queued,
-                history: Vec::new(),
-            }),
-            client: client,
-            threads: settings.clone().read().await.download_threads as u64,
-            settings: settings,
+            info: Mutex::new(info),
+            client,
+            headers,
+            threads,
+            settings,
             paused: AtomicBool::new(false),
             started: AtomicBool::new(false),
             cancel: AtomicBool::new(false),
-            speed_limit: AtomicU64::new(speed_limit),
+            throttle,
+            own_throttle,
+            bypass_global_limit,
+            share_throttle: Throttle::default(),
+            queue_throttle: StdRwLock::new(None),
+            ranges_unreliable: AtomicBool::new(false),
+            remote_changed: AtomicBool::new(false),
             notify_resume: Notify::new(),
-            downloaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(downloaded),
+            parts_done: AtomicU64::new(0),
+            parts_total: AtomicU64::new(0),
+            recording: AtomicBool::new(false),
+            stop_recording: AtomicBool::new(false),
+            notify_stop: Notify::new(),
             history: RwLock::new(Vec::new()),
+            segments: RwLock::new(segments),
+            hasher: StdMutex::new(None),
             handles: Mutex::new(Vec::new()),
+            log: StdMutex::new(VecDeque::new()),
+            storage,
             event_tx,
         })
     }
@@ -90,20 +196,55 @@ impl DownloadWorker {
         }
         self.started.store(true, Ordering::SeqCst);
 
-        let threads = self.threads;
         let (url, dest) = self.extract_info().await;
+        let merge_inputs = self.info.lock().await.options.merge_inputs.clone();
+        if !merge_inputs.is_empty() {
+            self.spawn_merge_task(merge_inputs, &dest).await;
+            return self.spawn_sampler_and_monitor().await;
+        }
         let head_data = self.fetch_head(&url).await?;
 
         let is_hls = is_hls_url(&url, &head_data.content_type);
-
-        if is_hls {
-            self.spawn_hls_download_task(&url, &dest).await?;
+        let is_dash = !is_hls && is_dash_url(&url, &head_data.content_type);
+
+        if is_hls || is_dash {
+            self.downloaded.store(0, Ordering::SeqCst);
+            self.segments.write().await.clear();
+            self.reset_hasher(false).await;
+            self.persist().await;
+            self.spawn_stream_download_task(&url, &dest, is_dash).await?;
         } else {
-            self.update_total_size(head_data.total_size).await;
-            let is_single_thread = !head_data.accept_ranges || head_data.total_size.is_none() || threads <= 1;
-            let size = head_data.total_size.unwrap_or(0);
-            self.prepare_file(&dest, size, is_single_thread)?;
-            self.spawn_download_tasks(&url, &dest, size, threads, is_single_thread, head_data.accept_ranges).await?;
+            let changed = {
+                let info = self.info.lock().await;
+                info.downloaded > 0 && info.validator.differs_from(&head_data.validator)
+            };
+            if changed {
+                let policy = self.settings.read().await.on_remote_change;
+                if policy == RemoteChangePolicy::Fail {
+                    let err_str = format!("Remote file changed since the download started: {}", url);
+                    logger::error(&err_str);
+                    self.set_state(DownloadState::Error(ErrorKind::Protocol, err_str.clone())).await;
+                    let id = self.info.lock().await.id;
+                    let _ = self.event_tx.send(WorkerEvent::Error(id, err_str)).await;
+                    return Ok(());
+                }
+                logger::debug(&format!("Remote file {} changed, starting over", url));
+            }
+            let resumable = if changed { None } else { self.resumable_segments(&dest, &head_data).await };
+            let segments = match resumable {
+                Some(segments) => {
+                    logger::debug(&format!("Resuming {} from {} saved segments", url, segments.len()));
+                    segments
+                }
+                None => self.fresh_segments(&dest, &head_data).await?,
+            };
+            let written = segments.iter().map(|s| s.written).sum();
+            // Bytes already on disk were never hashed, those downloads get a verification pass
+            self.reset_hasher(segments.len() == 1 && written == 0).await;
+            self.downloaded.store(written, Ordering::SeqCst);
+            *self.segments.write().await = segment::share(segments);
+            self.persist().await;
+            self.spawn_download_tasks(&url, &dest, head_data.accept_ranges).await?;
         }
 
         self.spawn_sampler_and_monitor().await?;
@@ -124,6 +265,7 @@ impl DownloadWorker {
 
         // This is a fresh start (either brand new or from a queued-paused state)
         self.paused.store(false, Ordering::SeqCst); // Explicitly reset the flag
+        self.cancel.store(false, Ordering::SeqCst);
 
         let mut info = self.info.lock().await;
         match info.state {
@@ -131,7 +273,7 @@ impl DownloadWorker {
                 let _ = self.event_tx.send(WorkerEvent::Completed(info.id)).await;
                 Ok(true)
             }
-            DownloadState::Running => Ok(true),
+            DownloadState::Running | DownloadState::Recording => Ok(true),
             _ => {
                 info.state = DownloadState::Running;
                 Ok(false)
@@ -139,34 +281,21 @@ impl DownloadWorker {
         }
     }
 
-    async fn fetch_head(&self, url: &str) -> Result<HeadData> {
-        let client = self.client.clone();
-        let head = client.head(url).send().await?;
-        let status = head.status();
-
-        let total_size = head
-            .headers()
-            .get(CONTENT_LENGTH)
-            .and_then(|hv| hv.to_str().ok())
-            .and_then(|s| s.parse::<u64>().ok());
-
-        let accept_ranges = head
-            .headers()
-            .get(ACCEPT_RANGES)
-            .and_then(|hv| hv.to_str().ok())
-            .map(|s| s.to_ascii_lowercase().contains("bytes"))
-            .unwrap_or(false);
-
-        let content_type = head
-            .headers()
-            .get(reqwest::header::CONTENT_TYPE)
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string());
+    /// Byte ranges only line up with the file when the body is sent as is, so the
+    /// browser-like `Accept-Encoding` of the client is overridden.
+    fn get(&self, client: &reqwest::Client, url: &str) -> reqwest::RequestBuilder {
+        client.get(url)
+            .headers(self.headers.clone())
+            .header(ACCEPT_ENCODING, HeaderValue::from_static("identity"))
+    }
 
+    async fn fetch_head(&self, url: &str) -> Result<HeadData> {
+        let info = probe_url(&self.client, url, &self.headers).await?;
         Ok(HeadData {
-            total_size,
-            accept_ranges,
-            content_type,
+            total_size: info.total_size,
+            accept_ranges: info.accept_ranges,
+            content_type: info.content_type,
+            validator: info.validator,
         })
     }
 
@@ -177,47 +306,85 @@ impl DownloadWorker {
         }
     }
 
+    /// Writes the current snapshot of this download to storage. Segments are saved with
+    /// their flushed progress only, so a resume never skips bytes that did not reach the file.
+    async fn persist(&self) {
+        let mut info = self.snapshot_info().await;
+        info.segments = segment::flushed_snapshot(&self.segments.read().await).await;
+        if let Err(e) = self.storage.save(&info).await {
+            logger::error(&format!("Failed to persist download {}: {:?}", info.id, e));
+        }
+    }
+
+    async fn set_state(&self, state: DownloadState) {
+        {
+            let mut info = self.info.lock().await;
+            info.state = state;
+            info.downloaded = self.downloaded.load(Ordering::SeqCst);
+        }
+        self.persist().await;
+    }
+
     async fn extract_info(&self) -> (String, std::path::PathBuf) {
         let info = self.info.lock().await;
         (info.url.clone(), info.dest.clone())
     }
 
+    /// Returns the stored segments when the partial file on disk can be continued.
+    async fn resumable_segments(&self, dest: &std::path::Path, head: &HeadData) -> Option<Vec<SegmentInfo>> {
+        let segments = segment::snapshot(&self.segments.read().await).await;
+        let total_size = self.info.lock().await.total_size;
+        if segments.is_empty() || !head.accept_ranges || head.total_size.is_none() || head.total_size != total_size {
+            return None;
+        }
+        let len = tokio::fs::metadata(dest).await.ok()?.len();
+        if segments.iter().any(|s| s.position() > len) {
+            return None;
+        }
+        Some(segments)
+    }
+
+    /// Sets up a transfer from scratch: records size and validators of the remote file,
+    /// creates the destination and splits it into segments.
+    async fn fresh_segments(&self, dest: &std::path::Path, head_data: &HeadData) -> Result<Vec<SegmentInfo>> {
+        self.update_total_size(head_data.total_size).await;
+        self.info.lock().await.validator = head_data.validator.clone();
+        let is_single_thread = !head_data.accept_ranges || head_data.total_size.is_none() || self.threads <= 1;
+        let size = head_data.total_size.unwrap_or(0);
+        self.prepare_file(dest, size, is_single_thread)?;
+        if is_single_thread {
+            // An unknown size leaves the range open-ended
+            let end = head_data.total_size.filter(|s| *s > 0).map_or(u64::MAX, |s| s - 1);
+            Ok(vec![SegmentInfo::new(0, end)])
+        } else {
+            Ok(segment::split_even(size, self.threads))
+        }
+    }
+
     fn prepare_file(&self, dest: &std::path::Path, size: u64, is_single_thread: bool) -> Result<()> {
         let f = std::fs::File::create(dest)?;
         if !is_single_thread {f.set_len(size)?};
         Ok(())
     }
 
-    async fn spawn_download_tasks(self: &Arc<Self>, url: &str, dest: &std::path::Path, size: u64, threads: u64, is_single_thread: bool, accept_ranges: bool) -> Result<()> {
+    async fn spawn_download_tasks(self: &Arc<Self>, url: &str, dest: &std::path::Path, accept_ranges: bool) -> Result<()> {
         let client = self.client.clone();
         let mut handles = Vec::new();
-        
-        if is_single_thread {
-            // For single-threaded, we use a single task covering the whole (potentially unknown) range
+        let segments = self.segments.read().await.clone();
+
+        for (i, seg) in segments.into_iter().enumerate() {
+            if seg.range.lock().await.is_complete() {
+                continue;
+            }
+            let client = client.clone();
             let worker = Arc::clone(self);
             let url = url.to_string();
             let dest = dest.to_path_buf();
+
             let h = tokio::spawn(async move {
-                worker.download_task(0, &client, &url, &dest, 0, size.saturating_sub(1), true, accept_ranges).await
+                worker.download_task(i, &client, &url, &dest, seg, accept_ranges).await
             });
             handles.push(h);
-        } else {
-            // Multi-threaded segment logic
-            let part_size = size / threads;
-            for i in 0..threads {
-                let start = i as u64 * part_size;
-                let end = if i == threads - 1 { size - 1 } else { start + part_size - 1 };
-
-                let client = client.clone();
-                let worker = Arc::clone(self);
-                let url = url.to_string();
-                let dest = dest.to_path_buf();
-
-                let h = tokio::spawn(async move {
-                    worker.download_task(i, &client, &url, &dest, start, end, false, true).await
-                });
-                handles.push(h);
-            }
         }
 
         let mut guard = self.handles.lock().await;
@@ -225,21 +392,146 @@ impl DownloadWorker {
         Ok(())
     }
 
+    /// Downloads `segment`, then keeps the connection busy by taking over
+    /// half of the largest range that is still left.
     async fn download_task(
         self: &Arc<Self>,
-        i: u64,
+        mut i: usize,
         client: &reqwest::Client,
         url: &str,
         dest: &std::path::Path,
-        start: u64,
-        end: u64,
-        is_single_thread: bool,
+        mut segment: SharedSegment,
         accept_ranges: bool,
     ) -> Result<()> {
-        let worker = Arc::clone(self);
+        if accept_ranges {
+            self.verify_overlap(i, client, url, dest, &segment).await?;
+        }
+        loop {
+            self.download_segment(i, client, url, dest, &segment, accept_ranges).await?;
+            if !accept_ranges || self.cancel.load(Ordering::SeqCst) || self.interrupted() {
+                return Ok(());
+            }
+
+            // Split and register under one lock so a flush never misses the stolen range
+            let mut segments = self.segments.write().await;
+            let Some(stolen) = segment::split_largest(&segments).await else {
+                return Ok(());
+            };
+            logger::debug(&format!(
+                "Segment {} finished early, taking over bytes {}-{}", i, stolen.start, stolen.end
+            ));
+            i = segments.len();
+            segment = segment::share_one(stolen);
+            segments.push(segment.clone());
+        }
+    }
+
+    /// Fetches the bytes just before where `segment` resumes and compares them with the
+    /// partial file. A mismatch moves the resume point back to the last matching byte,
+    /// widening the window while its first byte already differs; the segment starts over
+    /// once even the widest window does not match. A failed request leaves the segment as it is.
+    async fn verify_overlap(
+        &self,
+        i: usize,
+        client: &reqwest::Client,
+        url: &str,
+        dest: &std::path::Path,
+        segment: &SharedSegment,
+    ) -> Result<()> {
+        let (start, resume_at) = {
+            let seg = segment.range.lock().await;
+            (seg.start, seg.position())
+        };
+        if resume_at == start {
+            return Ok(());
+        }
+
+        let mut window = OVERLAP_WINDOW;
+        let good_until = loop {
+            let from = resume_at.saturating_sub(window).max(start);
+            let remote = match self.fetch_range(client, url, from, resume_at - 1).await {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    self.log(format!("Segment {}: could not verify the bytes before {}: {}", i, resume_at, e));
+                    return Ok(());
+                }
+            };
+            let mut local = vec![0; remote.len()];
+            let mut file = TokioFile::open(dest).await?;
+            file.seek(SeekFrom::Start(from)).await?;
+            file.read_exact(&mut local).await?;
+
+            match compare_overlap(&remote, &local, from, start, window) {
+                Overlap::Matches => break resume_at,
+                Overlap::GoodUntil(position) => break position,
+                Overlap::StepBack => {
+                    self.log(format!(
+                        "Segment {}: first byte of the {}-byte window before {} differs, stepping back",
+                        i, resume_at - from, resume_at
+                    ));
+                    window *= 2;
+                }
+            }
+        };
+
+        if good_until == resume_at {
+            self.log(format!("Segment {}: bytes before {} match the server, resuming", i, resume_at));
+            return Ok(());
+        }
+        if good_until == start {
+            self.log(format!("Segment {}: partial data does not match the server, restarting it from {}", i, start));
+        } else {
+            self.log(format!("Segment {}: data differs from byte {}, resuming there instead of {}", i, good_until, resume_at));
+        }
+        let mut seg = segment.range.lock().await;
+        let rewound = seg.position() - good_until;
+        seg.written -= rewound;
+        segment.flushed.fetch_min(seg.written, Ordering::SeqCst);
+        self.downloaded.fetch_sub(rewound, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Fetches bytes `from` to `to` (inclusive) of the file the download started on.
+    async fn fetch_range(&self, client: &reqwest::Client, url: &str, from: u64, to: u64) -> Result<Vec<u8>> {
+        let (validator, total_size, download_timeout) = {
+            let (validator, total_size) = {
+                let info = self.info.lock().await;
+                (info.validator.clone(), info.total_size)
+            };
+            (validator, total_size, self.settings.read().await.download_timeout)
+        };
+        let mut request_builder = self.get(client, url).header(RANGE, format!("bytes={}-{}", from, to));
+        if let Some(if_range) = validator.if_range() {
+            request_builder = request_builder.header(IF_RANGE, if_range);
+        }
+        let fetch = async {
+            let resp = request_builder.send().await?.error_for_status()?;
+            if resp.status() != StatusCode::PARTIAL_CONTENT {
+                return Err(anyhow::anyhow!("status {} instead of 206", resp.status()));
+            }
+            check_range_response(&resp, from, to, total_size)?;
+            let bytes = resp.bytes().await?;
+            if bytes.len() as u64 != to - from + 1 {
+                return Err(anyhow::anyhow!("got {} bytes instead of {}", bytes.len(), to - from + 1));
+            }
+            Ok(bytes.to_vec())
+        };
+        timeout(Duration::from_secs(download_timeout), fetch)
+            .await
+            .map_err(|_| anyhow::anyhow!("no answer for {}s", download_timeout))?
+    }
 
-        let mut segment_progress = 0u64;
-        let mut attempt = 1u8;
+    async fn download_segment(
+        self: &Arc<Self>,
+        i: usize,
+        client: &reqwest::Client,
+        url: &str,
+        dest: &std::path::Path,
+        segment: &SharedSegment,
+        accept_ranges: bool,
+    ) -> Result<()> {
+        let worker = Arc::clone(self);
+        let mut attempt = 1u32;
         
         let (download_timeout, download_retries) = {
             let s = self.settings.read().await;
@@ -247,8 +539,7 @@ impl DownloadWorker {
         };
 
         loop {
-            
-            while self.paused.load(std::sync::atomic::Ordering::SeqCst) {
+            while self.paused.load(Ordering::SeqCst) {
                 self.notify_resume.notified().await;
             }
             if self.cancel.load(Ordering::SeqCst) {
@@ -256,121 +547,164 @@ impl DownloadWorker {
                 return Ok(());
             }
 
+            let (current_start, end) = {
+                let mut seg = segment.range.lock().await;
+                if !accept_ranges && seg.written > 0 {
+                    // Without range support a retry has to start over
+                    self.downloaded.fetch_sub(seg.written, Ordering::SeqCst);
+                    seg.written = 0;
+                    segment.flushed.store(0, Ordering::SeqCst);
+                    let streaming = self.hasher.lock().is_ok_and(|h| h.is_some());
+                    self.reset_hasher(streaming).await;
+                }
+                if seg.is_complete() {
+                    return Ok(());
+                }
+                (seg.position(), seg.end)
+            };
 
-            let current_start = start + segment_progress;
-            if current_start >= end {
-                return Ok(());
-            }
-
-            let mut request_builder = client.get(url);
+            let (validator, total_size) = {
+                let info = self.info.lock().await;
+                (info.validator.clone(), info.total_size)
+            };
+            let mut request_builder = self.get(client, url);
             if accept_ranges {
-                let range = format!("bytes={}-{}", current_start, end);
+                let range = if end == u64::MAX {
+                    format!("bytes={}-", current_start)
+                } else {
+                    format!("bytes={}-{}", current_start, end)
+                };
                 request_builder = request_builder.header(RANGE, &range);
+                // a changed file then comes back whole, instead of a range of the new one
+                if let Some(if_range) = validator.if_range() {
+                    request_builder = request_builder.header(IF_RANGE, if_range);
+                }
             }
             let resp = match request_builder.send().await {
-                Ok(r) => match r.error_for_status() {
-                    Ok(v) => v,
-                    Err(e) => return Err(anyhow::anyhow!("Segment {} bad status: {}", i, e)),
-                },
+                Ok(r) if r.status().is_success() => r,
+                Ok(r) => {
+                    self.retry_or_give_up(&format!("Segment {}", i), &mut attempt, download_retries, DownloadError::from_response(&r)).await?;
+                    continue;
+                }
                 Err(e) => {
-                    logger::error(&format!("Segment {} request failed: {:?}", i, e));
-                    if attempt > download_retries {
-                        self.cancel().await?;
-                        return Err(anyhow::anyhow!("Segment {} request failed: {:?}", i, e))
-                    }
-                    attempt += 1;
+                    self.retry_or_give_up(&format!("Segment {}", i), &mut attempt, download_retries, e.into()).await?;
                     continue;
                 }
             };
 
+            if accept_ranges && validator.differs_from(&response_validator(&resp)) {
+                // The monitor handles this according to the remote change policy
+                self.remote_changed.store(true, Ordering::SeqCst);
+                return Err(DownloadError::Protocol(format!("segment {}: remote file changed", i)).into());
+            }
+            if accept_ranges && let Err(e) = check_range_response(&resp, current_start, end, total_size) {
+                // The monitor restarts the download as a single stream once all segments stop
+                self.ranges_unreliable.store(true, Ordering::SeqCst);
+                return Err(DownloadError::Protocol(format!("segment {} range not honoured: {}", i, e)).into());
+            }
+
             let mut file = TokioFile::options().write(true).open(dest).await?;
             if accept_ranges {
                 file.seek(SeekFrom::Start(current_start)).await?;
+            } else {
+                file.set_len(0).await?;
             }
             let mut stream = resp.bytes_stream();
+            // Flushed as often as the sampler persists progress, not after every chunk
+            let flush_every = Duration::from_secs(HISTORY_SAMPLE_INTERVAL_SECS * SEGMENT_FLUSH_SAMPLES as u64);
+            let mut last_flush = Instant::now();
+
+            let stream_end = loop {
+                let next_chunk = tokio::select! {
+                    next = timeout(Duration::from_secs(download_timeout), stream.next()) => match next {
+                        Ok(next_chunk) => next_chunk,
+                        Err(_) => break StreamEnd::Failed(DownloadError::Network(format!("no data for {}s", download_timeout))),
+                    },
+                    _ = segment.reconnect.notified() => break StreamEnd::Stalled,
+                };
 
-            while let Ok(next_chunk) = timeout(
-                Duration::from_secs(download_timeout),
-                stream.next(),
-            ).await {
                 while self.paused.load(Ordering::SeqCst) {
                     self.notify_resume.notified().await;
                 }
-                
                 if self.cancel.load(Ordering::SeqCst) {
                     logger::debug(&format!("Segment {} cancelled", i));
-                    return Ok(());
+                    break StreamEnd::Done;
+                }
+                if self.interrupted() {
+                    break StreamEnd::Done;
                 }
 
-                let next_chunk = match next_chunk {
-                    Some(Ok(chunk)) => {
-                        if chunk.is_empty() {
-                            continue;
-                        }
-                        chunk
-                    }
-
-                    Some(Err(e)) => {
-                        logger::error(&format!("Segment {}: stream error {:?}", i, e));
-                        if attempt > download_retries {
-                            self.cancel().await?;
-                            return Err(anyhow::anyhow!("Segment {} stream error: {}", i, e));
-                        }
-                        if !accept_ranges {
-                            self.downloaded.store(0, Ordering::SeqCst);
-                        }
-                        attempt += 1;
-                        continue;
-                    }
-
+                let chunk = match next_chunk {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => break StreamEnd::Failed(e.into()),
                     None => {
-                        if is_single_thread || start + segment_progress >= end {
-                            break;
-                        }
-                        if attempt > download_retries {
-                            self.cancel().await?;
-                            return Err(anyhow::anyhow!("Segment {}: stream ended unexpectedly", i));
+                        if end == u64::MAX || segment.range.lock().await.is_complete() {
+                            break StreamEnd::Done;
                         }
-                        if !accept_ranges {
-                            self.downloaded.store(0, Ordering::SeqCst);
-                        }
-                        attempt += 1;
-                        continue;
+                        break StreamEnd::Failed(DownloadError::Network("stream ended unexpectedly".to_string()));
                     }
                 };
 
-                if let Err(e) = file.write_all(&next_chunk).await {
-                    logger::error(&format!("Segment {}: file write error {:?}", i, e));
-                    if attempt > download_retries {
-                        self.cancel().await?;
-                        return Err(anyhow::anyhow!("Segment {} file write failed: {}", i, e));
-                    }
-                    if !accept_ranges {
-                        self.downloaded.store(0, Ordering::SeqCst);
-                    }
-                    attempt += 1;
-                    continue;
+                // The segment lock covers the write so `written` never runs ahead of the file,
+                // and clipping to `remaining` respects an end moved by a split
+                let mut seg = segment.range.lock().await;
+                let len = (chunk.len() as u64).min(seg.remaining()) as usize;
+                if let Err(e) = file.write_all(&chunk[..len]).await {
+                    break StreamEnd::Failed(e.into());
                 }
+                seg.written += len as u64;
+                self.downloaded.fetch_add(len as u64, Ordering::SeqCst);
+                if let Ok(mut hasher) = self.hasher.lock()
+                    && let Some(h) = hasher.as_mut() {
+                    h.update(&chunk[..len]);
+                }
+                if seg.is_complete() {
+                    break StreamEnd::Done;
+                }
+                drop(seg);
 
-                let len = next_chunk.len() as u64;
-                segment_progress += len;
-                self.downloaded.fetch_add(len, Ordering::SeqCst);
+                if last_flush.elapsed() >= flush_every {
+                    if let Err(e) = flush_segment(&mut file, segment).await {
+                        break StreamEnd::Failed(e.into());
+                    }
+                    last_flush = Instant::now();
+                }
+                worker.limit_speed(len).await;
+            };
 
-                if start + segment_progress >= end {
-                    break;
+            // Whatever ended the stream, the bytes written so far may now be persisted
+            let flushed = flush_segment(&mut file, segment).await;
+            let failure = match (stream_end, flushed) {
+                (StreamEnd::Failed(failure), _) => failure,
+                (_, Err(e)) => e.into(),
+                (StreamEnd::Done, Ok(())) => return Ok(()),
+                (StreamEnd::Stalled, Ok(())) => {
+                    logger::debug(&format!("Segment {} stalled, reconnecting", i));
+                    continue;
                 }
-                worker.limit_speed().await;
-            }
+            };
+            self.retry_or_give_up(&format!("Segment {}", i), &mut attempt, download_retries, failure).await?;
+        }
+    }
 
-            if is_single_thread || start + segment_progress >= end {
-                return Ok(());
-            }
+    /// Waits before the next attempt at `what` after `error`, or gives up with it when
+    /// the error is fatal or all `retries` are used.
+    async fn retry_or_give_up(&self, what: &str, attempt: &mut u32, retries: u8, error: DownloadError) -> Result<()> {
+        if !error.is_retryable() || *attempt > u32::from(retries) {
+            logger::error(&format!("{} failed: {}", what, error));
+            // Stop the sibling segments, the monitor reports the error
+            self.cancel.store(true, Ordering::SeqCst);
+            return Err(error.into());
         }
-        // Err(anyhow::anyhow!("Segment {} failed after retries", i))
+        let delay = error.retry_delay(*attempt);
+        logger::error(&format!("{}: {}, retrying in {:.1}s", what, error, delay.as_secs_f64()));
+        *attempt += 1;
+        tokio::time::sleep(delay).await;
+        Ok(())
     }
 
-    async fn spawn_hls_download_task(self: &Arc<Self>, url: &str, dest: &std::path::Path) -> Result<()> {
-        logger::debug(&format!("Starting HLS download for {}", url));
+    async fn spawn_stream_download_task(self: &Arc<Self>, url: &str, dest: &std::path::Path, is_dash: bool) -> Result<()> {
+        logger::debug(&format!("Starting {} download for {}", if is_dash { "DASH" } else { "HLS" }, url));
         let client = self.client.clone();
         let worker = Arc::clone(self);
 
@@ -378,7 +712,7 @@ impl DownloadWorker {
         let dest = dest.to_path_buf();
 
         let h = tokio::spawn(async move {
-            worker.download_hls_stream(&client, &url, &dest).await
+            worker.download_stream(&client, &url, &dest, is_dash).await
         });
 
         let mut handles = self.handles.lock().await;
@@ -386,241 +720,960 @@ impl DownloadWorker {
         Ok(())
     }
 
-    async fn download_hls_stream(
+    /// Muxes the video and audio files of a yt-dlp download into `dest`, then removes them.
+    async fn spawn_merge_task(self: &Arc<Self>, inputs: Vec<PathBuf>, dest: &std::path::Path) {
+        logger::debug(&format!("Merging {} files into {}", inputs.len(), dest.display()));
+        let dest = dest.to_path_buf();
+        let h = tokio::spawn(async move {
+            let mut command = tokio::process::Command::new("ffmpeg");
+            for input in &inputs {
+                command.arg("-i").arg(input);
+            }
+            command.arg("-c").arg("copy")
+                   .arg("-map").arg("0:v:0")
+                   .arg("-map").arg("1:a:0")
+                   .arg("-y").arg(&dest);
+            run_ffmpeg(command).await?;
+            for input in &inputs {
+                let _ = tokio::fs::remove_file(input).await;
+            }
+            Ok(())
+        });
+        self.handles.lock().await.push(h);
+    }
+
+    async fn download_stream(
         self: &Arc<Self>, 
         client: &reqwest::Client, 
         url: &str, 
-        dest: &std::path::Path) -> Result<()> {
-        // 1. Fetch master playlist
-        let playlist_content = client.get(url).send().await?.text().await?;
-
-        // 2. Parse playlist to find segments
-        // For simplicity, assuming it's a media playlist, not a master playlist with variants.
-        let base_url = {
-            let mut url_parts = url.split('/').collect::<Vec<_>>();
-            url_parts.pop();
-            url_parts.join("/") + "/"
-        };
-
-        let mut segment_urls = Vec::new();
-        for line in playlist_content.lines() {
-            if !line.starts_with('#') && !line.is_empty() {
-                if line.starts_with("http") {
-                    segment_urls.push(line.to_string());
-                } else {
-                    segment_urls.push(format!("{}{}", base_url, line));
-                }
+        dest: &std::path::Path,
+        is_dash: bool) -> Result<()> {
+        let tracks = if is_dash {
+            self.fetch_dash_tracks(client, url).await?
+        } else {
+            self.fetch_tracks(client, url).await?
+        };
+
+        // Create a temporary directory for segments
+        let parent = dest.parent().ok_or_else(|| anyhow::anyhow!("Invalid destination {:?}", dest))?;
+        let id = self.info.lock().await.id;
+        let temp_dir = parent.join(format!("temp_{}", id));
+        tokio::fs::create_dir_all(&temp_dir).await?;
+
+        // Segments finished by an earlier run, reused if they are still intact on disk
+        let done = self.storage.load_parts(id).await.unwrap_or_else(|e| {
+            logger::error(&format!("Failed to load HLS progress for {}: {:?}", id, e));
+            HashMap::new()
+        });
+        if !done.is_empty() {
+            logger::debug(&format!("Resuming HLS download {} with {} stored segments", id, done.len()));
+        }
+
+        let live = tracks.first().is_some_and(|t| !t.playlist.end_list);
+        if live {
+            logger::debug(&format!("Recording live HLS stream {}", url));
+            self.stop_recording.store(false, Ordering::SeqCst);
+            self.recording.store(true, Ordering::SeqCst);
+            self.set_state(DownloadState::Recording).await;
+        }
+
+        self.parts_done.store(0, Ordering::SeqCst);
+        self.parts_total.store(0, Ordering::SeqCst);
+        self.info.lock().await.parts = Some((0, 0));
+
+        let keys = Mutex::new(HashMap::new());
+        let job = HlsJob { client, keys: &keys, temp_dir: &temp_dir, done: &done };
+        let has_subtitles = tracks.iter().any(|t| t.kind == RenditionKind::Subtitles);
+        let results = futures::future::try_join_all(
+            tracks.into_iter().enumerate().map(|(t, track)| self.download_hls_track(&job, t, track)),
+        )
+        .await;
+        self.recording.store(false, Ordering::SeqCst);
+
+        let mut indexes = Vec::new();
+        for index in results? {
+            match index {
+                Some(index) => indexes.push(index),
+                None => return Ok(()),
             }
         }
 
-        if segment_urls.is_empty() {
-            return Err(anyhow::anyhow!("No segments found in HLS playlist"));
+        // 6. Mux the tracks with ffmpeg. Its HLS demuxer reads the local playlists, so init
+        // sections, discontinuities and SAMPLE-AES are handled the same as for a remote stream.
+        let mut command = tokio::process::Command::new("ffmpeg");
+        for index in &indexes {
+            command.arg("-allowed_extensions").arg("ALL")
+                   .arg("-i").arg(index);
         }
+        for i in 0..indexes.len() {
+            command.arg("-map").arg(i.to_string());
+        }
+        command.arg("-c").arg("copy");
+        let ext = dest.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+        if has_subtitles && matches!(ext.as_str(), "mp4" | "m4v" | "mov") {
+            // WebVTT cannot be stream-copied into MP4
+            command.arg("-c:s").arg("mov_text");
+        }
+        command.arg("-y").arg(dest);
+        run_ffmpeg(command).await?;
 
-        // Create a temporary directory for segments
-        let temp_dir = dest.parent().unwrap().join(format!("temp_{}", self.info.lock().await.id));
-        tokio::fs::create_dir_all(&temp_dir).await?;
+        self.finish_hls(id, &temp_dir).await
+    }
 
-        // 5. Download all segments
+    /// Downloads every segment of one track, `threads` at a time, and returns the local
+    /// playlist describing them; `None` when the download was cancelled. A live playlist is
+    /// polled again every target duration for segments past the last one.
+    async fn download_hls_track(&self, job: &HlsJob<'_>, t: usize, track: HlsTrack) -> Result<Option<PathBuf>> {
+        let HlsTrack { url, mut playlist, .. } = track;
+        let live = !playlist.end_list;
+        let mut segments: Vec<MediaSegment> = Vec::new();
         let mut segment_paths = Vec::new();
-        for (i, segment_url) in segment_urls.iter().enumerate() {
-            if self.cancel.load(Ordering::SeqCst) {
-                return Ok(());
+        let mut next_sequence = None;
+        loop {
+            let fresh = playlist
+                .segments
+                .into_iter()
+                .filter(|s| next_sequence.is_none_or(|next| s.sequence >= next))
+                .collect::<Vec<_>>();
+            if let Some(key) = fresh.iter().filter_map(|s| s.key.as_ref()).find(|k| !k.is_identity()) {
+                return Err(anyhow::anyhow!(
+                    "HLS stream uses an unsupported key system ({})",
+                    key.key_format.as_deref().unwrap_or_default()
+                ));
             }
-            while self.paused.load(Ordering::SeqCst) {
-                self.notify_resume.notified().await;
+            if let (Some(next), Some(first)) = (next_sequence, fresh.first())
+                && first.sequence > next
+            {
+                logger::error(&format!(
+                    "Live stream {} skipped ahead, segments {}..{} were missed",
+                    url, next, first.sequence
+                ));
+            }
+            if let Some(last) = fresh.last() {
+                next_sequence = Some(last.sequence + 1);
             }
 
-            let segment_path = temp_dir.join(format!("segment_{}.ts", i));
-            let mut file = TokioFile::create(&segment_path).await?;
-            
-            let resp = client.get(segment_url).send().await?;
-            let mut stream = resp.bytes_stream();
-
-            while let Some(chunk) = stream.next().await {
-                while self.paused.load(Ordering::SeqCst) {
-                    self.notify_resume.notified().await;
+            let offset = segments.len();
+            segments.extend(fresh);
+            self.parts_total.fetch_add((segments.len() - offset) as u64, Ordering::SeqCst);
+
+            // `buffered` yields the parts in playlist order
+            let mut parts = futures::stream::iter(offset..segments.len())
+                .map(|i| self.download_hls_part(job, t, i, &segments[i]))
+                .buffered(self.threads.max(1) as usize);
+            while let Some(part) = parts.next().await {
+                match part? {
+                    Some(path) => segment_paths.push(path),
+                    None => return Ok(None),
                 }
-                if self.cancel.load(Ordering::SeqCst) {
-                    logger::debug(&format!("Segment {} cancelled", i));
-                    return Ok(());
+                if self.stop_recording.load(Ordering::SeqCst) {
+                    break;
                 }
-
-                let chunk = chunk?;
-                file.write_all(&chunk).await?;
-                self.downloaded.fetch_add(chunk.len() as u64, Ordering::SeqCst);
             }
-            segment_paths.push(segment_path);
-        }
-
-        // 6. Concatenate segments using ffmpeg
-        let list_path = temp_dir.join("mylist.txt");
-        let mut list_file = TokioFile::create(&list_path).await?;
-        for path in &segment_paths {
-            let line = format!("file '{}'\n", path.to_str().unwrap());
-            list_file.write_all(line.as_bytes()).await?;
-        }
-        list_file.flush().await?;
-
-        let mut command = tokio::process::Command::new("ffmpeg");
-        command.arg("-f").arg("concat")
-               .arg("-safe").arg("0")
-               .arg("-i").arg(&list_path)
-               .arg("-c").arg("copy")
-               .arg("-y")
-               .arg(dest);
+            drop(parts);
+            if self.stop_recording.load(Ordering::SeqCst) {
+                // parts of the batch not fetched yet are left out of the recording
+                let unfetched = segments.len() - segment_paths.len();
+                segments.truncate(segment_paths.len());
+                self.parts_total.fetch_sub(unfetched as u64, Ordering::SeqCst);
+                logger::debug(&format!("Recording of {} stopped by user", url));
+                break;
+            }
 
-        match command.output().await {
-            Ok(output) => {
-                if !output.status.success() {
-                    let stderr = String::from_utf8_lossy(&output.stderr);
-                    return Err(anyhow::anyhow!("ffmpeg failed: {}", stderr));
+            if !live || playlist.end_list || self.live_limit_reached(&segments).await {
+                break;
+            }
+            // A stop that came while the parts were fetched is seen through the flag, one
+            // coming from here on through the already registered `Notified`
+            let stopped = self.notify_stop.notified();
+            tokio::pin!(stopped);
+            stopped.as_mut().enable();
+            if !self.stop_recording.load(Ordering::SeqCst) {
+                let wait = Duration::from_secs_f64(playlist.target_duration.max(1.0));
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = &mut stopped => {}
                 }
             }
-            Err(e) => return Err(anyhow::anyhow!("ffmpeg execution failed: {}", e)),
-        }
+            if self.stop_recording.load(Ordering::SeqCst) {
+                logger::debug(&format!("Recording of {} stopped by user", url));
+                break;
+            }
 
-        // Clean up temp directory
-        tokio::fs::remove_dir_all(&temp_dir).await?;
+            playlist = match hls::fetch_playlist(self.get(job.client, &url)).await {
+                Ok(Playlist::Media(media)) => media,
+                Ok(Playlist::Master(_)) => return Err(anyhow::anyhow!("Live playlist turned into a master playlist")),
+                // Streams often vanish instead of publishing ENDLIST, keep what was recorded.
+                Err(e) => {
+                    logger::error(&format!("Live playlist {} stopped responding: {:?}", url, e));
+                    break;
+                }
+            };
+        }
+        if segment_paths.is_empty() {
+            return Err(anyhow::anyhow!("No segments were recorded from {}", url));
+        }
 
-        Ok(())
+        let Some(maps) = self.download_init_sections(job, t, &segments).await? else {
+            return Ok(None);
+        };
+        self.write_local_playlist(job, t, &segments, &segment_paths, &maps).await.map(Some)
     }
 
-    async fn spawn_sampler_and_monitor(self: &Arc<Self>) -> Result<()> {
-        let stop_flag = Arc::new(Notify::new());
-        let stop_clone = stop_flag.clone();
+    /// Whether a live recording reached its configured duration or size cap.
+    async fn live_limit_reached(&self, segments: &[MediaSegment]) -> bool {
+        let (max_duration, max_size) = {
+            let options = &self.info.lock().await.options;
+            (options.live_max_duration, options.live_max_size)
+        };
+        let duration = segments.iter().map(|s| s.duration).sum::<f64>();
+        max_duration.is_some_and(|max| duration >= max as f64)
+            || max_size.is_some_and(|max| self.downloaded.load(Ordering::SeqCst) >= max)
+    }
 
-        self.spawn_sampler(stop_flag);
-        self.spawn_monitor(stop_clone).await?;
+    /// Removes the temporary segments and their stored progress once the output is written.
+    async fn finish_hls(&self, id: Uuid, temp_dir: &std::path::Path) -> Result<()> {
+        tokio::fs::remove_dir_all(temp_dir).await?;
+        if let Err(e) = self.storage.clear_parts(id).await {
+            logger::error(&format!("Failed to clear HLS progress for {}: {:?}", id, e));
+        }
         Ok(())
     }
 
-    fn spawn_sampler(self: &Arc<Self>, stop_flag: Arc<Notify>) {
-        let sampler_worker = Arc::clone(self);
-        tokio::spawn(async move {
-            let mut samp = interval(Duration::from_secs(HISTORY_SAMPLE_INTERVAL_SECS));
-            loop {
-                while sampler_worker.paused.load(Ordering::SeqCst) {
-                    sampler_worker.notify_resume.notified().await;
-                    continue;
-                }
-                if sampler_worker.cancel.load(Ordering::SeqCst) {
-                    break;
-                }
+    /// Downloads, decrypts and stores segment `i` of track `t`; `None` when the download was cancelled.
+    async fn download_hls_part(
+        &self,
+        job: &HlsJob<'_>,
+        t: usize,
+        i: usize,
+        segment: &MediaSegment,
+    ) -> Result<Option<PathBuf>> {
+        if self.cancel.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        while self.paused.load(Ordering::SeqCst) {
+            self.notify_resume.notified().await;
+        }
 
-                tokio::select! {
-                    _ = samp.tick() => {
-                        let snapshot = sampler_worker.downloaded.load(Ordering::SeqCst);
-                        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-                        sampler_worker.history.write().await.push((ts, snapshot));
-                        let hist_len = sampler_worker.history.read().await.len();
-                        if hist_len > MAX_HISTORY { 
-                            let remove = hist_len - MAX_HISTORY; 
-                            sampler_worker.history.write().await.drain(0..remove);
-                        }
-                    }
-                    _ = stop_flag.notified() => {
-                        break;
+        let part = t * PART_TRACK_STRIDE + i;
+        let segment_path = job.temp_dir.join(format!("t{}_{}.{}", t, i, segment_extension(&segment.uri)));
+        if let Some((uri, size)) = job.done.get(&part)
+            && uri == segment.uri.as_str()
+            && tokio::fs::metadata(&segment_path).await.is_ok_and(|m| m.len() == *size)
+        {
+            self.downloaded.fetch_add(*size, Ordering::SeqCst);
+            self.parts_done.fetch_add(1, Ordering::SeqCst);
+            return Ok(Some(segment_path));
+        }
+
+        let fetched = match segment.key.as_ref().filter(|k| k.method == KeyMethod::Aes128) {
+            // CBC needs the whole segment, and encrypted segments are small enough to hold
+            Some(key) => {
+                let mut data = Vec::new();
+                match self.fetch_hls_resource(job.client, &segment.uri, segment.byte_range, &mut data).await? {
+                    Some(_) => {
+                        let secret = self.fetch_hls_key(job.client, job.keys, &key.uri).await?;
+                        let data = hls::decrypt_aes128(&data, &secret, &key.iv_for(segment.sequence))?;
+                        tokio::fs::write(&segment_path, &data).await?;
+                        Some(data.len() as u64)
                     }
+                    None => None,
                 }
             }
-        });
+            // Anything else goes straight to disk, a DASH representation without
+            // segment information is a single part as large as the whole track
+            None => {
+                let mut file = TokioFile::create(&segment_path).await?;
+                self.fetch_hls_resource(job.client, &segment.uri, segment.byte_range, &mut file).await?
+            }
+        };
+        let Some(size) = fetched else {
+            logger::debug(&format!("Segment {} cancelled", i));
+            return Ok(None);
+        };
+        let id = self.info.lock().await.id;
+        if let Err(e) = self.storage.save_part(id, part, segment.uri.as_str(), size).await {
+            logger::error(&format!("Failed to store HLS segment {} of {}: {:?}", i, id, e));
+        }
+        self.parts_done.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(segment_path))
     }
 
-    async fn spawn_monitor(self: &Arc<Self>, stop_flag: Arc<Notify>) -> Result<()> {
-        let monitor_worker = Arc::clone(self);
-        let handles = {
-            let mut guard = monitor_worker.handles.lock().await;
-            std::mem::take(&mut *guard)
+    /// Fetches each distinct `#EXT-X-MAP` of a track once, in the order they appear.
+    async fn download_init_sections(
+        &self,
+        job: &HlsJob<'_>,
+        t: usize,
+        segments: &[MediaSegment],
+    ) -> Result<Option<Vec<(InitSection, PathBuf)>>> {
+        let mut maps: Vec<(InitSection, PathBuf)> = Vec::new();
+        for segment in segments {
+            let Some(map) = &segment.map else { continue };
+            if maps.iter().any(|(m, _)| m == map) {
+                continue;
+            }
+            let mut data = Vec::new();
+            if self.fetch_hls_resource(job.client, &map.uri, map.byte_range, &mut data).await?.is_none() {
+                return Ok(None);
+            }
+            if let Some(key) = &map.key {
+                let secret = self.fetch_hls_key(job.client, job.keys, &key.uri).await?;
+                data = hls::decrypt_aes128(&data, &secret, &key.iv_for(segment.sequence))?;
+            }
+            let path = job.temp_dir.join(format!("t{}_init_{}.{}", t, maps.len(), segment_extension(&map.uri)));
+            tokio::fs::write(&path, &data).await?;
+            maps.push((map.clone(), path));
+        }
+        Ok(Some(maps))
+    }
+
+    /// Streams one HLS resource (or the given range of it) into `out` and returns the
+    /// bytes written; `None` when the download was cancelled meanwhile. A failed transfer
+    /// is retried like a byte-range segment, picking up after the bytes already written.
+    /// Progress counted for a transfer that fails for good is taken back.
+    async fn fetch_hls_resource<W: AsyncWrite + Unpin>(
+        &self,
+        client: &reqwest::Client,
+        uri: &reqwest::Url,
+        byte_range: Option<ByteRange>,
+        out: &mut W,
+    ) -> Result<Option<u64>> {
+        let download_retries = self.settings.read().await.download_retries;
+        let mut attempt = 1u32;
+        let mut written = 0;
+        loop {
+            let failure = match self.stream_hls_resource(client, uri, byte_range, out, &mut written).await {
+                Ok(finished) => return Ok(finished.then_some(written)),
+                Err(failure) => failure,
+            };
+            if let Err(e) = self.retry_or_give_up(uri.as_str(), &mut attempt, download_retries, failure).await {
+                self.downloaded.fetch_sub(written, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+    }
+
+    /// One attempt of `fetch_hls_resource`, asking only for what follows the `written`
+    /// bytes of earlier attempts.
+    async fn stream_hls_resource<W: AsyncWrite + Unpin>(
+        &self,
+        client: &reqwest::Client,
+        uri: &reqwest::Url,
+        byte_range: Option<ByteRange>,
+        out: &mut W,
+        written: &mut u64,
+    ) -> Result<bool, DownloadError> {
+        let start = byte_range.map_or(0, |r| r.offset) + *written;
+        let length = byte_range.map(|r| r.length.saturating_sub(*written));
+        let mut request = self.get(client, uri.as_str());
+        let ranged = byte_range.is_some() || start > 0;
+        if ranged {
+            let range = match length {
+                Some(length) => ByteRange { length, offset: start }.header(),
+                None => format!("bytes={}-", start),
+            };
+            request = request.header(RANGE, range);
+        }
+        let resp = request.send().await?;
+        if !resp.status().is_success() {
+            return Err(DownloadError::from_response(&resp));
+        }
+        // A server ignoring the range sends the whole resource, cut the range out ourselves
+        let (mut skip, mut left) = if ranged && resp.status() != StatusCode::PARTIAL_CONTENT {
+            (start, length)
+        } else {
+            (0, None)
+        };
+        let download_timeout = self.settings.read().await.download_timeout;
+        let mut stream = resp.bytes_stream();
+
+        loop {
+            let next_chunk = timeout(Duration::from_secs(download_timeout), stream.next())
+                .await
+                .map_err(|_| DownloadError::Network(format!("no data for {}s", download_timeout)))?;
+            let Some(chunk) = next_chunk else { break };
+            while self.paused.load(Ordering::SeqCst) {
+                self.notify_resume.notified().await;
+            }
+            if self.cancel.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+
+            let chunk = chunk?;
+            let skipped = skip.min(chunk.len() as u64);
+            skip -= skipped;
+            let mut data = &chunk[skipped as usize..];
+            if let Some(left) = left.as_mut() {
+                let take = (*left).min(data.len() as u64);
+                data = &data[..take as usize];
+                *left -= take;
+            }
+            out.write_all(data).await?;
+            *written += data.len() as u64;
+            self.downloaded.fetch_add(data.len() as u64, Ordering::SeqCst);
+            self.limit_speed(chunk.len()).await;
+            if left == Some(0) {
+                break;
+            }
+        }
+        out.flush().await?;
+        Ok(true)
+    }
+
+    /// Returns the 16 byte key behind `uri`, fetching it only the first time it is seen.
+    async fn fetch_hls_key(
+        &self,
+        client: &reqwest::Client,
+        keys: &KeyCache,
+        uri: &reqwest::Url,
+    ) -> Result<[u8; 16]> {
+        // Held across the request so concurrent parts sharing a key fetch it once.
+        let mut keys = keys.lock().await;
+        if let Some(key) = keys.get(uri) {
+            return Ok(*key);
+        }
+        let bytes = self.get(client, uri.as_str()).send().await?.error_for_status()?.bytes().await?;
+        let key: [u8; 16] = bytes
+            .as_ref()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Key {} is {} bytes, expected 16", uri, bytes.len()))?;
+        keys.insert(uri.clone(), key);
+        Ok(key)
+    }
+
+    /// Writes a playlist for track `t` pointing at the downloaded segments, their init
+    /// sections and local copies of their keys. Entries are relative to the playlist.
+    async fn write_local_playlist(
+        &self,
+        job: &HlsJob<'_>,
+        t: usize,
+        segments: &[MediaSegment],
+        paths: &[PathBuf],
+        maps: &[(InitSection, PathBuf)],
+    ) -> Result<PathBuf> {
+        let file_name = |path: &std::path::Path| {
+            path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+        };
+        let target = segments.iter().map(|s| s.duration).fold(0.0, f64::max).ceil();
+        let first_sequence = segments.first().map_or(0, |s| s.sequence);
+        let mut out = format!(
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            target, first_sequence
+        );
+        let mut current_key = None;
+        let mut current_map = None;
+
+        for (segment, path) in segments.iter().zip(paths) {
+            if segment.discontinuity {
+                out.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            // AES-128 segments were already decrypted while downloading.
+            let key = segment.key.as_ref().filter(|k| k.method == KeyMethod::SampleAes);
+            if key != current_key {
+                match key {
+                    Some(key) => {
+                        let secret = self.fetch_hls_key(job.client, job.keys, &key.uri).await?;
+                        let key_path = job.temp_dir.join(format!("t{}_key_{}.bin", t, segment.sequence));
+                        tokio::fs::write(&key_path, secret).await?;
+                        out.push_str(&format!("#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"{}\"", file_name(&key_path)));
+                        if let Some(iv) = key.iv {
+                            out.push_str(&format!(",IV=0x{:032x}", u128::from_be_bytes(iv)));
+                        }
+                        out.push('\n');
+                    }
+                    None => out.push_str("#EXT-X-KEY:METHOD=NONE\n"),
+                }
+                current_key = key;
+            }
+            if segment.map.as_ref() != current_map
+                && let Some((_, map_path)) = maps.iter().find(|(m, _)| Some(m) == segment.map.as_ref())
+            {
+                out.push_str(&format!("#EXT-X-MAP:URI=\"{}\"\n", file_name(map_path)));
+                current_map = segment.map.as_ref();
+            }
+            out.push_str(&format!("#EXTINF:{},\n{}\n", segment.duration, file_name(path)));
+        }
+        out.push_str("#EXT-X-ENDLIST\n");
+
+        let index = job.temp_dir.join(format!("track_{}.m3u8", t));
+        tokio::fs::write(&index, out).await?;
+        Ok(index)
+    }
+
+    /// Fetches `url`, following a master playlist to the chosen (or best) variant and the
+    /// audio and subtitle renditions that go with it. The video track comes first.
+    async fn fetch_tracks(&self, client: &reqwest::Client, url: &str) -> Result<Vec<HlsTrack>> {
+        let master = match hls::fetch_playlist(self.get(client, url)).await? {
+            Playlist::Media(playlist) => {
+                return Ok(vec![HlsTrack { kind: RenditionKind::Video, url: url.to_string(), playlist }]);
+            }
+            Playlist::Master(master) => master,
+        };
+
+        let options = self.info.lock().await.options.clone();
+        let variant = hls::select_variant(&master.variants, options.stream_variant.as_deref())
+            .ok_or_else(|| anyhow::anyhow!("Master playlist has no variants"))?;
+        logger::debug(&format!(
+            "Selected HLS variant {} ({} bps, {})",
+            variant.uri,
+            variant.bandwidth,
+            variant.resolution.as_deref().unwrap_or("unknown resolution")
+        ));
+
+        let mut sources = vec![(RenditionKind::Video, variant.uri.clone())];
+        // Audio that is not muxed into the variant is needed for a playable result
+        if let Some(group) = &variant.audio
+            && let Some(audio) = hls::select_rendition(
+                &master.renditions, RenditionKind::Audio, Some(group), options.stream_audio.as_deref(),
+            )
+            && let Some(uri) = &audio.uri
+        {
+            logger::debug(&format!("Selected HLS audio rendition {} ({})", audio.name, uri));
+            sources.push((RenditionKind::Audio, uri.clone()));
+        }
+        // Subtitles only when asked for
+        if let Some(preferred) = options.stream_subtitles.as_deref()
+            && let Some(subtitles) = hls::select_rendition(
+                &master.renditions, RenditionKind::Subtitles, variant.subtitles.as_deref(), Some(preferred),
+            )
+            && let Some(uri) = &subtitles.uri
+        {
+            logger::debug(&format!("Selected HLS subtitle rendition {} ({})", subtitles.name, uri));
+            sources.push((RenditionKind::Subtitles, uri.clone()));
+        }
+
+        let mut tracks = Vec::with_capacity(sources.len());
+        for (kind, uri) in sources {
+            match hls::fetch_playlist(self.get(client, uri.as_str())).await? {
+                Playlist::Media(playlist) => tracks.push(HlsTrack { kind, url: uri.to_string(), playlist }),
+                Playlist::Master(_) => return Err(anyhow::anyhow!("{} is not a media playlist", uri)),
+            }
+        }
+        Ok(tracks)
+    }
+
+    /// Turns the chosen video and audio representations of a static manifest into tracks,
+    /// periods following each other with a discontinuity in between.
+    async fn fetch_dash_tracks(&self, client: &reqwest::Client, url: &str) -> Result<Vec<HlsTrack>> {
+        let manifest = dash::fetch_manifest(self.get(client, url)).await?;
+        if manifest.dynamic {
+            return Err(anyhow::anyhow!("Live DASH manifests are not supported"));
+        }
+
+        let options = self.info.lock().await.options.clone();
+        let choices = [
+            (ContentKind::Video, RenditionKind::Video, options.stream_variant),
+            (ContentKind::Audio, RenditionKind::Audio, options.stream_audio),
+        ];
+        let mut tracks = Vec::new();
+        for (content, kind, preferred) in choices {
+            let mut segments: Vec<MediaSegment> = Vec::new();
+            for period in &manifest.periods {
+                let Some(rep) = dash::select_representation(&period.representations(content), preferred.as_deref()) else {
+                    continue;
+                };
+                logger::debug(&format!(
+                    "Selected DASH {} representation {} ({} bps)", kind.as_str(), rep.id, rep.bandwidth
+                ));
+                let mut media = self.dash_segments(client, &rep.segments).await?;
+                if let Some(first) = media.first_mut() {
+                    first.discontinuity = !segments.is_empty();
+                }
+                segments.extend(media);
+            }
+            for (i, segment) in segments.iter_mut().enumerate() {
+                segment.sequence = i as u64;
+            }
+            if !segments.is_empty() {
+                let playlist = MediaPlaylist { target_duration: 0.0, end_list: true, segments };
+                tracks.push(HlsTrack { kind, url: url.to_string(), playlist });
+            }
+        }
+
+        if tracks.is_empty() {
+            return Err(anyhow::anyhow!("MPD manifest has no video or audio representations"));
+        }
+        Ok(tracks)
+    }
+
+    /// Lists the segments of a representation, reading the `sidx` index of SegmentBase files.
+    async fn dash_segments(&self, client: &reqwest::Client, segments: &Segments) -> Result<Vec<MediaSegment>> {
+        let segment = |uri: &reqwest::Url, duration, byte_range, map| MediaSegment {
+            uri: uri.clone(),
+            duration,
+            sequence: 0,
+            key: None,
+            byte_range,
+            map,
+            discontinuity: false,
         };
+        match segments {
+            Segments::List { init, media } => Ok(media
+                .iter()
+                .map(|s| MediaSegment { map: init.clone(), ..s.clone() })
+                .collect()),
+            Segments::Indexed { uri, index_range } => {
+                let resp = self
+                    .get(client, uri.as_str())
+                    .header(RANGE, index_range.header())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let partial = resp.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+                let data = resp.bytes().await?;
+                let index = if partial {
+                    &data[..]
+                } else {
+                    data.get(index_range.offset as usize..).unwrap_or_default()
+                };
+                let (init, parts) = dash::parse_sidx(index, *index_range)?;
+                let map = InitSection { uri: uri.clone(), byte_range: Some(init), key: None };
+                Ok(parts
+                    .into_iter()
+                    .map(|(range, duration)| segment(uri, duration, Some(range), Some(map.clone())))
+                    .collect())
+            }
+            Segments::Single { uri } => Ok(vec![segment(uri, 0.0, None, None)]),
+        }
+    }
+
+    async fn spawn_sampler_and_monitor(self: &Arc<Self>) -> Result<()> {
+        let stop_flag = Arc::new(Notify::new());
+        let stop_clone = stop_flag.clone();
 
+        self.spawn_sampler(stop_flag);
+        self.spawn_monitor(stop_clone).await?;
+        Ok(())
+    }
+
+    fn spawn_sampler(self: &Arc<Self>, stop_flag: Arc<Notify>) {
+        let sampler_worker = Arc::clone(self);
         tokio::spawn(async move {
-            let results = join_all(handles).await;
+            let mut samp = interval(Duration::from_secs(HISTORY_SAMPLE_INTERVAL_SECS));
+            let mut samples = 0u32;
+            let mut stalls = StallDetector::default();
+            loop {
+                while sampler_worker.paused.load(Ordering::SeqCst) {
+                    sampler_worker.notify_resume.notified().await;
+                    continue;
+                }
+                if sampler_worker.cancel.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                tokio::select! {
+                    _ = samp.tick() => {
+                        let snapshot = sampler_worker.downloaded.load(Ordering::SeqCst);
+                        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                        sampler_worker.history.write().await.push((ts, snapshot));
+                        let hist_len = sampler_worker.history.read().await.len();
+                        if hist_len > MAX_HISTORY { 
+                            let remove = hist_len - MAX_HISTORY; 
+                            sampler_worker.history.write().await.drain(0..remove);
+                        }
+                        sampler_worker.reconnect_stalled(&mut stalls).await;
+                        samples += 1;
+                        if samples.is_multiple_of(SEGMENT_FLUSH_SAMPLES) {
+                            sampler_worker.persist().await;
+                        }
+                    }
+                    _ = stop_flag.notified() => {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn reconnect_stalled(&self, stalls: &mut StallDetector) {
+        let segments = self.segments.read().await;
+        let snapshot = segment::snapshot(&segments).await;
+        for i in stalls.sample(&snapshot) {
+            logger::debug(&format!("Segment {} is far below the average speed", i));
+            segments[i].reconnect.notify_one();
+        }
+    }
+
+    async fn spawn_monitor(self: &Arc<Self>, stop_flag: Arc<Notify>) -> Result<()> {
+        let monitor_worker = Arc::clone(self);
+        let mut handles = {
+            let mut guard = monitor_worker.handles.lock().await;
+            std::mem::take(&mut *guard)
+        };
+
+        tokio::spawn(async move {
+            let results = loop {
+                let results = join_all(handles).await;
+                if monitor_worker.cancel.load(Ordering::SeqCst) {
+                    break results;
+                }
+                let restarted = if monitor_worker.remote_changed.load(Ordering::SeqCst) {
+                    let policy = monitor_worker.settings.read().await.on_remote_change;
+                    if policy == RemoteChangePolicy::Fail {
+                        let changed = DownloadError::Protocol("remote file changed during the download".to_string());
+                        break vec![Ok(Err(changed.into()))];
+                    }
+                    monitor_worker.restart_fresh().await
+                } else if monitor_worker.ranges_unreliable.load(Ordering::SeqCst) {
+                    monitor_worker.restart_single_stream().await
+                } else {
+                    break results;
+                };
+                if let Err(e) = restarted {
+                    break vec![Ok(Err(e))];
+                }
+                handles = std::mem::take(&mut *monitor_worker.handles.lock().await);
+            };
             stop_flag.notify_waiters();
             
             for (i, res) in results.into_iter().enumerate() {
                 match res {
                     Ok(Ok(())) => {},
                     Ok(Err(e)) => {
-                        let err_str = format!("Monitor: segment {} failed {:?}", i, e);
+                        let err_str = format!("Monitor: segment {} failed: {:#}", i, e);
                         logger::error(&err_str);
-                        let mut info = monitor_worker.info.lock().await;
-                        info.state = DownloadState::Error(err_str.clone());
-                        let id = info.id;
+                        monitor_worker.set_state(DownloadState::Error(error::kind_of(&e), err_str.clone())).await;
+                        let id = monitor_worker.info.lock().await.id;
                         let _ = monitor_worker.event_tx.send(WorkerEvent::Error(id,err_str)).await;
                         return;
                     }
                     Err(e) => {
                         let err_str = format!("Monitor: join error {:?}", &e);
                         logger::error(&err_str);
-                        let mut info = monitor_worker.info.lock().await;
-                        info.state = DownloadState::Error(err_str.clone());
-                        let id = info.id;
+                        monitor_worker.set_state(DownloadState::Error(ErrorKind::Other, err_str.clone())).await;
+                        let id = monitor_worker.info.lock().await.id;
                         let _ = monitor_worker.event_tx.send(WorkerEvent::Error(id,err_str)).await;
                         return;
                     }
                 }
             }
             if !monitor_worker.cancel.load(Ordering::SeqCst) {
-                let mut info = monitor_worker.info.lock().await;
-                info.state = DownloadState::Completed;
-                let id = info.id;
-                let _ = monitor_worker.event_tx.send(WorkerEvent::Completed(id)).await;
+                let id = monitor_worker.info.lock().await.id;
+                if let Some(short) = monitor_worker.check_size().await {
+                    let err = DownloadError::Verification(short);
+                    logger::error(&format!("Download {} is incomplete: {}", id, err));
+                    monitor_worker.set_state(DownloadState::Error(err.kind(), err.to_string())).await;
+                    let _ = monitor_worker.event_tx.send(WorkerEvent::Error(id, err.to_string())).await;
+                    return;
+                }
+                match monitor_worker.verify_checksum().await {
+                    Ok(None) => {
+                        monitor_worker.set_state(DownloadState::Completed).await;
+                        let _ = monitor_worker.event_tx.send(WorkerEvent::Completed(id)).await;
+                    }
+                    Ok(Some(mismatch)) => {
+                        logger::error(&format!("Download {} failed verification: {}", id, mismatch));
+                        monitor_worker.set_state(DownloadState::ChecksumMismatch(mismatch.clone())).await;
+                        let _ = monitor_worker.event_tx.send(WorkerEvent::Error(id, mismatch)).await;
+                    }
+                    Err(e) => {
+                        let err_str = format!("Monitor: checksum verification failed {:?}", e);
+                        logger::error(&err_str);
+                        monitor_worker.set_state(DownloadState::Error(error::kind_of(&e), err_str.clone())).await;
+                        let _ = monitor_worker.event_tx.send(WorkerEvent::Error(id, err_str)).await;
+                    }
+                }
             }
         });
 
         Ok(())
     }
 
-    async fn change_speed_limit(self: &Arc<Self>, limit: u64) {
-        self.speed_limit.store(limit, Ordering::SeqCst);
+    /// Starts over without ranges after the server answered a segment request with
+    /// something other than the bytes asked for.
+    async fn restart_single_stream(self: &Arc<Self>) -> Result<()> {
+        let (url, dest) = self.extract_info().await;
+        let total_size = self.info.lock().await.total_size;
+        logger::debug(&format!("Ranges of {} are not honoured, downloading as a single stream", url));
+
+        let end = total_size.filter(|s| *s > 0).map_or(u64::MAX, |s| s - 1);
+        self.prepare_file(&dest, 0, true)?;
+        self.downloaded.store(0, Ordering::SeqCst);
+        *self.segments.write().await = segment::share(vec![SegmentInfo::new(0, end)]);
+        self.reset_hasher(true).await;
+        self.ranges_unreliable.store(false, Ordering::SeqCst);
+        self.persist().await;
+        self.spawn_download_tasks(&url, &dest, false).await
     }
 
-    async fn limit_speed(self: &Arc<Self>) {
-        let limit = self.speed_limit.load(Ordering::SeqCst) as f64;
-        if limit > 0.0 {
-            let speed = calc_speed(self.history.read().await.to_vec());
-            let sleep_dur = (speed/limit) - 1.0;
+    /// Starts over from the first byte after the remote file changed, probing it again
+    /// for its new size and validators.
+    async fn restart_fresh(self: &Arc<Self>) -> Result<()> {
+        let (url, dest) = self.extract_info().await;
+        logger::debug(&format!("Remote file {} changed, starting over", url));
+        let head_data = self.fetch_head(&url).await?;
+        let segments = self.fresh_segments(&dest, &head_data).await?;
+        self.reset_hasher(segments.len() == 1).await;
+        self.downloaded.store(0, Ordering::SeqCst);
+        *self.segments.write().await = segment::share(segments);
+        self.ranges_unreliable.store(false, Ordering::SeqCst);
+        self.remote_changed.store(false, Ordering::SeqCst);
+        self.persist().await;
+        self.spawn_download_tasks(&url, &dest, head_data.accept_ranges).await
+    }
 
-            if sleep_dur > 0.0 {
-                sleep_until(Instant::now() + Duration::from_secs_f64(sleep_dur)).await;
+    /// True once the running segments have to stop for the monitor to start over.
+    fn interrupted(&self) -> bool {
+        self.ranges_unreliable.load(Ordering::SeqCst) || self.remote_changed.load(Ordering::SeqCst)
+    }
+
+    /// Describes how the finished file falls short of the size the server announced.
+    async fn check_size(&self) -> Option<String> {
+        let info = self.info.lock().await;
+        // streams are measured in parts, not bytes
+        if info.parts.is_some() {
+            return None;
+        }
+        let total = info.total_size?;
+        let downloaded = self.downloaded.load(Ordering::SeqCst);
+        (downloaded != total).then(|| format!("got {} of {} bytes", downloaded, total))
+    }
+
+    /// Starts a fresh streaming digest when `streaming` and a checksum is expected.
+    async fn reset_hasher(&self, streaming: bool) {
+        let algorithm = self.info.lock().await.options.checksum.as_ref().map(|c| c.algorithm);
+        if let Ok(mut hasher) = self.hasher.lock() {
+            *hasher = algorithm.filter(|_| streaming).map(Hasher::new);
+        }
+    }
+
+    /// Compares the finished file with the expected digest, if one was given.
+    /// Returns a description of the mismatch.
+    async fn verify_checksum(&self) -> Result<Option<String>> {
+        let (expected, dest) = {
+            let info = self.info.lock().await;
+            (info.options.checksum.clone(), info.dest.clone())
+        };
+        let Some(expected) = expected else {
+            return Ok(None);
+        };
+
+        let streamed = self.hasher.lock().ok().and_then(|mut h| h.take());
+        let actual = match streamed {
+            Some(hasher) => hasher.finalize_hex(),
+            None => {
+                logger::debug(&format!("Verifying {} of {}", expected.algorithm, dest.display()));
+                hash_file(&dest, expected.algorithm).await?
             }
+        };
+
+        if expected.matches(&actual) {
+            Ok(None)
+        } else {
+            Ok(Some(format!("{} expected {} but got {}", expected.algorithm, expected.digest, actual)))
+        }
+    }
+
+
+    /// Waits until `bytes` fit into this download's limit, its queue's and, unless exempt,
+    /// the global one.
+    async fn limit_speed(&self, bytes: usize) {
+        self.own_throttle.consume(bytes).await;
+        let queue_throttle = self.queue_throttle.read().ok().and_then(|t| t.clone());
+        if let Some(queue_throttle) = queue_throttle {
+            queue_throttle.consume(bytes).await;
+        }
+        if !self.bypass_global_limit.load(Ordering::SeqCst) {
+            self.share_throttle.consume(bytes).await;
+            self.throttle.consume(bytes).await;
+        }
+    }
+
+    pub async fn set_speed_limit(&self, limit: Option<u64>, bypass_global_limit: bool) {
+        let limit = limit.filter(|l| *l > 0);
+        self.own_throttle.set_rate(limit.unwrap_or(0));
+        self.bypass_global_limit.store(bypass_global_limit, Ordering::SeqCst);
+        {
+            let mut info = self.info.lock().await;
+            info.options.speed_limit = limit;
+            info.options.bypass_global_limit = bypass_global_limit;
         }
+        self.persist().await;
+    }
+
+    pub async fn set_weight(&self, weight: BandwidthWeight) {
+        self.info.lock().await.options.weight = weight;
+        self.persist().await;
     }
 
+    fn set_queue_throttle(&self, throttle: Option<Arc<Throttle>>) {
+        if let Ok(mut t) = self.queue_throttle.write() {
+            *t = throttle;
+        }
+    }
+
+    pub async fn set_queue(&self, queue: Option<String>, throttle: Option<Arc<Throttle>>) {
+        self.info.lock().await.options.queue = queue;
+        self.set_queue_throttle(throttle);
+        self.persist().await;
+    }
+
+    pub async fn set_dependencies(&self, depends_on: Vec<Uuid>, policy: DependencyPolicy) {
+        {
+            let mut info = self.info.lock().await;
+            info.options.depends_on = depends_on;
+            info.options.on_dependency_failure = policy;
+        }
+        self.persist().await;
+    }
+
+    pub async fn set_priority(&self, priority: i32) {
+        self.info.lock().await.options.priority = priority;
+        self.persist().await;
+    }
 
     pub async fn pause(&self) -> Result<()> {
         self.paused.store(true, Ordering::SeqCst);
-        let mut info = self.info.lock().await;
-        info.state = DownloadState::Paused;
-        info.downloaded = self.downloaded.load(Ordering::SeqCst);
-        info.history = self.history.read().await.clone();
+        {
+            let mut info = self.info.lock().await;
+            info.state = DownloadState::Paused;
+            info.downloaded = self.downloaded.load(Ordering::SeqCst);
+            info.history = self.history.read().await.clone();
+        }
+        self.persist().await;
         Ok(())
     }
 
     pub async fn resume(self: &Arc<Self>) -> Result<()> {
         self.paused.store(false, Ordering::SeqCst);
         self.notify_resume.notify_waiters();
-        {
-            let mut info = self.info.lock().await;
-            info.state = DownloadState::Running;
+        let state = if self.recording.load(Ordering::SeqCst) {
+            DownloadState::Recording
+        } else {
+            DownloadState::Running
+        };
+        self.set_state(state).await;
+        Ok(())
+    }
+
+    /// Ends a live recording after the segments in flight; the output keeps what was recorded.
+    pub async fn stop_recording(&self) -> Result<()> {
+        if !self.recording.load(Ordering::SeqCst) {
+            return Err(anyhow::anyhow!("Download is not recording"));
         }
+        self.stop_recording.store(true, Ordering::SeqCst);
+        self.notify_stop.notify_waiters();
         Ok(())
     }
 
     pub async fn cancel(&self) -> Result<()> {
         self.cancel.store(true, Ordering::SeqCst);
-        let mut handles = self.handles.lock().await;
-        for h in handles.drain(..) {
-            h.abort();
+        {
+            let mut handles = self.handles.lock().await;
+            for h in handles.drain(..) {
+                h.abort();
+            }
         }
-        let mut info = self.info.lock().await;
-        info.state = DownloadState::Cancelled;
-        self.paused.store(false, Ordering::SeqCst);
-        self.notify_resume.notify_waiters();
-        info.downloaded = self.downloaded.load(Ordering::SeqCst);
-        info.history = self.history.read().await.clone();
-        let id = info.id;
+        let id = {
+            let mut info = self.info.lock().await;
+            info.state = DownloadState::Cancelled;
+            self.paused.store(false, Ordering::SeqCst);
+            self.notify_resume.notify_waiters();
+            info.downloaded = self.downloaded.load(Ordering::SeqCst);
+            info.history = self.history.read().await.clone();
+            info.id
+        };
+        self.persist().await;
         let _ = self.event_tx.send(WorkerEvent::Cancelled(id)).await;
         Ok(())
     }
@@ -632,11 +1685,160 @@ impl DownloadWorker {
         snapshot.downloaded = d;
         let hist = self.history.read().await;
         snapshot.history = hist.clone();
+        snapshot.segments = segment::snapshot(&self.segments.read().await).await;
+        if snapshot.parts.is_some() {
+            snapshot.parts = Some((
+                self.parts_done.load(Ordering::SeqCst),
+                self.parts_total.load(Ordering::SeqCst),
+            ));
+        }
         snapshot
     }
     pub async fn info(&self) -> DownloadInfo {
         self.snapshot_info().await
     }
+
+    /// Adds a line to the log of this download.
+    fn log(&self, message: String) {
+        logger::debug(&message);
+        if let Ok(mut log) = self.log.lock() {
+            if log.len() == MAX_LOG_LINES {
+                log.pop_front();
+            }
+            log.push_back(format!("{} {}", Local::now().format("%H:%M:%S"), message));
+        }
+    }
+
+    pub fn log_lines(&self) -> Vec<String> {
+        self.log.lock().map(|log| log.iter().cloned().collect()).unwrap_or_default()
+    }
+}
+
+/// File extension for a downloaded segment, taken from its URI so ffmpeg probes it right.
+fn segment_extension(uri: &reqwest::Url) -> &str {
+    uri.path()
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.is_empty() && ext.len() <= 5 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or("ts")
+}
+
+async fn run_ffmpeg(mut command: tokio::process::Command) -> Result<()> {
+    match command.output().await {
+        Ok(output) => {
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(anyhow::anyhow!("ffmpeg failed: {}", stderr));
+            }
+            Ok(())
+        }
+        Err(e) => Err(anyhow::anyhow!("ffmpeg execution failed: {}", e)),
+    }
+}
+
+// one queue as seen by `process_queue`: its running downloads with their priority and
+// queue position, and its queued ones with their priority, in queue order
+#[derive(Default)]
+struct QueueLoad {
+    running: Vec<(i32, usize, Uuid, Arc<DownloadWorker>)>,
+    queued: Vec<(i32, Uuid)>,
+}
+
+/// What comparing the server's bytes from `from` with the partial file says about a resume.
+#[derive(Debug, PartialEq, Eq)]
+enum Overlap {
+    // every byte matches, the segment resumes where it stopped
+    Matches,
+    // the file is only good up to here
+    GoodUntil(u64),
+    // nothing in the window matches, a wider one may still find where the data went wrong
+    StepBack,
+}
+
+/// Compares the `remote` and `local` bytes of a segment starting at `start`, read from
+/// `from` with a window of `window` bytes.
+fn compare_overlap(remote: &[u8], local: &[u8], from: u64, start: u64, window: u64) -> Overlap {
+    match remote.iter().zip(local).position(|(r, l)| r != l) {
+        None => Overlap::Matches,
+        Some(0) if from > start && window < MAX_OVERLAP_WINDOW => Overlap::StepBack,
+        Some(0) => Overlap::GoodUntil(start),
+        Some(n) => Overlap::GoodUntil(from + n as u64),
+    }
+}
+
+/// Waits for the writes to `file` to land and marks the bytes `segment` has written so far
+/// as safe to persist.
+async fn flush_segment(file: &mut TokioFile, segment: &SharedSegment) -> std::io::Result<()> {
+    file.flush().await?;
+    let written = segment.range.lock().await.written;
+    segment.flushed.store(written, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Checks that the response to a range request for `start..=end` (`end` is `u64::MAX`
+/// for an open range) carries exactly those bytes of a file of `total_size`. A plain `200`
+/// only does when `start` is 0.
+///
+/// Compressed partial bodies cannot be caught here: reqwest decodes them and drops
+/// `Content-Encoding` before we see the response. Every ranged request is sent with
+/// `Accept-Encoding: identity` instead, see `DownloadWorker::get`.
+fn check_range_response(resp: &reqwest::Response, start: u64, end: u64, total_size: Option<u64>) -> Result<()> {
+    check_range(resp.status(), resp.headers(), start, end, total_size)
+}
+
+fn check_range(status: StatusCode, headers: &HeaderMap, start: u64, end: u64, total_size: Option<u64>) -> Result<()> {
+    if status != StatusCode::PARTIAL_CONTENT {
+        if start > 0 {
+            return Err(anyhow::anyhow!("status {} instead of 206", status));
+        }
+        return Ok(());
+    }
+
+    let content_range = headers
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| anyhow::anyhow!("206 without Content-Range"))?;
+    let (first, last, total) = parse_content_range(content_range)
+        .ok_or_else(|| anyhow::anyhow!("malformed Content-Range {}", content_range))?;
+    if first != start {
+        return Err(anyhow::anyhow!("asked for byte {} but got {}", start, first));
+    }
+    if last > end {
+        return Err(anyhow::anyhow!("asked for bytes up to {} but got up to {}", end, last));
+    }
+    if let (Some(total), Some(known)) = (total, total_size)
+        && total != known
+    {
+        return Err(anyhow::anyhow!("file is {} bytes now instead of {}", total, known));
+    }
+    Ok(())
+}
+
+/// Parses `bytes <first>-<last>/<total>`, where the total may be `*` when unknown.
+fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let first = first.trim().parse().ok()?;
+    let last = last.trim().parse().ok()?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    (first <= last).then_some((first, last, total))
+}
+
+fn response_validator(resp: &reqwest::Response) -> RemoteValidator {
+    let header_string = |name| resp.headers().get(name).and_then(|v: &HeaderValue| v.to_str().ok()).map(str::to_string);
+    RemoteValidator {
+        etag: header_string(ETAG),
+        last_modified: header_string(LAST_MODIFIED),
+    }
+}
+
+/// Day of the week (0 = Monday) and minute of the day, local time.
+fn local_weekday_minute() -> (u8, u16) {
+    let now = Local::now();
+    (now.weekday().num_days_from_monday() as u8, (now.hour() * 60 + now.minute()) as u16)
 }
 
 #[derive(Debug)]
@@ -645,19 +1847,34 @@ pub struct DownloadManager {
     pub settings: Arc<RwLock<DMSettings>>,
     workers: Arc<Mutex<IndexMap<Uuid, Arc<DownloadWorker>>>>,
     active: Arc<Mutex<HashSet<Uuid>>>,
-    concurrency: Arc<AtomicU8>,
+    // held while the queue is processed so two passes cannot start the same slot twice
+    queue_lock: Mutex<()>,
+    storage: Arc<Storage>,
+    // global speed limit, drawn from by every connection of every download
+    throttle: Arc<Throttle>,
+    // the schedule window currently overriding the settings' limits
+    active_profile: RwLock<Option<ScheduleRule>>,
+    // false outside the queue window, when nothing may run
+    queue_open: AtomicBool,
+    // speed limits of the named queues, shared by their downloads
+    queue_throttles: StdMutex<HashMap<String, Arc<Throttle>>>,
     sender: mpsc::Sender<WorkerEvent>,
 }
 
 impl DownloadManager {
-    pub fn new(client: reqwest::Client, settings: DMSettings) -> Arc<Self> {
+    pub fn new(client: reqwest::Client, settings: DMSettings, storage: Arc<Storage>) -> Arc<Self> {
         let (tx, mut rx) = mpsc::channel::<WorkerEvent>(64);
         let mgr = Arc::new(Self {
             client,
-            settings: Arc::new(RwLock::new(settings)),
             workers: Arc::new(Mutex::new(IndexMap::new())),
             active: Arc::new(Mutex::new(HashSet::new())),
-            concurrency: Arc::new(AtomicU8::new(0)),
+            queue_lock: Mutex::new(()),
+            throttle: Arc::new(Throttle::new(settings.speed_limit)),
+            settings: Arc::new(RwLock::new(settings)),
+            active_profile: RwLock::new(None),
+            queue_open: AtomicBool::new(true),
+            queue_throttles: StdMutex::new(HashMap::new()),
+            storage,
             sender: tx.clone(),
         });
 
@@ -685,13 +1902,10 @@ impl DownloadManager {
             WorkerEvent::Completed(id)
             | WorkerEvent::Cancelled(id)
             | WorkerEvent::Error(id, _) => {
-                self.active.lock().await.remove(&id);
-                {
-                    let conc = self.concurrency.load(Ordering::SeqCst).clone();
-                    if conc > 0 {
-                        self.concurrency.store(conc-1, Ordering::SeqCst);
-                    };
+                if let WorkerEvent::Error(_, e) = &event {
+                    logger::error(&format!("Worker {} failed: {}", id, e));
                 }
+                self.active.lock().await.remove(&id);
 
                 logger::debug(&format!("Worker {:?} finished event: {:?}", id, event));
             }
@@ -699,76 +1913,109 @@ impl DownloadManager {
         self.process_queue().await;
     }
 
+    /// Starts queued downloads while their queue has free slots, and sends the running
+    /// ones back to the queue where it has too many.
     pub async fn process_queue(&self) {
-        let limit = self.settings.read().await.concurrency_limit.clone();
-        let active_count = self.concurrency.load(Ordering::SeqCst).clone();
-
-        if active_count == limit {
-            return;
-        }
-        if active_count > limit {
-            let to_pause_count = active_count - limit;
-            let workers_to_pause = {
-                let active = self.active.lock().await;
-                let workers = self.workers.lock().await;
-                active.iter()
-                    .take(to_pause_count as usize)
-                    .map(|id| (*id, workers.get(id).cloned()))
-                    .filter_map(|(id, w_opt)| w_opt.map(|w| (id, w)))
-                    .collect::<Vec<_>>()
+        let _guard = self.queue_lock.lock().await;
+        let named = {
+            let settings = self.settings.read().await;
+            settings.queues.iter().map(|q| q.name.clone()).collect::<HashSet<_>>()
+        };
+        let all_workers = {
+            let workers_map = self.workers.lock().await;
+            workers_map.iter()
+                .map(|(id, w)| (*id, w.clone()))
+                .collect::<Vec<_>>()
+        };
+        let active = self.active.lock().await.clone();
+        self.resolve_dependencies(&all_workers).await;
+
+        // downloads of a queue that no longer exists fall back to the default one
+        let mut queues: IndexMap<String, QueueLoad> = IndexMap::new();
+        for (index, (id, worker)) in all_workers.into_iter().enumerate() {
+            let info = worker.info.lock().await;
+            let queue = match info.options.queue_name() {
+                name if named.contains(name) => name.to_string(),
+                _ => DEFAULT_QUEUE.to_string(),
             };
+            let priority = info.options.priority;
+            let is_queued = matches!(info.state, DownloadState::Queued);
+            drop(info);
+
+            let load = queues.entry(queue).or_default();
+            if active.contains(&id) {
+                load.running.push((priority, index, id, worker));
+            } else if is_queued {
+                load.queued.push((priority, id));
+            }
+        }
 
-            for (id, worker) in workers_to_pause {
-                if worker.pause().await.is_ok() {
-                    worker.info.lock().await.state = DownloadState::Queued;
-                    if self.active.lock().await.remove(&id) {
-                        self.concurrency.fetch_sub(1, Ordering::SeqCst);
+        for (queue, QueueLoad { mut running, mut queued }) in queues {
+            let (_, limit) = self.queue_limits(&queue).await;
+            let limit = limit as usize;
+
+            if running.len() > limit {
+                // the lowest priority goes first, and of those the one furthest down the queue
+                running.sort_by_key(|(priority, index, _, _)| (*priority, std::cmp::Reverse(*index)));
+                let to_pause_count = running.len() - limit;
+                for (_, _, id, worker) in running.into_iter().take(to_pause_count) {
+                    if worker.pause().await.is_ok() {
+                        worker.set_state(DownloadState::Queued).await;
+                        self.active.lock().await.remove(&id);
                     }
                 }
+                continue;
             }
-            return;
-        }
-
-        let slots = limit - active_count;
-        let mut to_start = Vec::new();
-        let workers_map = self.workers.lock().await;
-        let queued_workers = workers_map.iter()
-            .map(|(id, w)| (*id, w.clone()))
-            .collect::<Vec<_>>();
-        drop(workers_map);
 
-        for (id, worker) in queued_workers {
-            if to_start.len() >= slots as usize {
-                break;
-            }
-            let info = worker.info().await;
-            if matches!(info.state, DownloadState::Queued) {
-                to_start.push(id);
-            }
-        }
-        
-        for id in to_start {
-            // It's possible another task already started a worker, so we check again.
-            let current_active = self.concurrency.load(Ordering::SeqCst);
-            if current_active < limit {
-                self.concurrency.fetch_add(1, Ordering::SeqCst);
+            // stable, so equal priorities keep their queue order
+            queued.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+            for (_, id) in queued.into_iter().take(limit - running.len()) {
                 let _ = self.start(id).await;
-            } else {
-                break;
             }
         }
     }
 
-    pub async fn add_download(&self, url: String, dest: PathBuf) -> Result<Uuid> {
+    /// Adds a download in `state`: `Queued` to start as soon as there is a slot,
+    /// `Scheduled` to wait for its start time, or `Paused` to wait to be resumed.
+    pub async fn add_download(&self, url: String, dest: PathBuf, options: DownloadOptions, state: DownloadState) -> Result<Uuid> {
         let id = Uuid::new_v4();
+        let mut info = DownloadInfo::new(id, url, dest, options);
+        info.state = state;
+        let queue_throttle = self.queue_throttle(info.options.queue_name());
         let worker = DownloadWorker::new(
-            id, self.client.clone(), self.settings.clone(), url, dest, self.sender.clone()
+            info, self.client.clone(), self.settings.clone(), self.storage.clone(), self.throttle.clone(), self.sender.clone()
         ).await;
+        worker.set_queue_throttle(queue_throttle);
+        worker.persist().await;
         self.workers.lock().await.insert(id, worker);
+        self.persist_order().await;
         self.process_queue().await;
         Ok(id)
     }
 
+    /// Reloads the stored queue. Downloads that were running when the app
+    /// went away come back paused so they can be resumed by hand.
+    pub async fn restore(&self) -> Result<()> {
+        let stored = self.storage.load_all().await?;
+        let count = stored.len();
+        for mut info in stored {
+            if matches!(info.state, DownloadState::Running | DownloadState::Recording) {
+                info.state = DownloadState::Paused;
+            }
+            let id = info.id;
+            let queue_throttle = self.queue_throttle(info.options.queue_name());
+            let worker = DownloadWorker::new(
+                info, self.client.clone(), self.settings.clone(), self.storage.clone(), self.throttle.clone(), self.sender.clone()
+            ).await;
+            worker.set_queue_throttle(queue_throttle);
+            worker.persist().await;
+            self.workers.lock().await.insert(id, worker);
+        }
+        logger::debug(&format!("Restored {} downloads from storage", count));
+        self.process_queue().await;
+        Ok(())
+    }
+
     pub async fn start(&self, id: Uuid) -> Result<()> {
         let worker_opt = { self.workers.lock().await.get(&id).cloned() };
         let worker = match worker_opt {
@@ -782,8 +2029,14 @@ impl DownloadManager {
         
         let w = Arc::clone(&worker);
         
+        // a failure to even start (probe, disk, missing folder) is reported like one
+        // mid-download, so the event loop frees the slot and moves the queue on
         tokio::spawn(async move {
-            let _ = w.start().await;
+            if let Err(e) = w.start().await {
+                let err_str = format!("{:#}", e);
+                w.set_state(DownloadState::Error(error::kind_of(&e), err_str.clone())).await;
+                let _ = w.event_tx.send(WorkerEvent::Error(id, err_str)).await;
+            }
         });
         Ok(())
     }
@@ -793,11 +2046,7 @@ impl DownloadManager {
         match w {
             Some(worker) => {
                 worker.pause().await?;
-                if self.active.lock().await.remove(&id) {
-                    if self.concurrency.load(Ordering::SeqCst) > 0 {
-                        self.concurrency.fetch_sub(1, Ordering::SeqCst);
-                    }
-                }
+                self.active.lock().await.remove(&id);
                 self.process_queue().await;
                 Ok(())
             }
@@ -805,21 +2054,169 @@ impl DownloadManager {
         }
     }
 
+    pub async fn stop_recording(&self, id: Uuid) -> Result<()> {
+        let w = { self.workers.lock().await.get(&id).cloned() };
+        match w {
+            Some(worker) => worker.stop_recording().await,
+            None => Err(anyhow::anyhow!("Worker not found")),
+        }
+    }
+
     pub async fn resume(&self, id: Uuid) -> Result<()> {
         let w = { self.workers.lock().await.get(&id).cloned() };
         match w {
             Some(worker) => {
                 {
-                    let mut info = worker.info.lock().await;
-                    match info.state {
-                        DownloadState::Completed | DownloadState::Running => {
-                            return Ok(());
-                        }
-                        _ => {
-                            info.state = DownloadState::Queued;
+                    let info = worker.info.lock().await;
+                    if matches!(
+                        info.state,
+                        DownloadState::Completed | DownloadState::Running | DownloadState::Recording
+                    ) {
+                        return Ok(());
+                    }
+                }
+                worker.set_state(DownloadState::Queued).await;
+                self.process_queue().await;
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Worker not found")),
+        }
+    }
+
+    pub async fn set_speed_limit(&self, id: Uuid, limit: Option<u64>, bypass_global_limit: bool) -> Result<()> {
+        let w = { self.workers.lock().await.get(&id).cloned() };
+        match w {
+            Some(worker) => {
+                worker.set_speed_limit(limit, bypass_global_limit).await;
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Worker not found")),
+        }
+    }
+
+    pub async fn set_weight(&self, id: Uuid, weight: BandwidthWeight) -> Result<()> {
+        let w = { self.workers.lock().await.get(&id).cloned() };
+        match w {
+            Some(worker) => {
+                worker.set_weight(weight).await;
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Worker not found")),
+        }
+    }
+
+    /// Replaces the downloads `id` waits for. Fails when one of them is unknown or
+    /// already waits, directly or not, for `id`.
+    pub async fn set_dependencies(&self, id: Uuid, depends_on: Vec<Uuid>, policy: DependencyPolicy) -> Result<()> {
+        let workers = { self.workers.lock().await.clone() };
+        let worker = workers.get(&id).cloned().ok_or_else(|| anyhow::anyhow!("Worker not found"))?;
+
+        let mut pending = depends_on.clone();
+        let mut seen = HashSet::new();
+        while let Some(dep) = pending.pop() {
+            if dep == id {
+                return Err(anyhow::anyhow!("Dependency cycle through {}", id));
+            }
+            if !seen.insert(dep) {
+                continue;
+            }
+            match workers.get(&dep) {
+                Some(w) => pending.extend(w.info.lock().await.options.depends_on.iter().copied()),
+                None => return Err(anyhow::anyhow!("Unknown dependency {}", dep)),
+            }
+        }
+
+        worker.set_dependencies(depends_on, policy).await;
+        self.process_queue().await;
+        Ok(())
+    }
+
+    /// Blocks queued downloads whose dependencies have not completed yet, releases blocked
+    /// ones whose dependencies have, and fails or skips those with a dependency that failed,
+    /// was cancelled or is gone. Repeats while failures cascade down the chain.
+    async fn resolve_dependencies(&self, workers: &[(Uuid, Arc<DownloadWorker>)]) {
+        loop {
+            let mut states = HashMap::new();
+            for (id, worker) in workers {
+                states.insert(*id, worker.info.lock().await.state.clone());
+            }
+
+            let mut changes = Vec::new();
+            for (id, worker) in workers {
+                let info = worker.info.lock().await;
+                let blocked = match info.state {
+                    DownloadState::Queued => false,
+                    DownloadState::Blocked => true,
+                    _ => continue,
+                };
+                let mut pending = false;
+                let mut failed = None;
+                for dep in &info.options.depends_on {
+                    match states.get(dep) {
+                        Some(DownloadState::Completed) => {}
+                        Some(DownloadState::Error(..) | DownloadState::ChecksumMismatch(_) | DownloadState::Cancelled)
+                        | None => {
+                            failed = Some(*dep);
+                            break;
                         }
+                        Some(_) => pending = true,
                     }
                 }
+                let next = match (failed, info.options.on_dependency_failure) {
+                    (Some(dep), DependencyPolicy::Fail) => {
+                        DownloadState::Error(ErrorKind::Dependency, format!("Dependency {} did not complete", dep))
+                    }
+                    (Some(dep), DependencyPolicy::Skip) => {
+                        logger::debug(&format!("Skipping {} as dependency {} did not complete", id, dep));
+                        DownloadState::Cancelled
+                    }
+                    (None, _) if pending && !blocked => DownloadState::Blocked,
+                    (None, _) if !pending && blocked => DownloadState::Queued,
+                    _ => continue,
+                };
+                changes.push((worker.clone(), next));
+            }
+
+            let cascades = changes.iter()
+                .any(|(_, s)| !matches!(s, DownloadState::Queued | DownloadState::Blocked));
+            for (worker, state) in changes {
+                worker.set_state(state).await;
+            }
+            if !cascades {
+                return;
+            }
+        }
+    }
+
+    /// Moves a download to another named queue; the queues are rebalanced right away.
+    pub async fn set_queue(&self, id: Uuid, queue: &str) -> Result<()> {
+        let w = { self.workers.lock().await.get(&id).cloned() };
+        match w {
+            Some(worker) => {
+                let name = (!queue.is_empty() && queue != DEFAULT_QUEUE).then(|| queue.to_string());
+                worker.set_queue(name, self.queue_throttle(queue)).await;
+                self.process_queue().await;
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Worker not found")),
+        }
+    }
+
+    /// Throttle shared by the downloads of a named queue; the default queue has none
+    /// besides the global one.
+    fn queue_throttle(&self, queue: &str) -> Option<Arc<Throttle>> {
+        if queue.is_empty() || queue == DEFAULT_QUEUE {
+            return None;
+        }
+        let mut throttles = self.queue_throttles.lock().ok()?;
+        Some(throttles.entry(queue.to_string()).or_default().clone())
+    }
+
+    pub async fn set_priority(&self, id: Uuid, priority: i32) -> Result<()> {
+        let w = { self.workers.lock().await.get(&id).cloned() };
+        match w {
+            Some(worker) => {
+                worker.set_priority(priority).await;
                 self.process_queue().await;
                 Ok(())
             }
@@ -827,16 +2224,77 @@ impl DownloadManager {
         }
     }
 
+    /// Moves a download within its queue, which is the order queued downloads of the
+    /// same priority start in. Only the queued and scheduled downloads of that queue
+    /// count, finished ones and other queues keep their places.
+    pub async fn move_download(&self, id: Uuid, to: QueueMove) -> Result<()> {
+        {
+            let mut workers = self.workers.lock().await;
+            let queue = match workers.get(&id) {
+                Some(worker) => {
+                    let info = worker.info.lock().await;
+                    if !matches!(info.state, DownloadState::Queued | DownloadState::Scheduled(_)) {
+                        return Err(anyhow::anyhow!("Only queued downloads can be moved"));
+                    }
+                    info.options.queue_name().to_string()
+                }
+                None => return Err(anyhow::anyhow!("Worker not found")),
+            };
+
+            // indexes of the downloads waiting in the same queue, in start order
+            let mut slots = Vec::new();
+            for (index, worker) in workers.values().enumerate() {
+                let info = worker.info.lock().await;
+                if matches!(info.state, DownloadState::Queued | DownloadState::Scheduled(_))
+                    && info.options.queue_name() == queue
+                {
+                    slots.push(index);
+                }
+            }
+            let Some(from) = workers.get_index_of(&id).and_then(|i| slots.iter().position(|s| *s == i)) else {
+                return Err(anyhow::anyhow!("Worker not found"));
+            };
+            let last = slots.len() - 1;
+            let to = match to {
+                QueueMove::Top => 0,
+                QueueMove::Up => from.saturating_sub(1),
+                QueueMove::Down => (from + 1).min(last),
+                QueueMove::Bottom => last,
+            };
+            if from == to {
+                return Ok(());
+            }
+
+            // Shift the waiting downloads between the two slots by one, leaving every
+            // other entry where it was
+            if from < to {
+                for pair in slots[from..=to].windows(2) {
+                    workers.swap_indices(pair[0], pair[1]);
+                }
+            } else {
+                for pair in slots[to..=from].windows(2).rev() {
+                    workers.swap_indices(pair[0], pair[1]);
+                }
+            }
+        }
+        self.persist_order().await;
+        self.process_queue().await;
+        Ok(())
+    }
+
+    async fn persist_order(&self) {
+        let ids = { self.workers.lock().await.keys().copied().collect::<Vec<_>>() };
+        if let Err(e) = self.storage.save_order(&ids).await {
+            logger::error(&format!("Failed to persist queue order: {:?}", e));
+        }
+    }
+
     pub async fn cancel(&self, id: Uuid) -> Result<()> {
         let w = { self.workers.lock().await.get(&id).cloned() };
         match w {
             Some(worker) => {
                 worker.cancel().await?;
                 self.active.lock().await.remove(&id);
-                let conc = self.concurrency.load(Ordering::SeqCst).clone();
-                if conc > 0 {
-                    self.concurrency.store(conc-1, Ordering::SeqCst);
-                };
                 self.process_queue().await;
                 Ok(())
             }
@@ -855,6 +2313,14 @@ impl DownloadManager {
         }
     }
 
+    pub async fn log(&self, id: Uuid) -> Result<Vec<String>> {
+        let map = self.workers.lock().await;
+        match map.get(&id) {
+            Some(worker) => Ok(worker.log_lines()),
+            None => Err(anyhow::anyhow!("Worker not found")),
+        }
+    }
+
     pub async fn list_all(&self) -> Result<Vec<DownloadInfo>> {
         let map = self.workers.lock().await;
         let mut out = Vec::new();
@@ -871,15 +2337,18 @@ impl DownloadManager {
             match self.list_all().await {
                 Ok(list) => {
                     let mut download_list = Vec::new();
-                    let list_ref = list.clone();
                     for info in list {
                         let state_str = match &info.state {
                             DownloadState::Queued => "Queued".to_string(),
+                            DownloadState::Scheduled(_) => "Scheduled".to_string(),
+                            DownloadState::Blocked => "Blocked".to_string(),
                             DownloadState::Running => "Running".to_string(),
+                            DownloadState::Recording => "Recording".to_string(),
                             DownloadState::Paused => "Paused".to_string(),
                             DownloadState::Completed => "Completed".to_string(),
                             DownloadState::Cancelled => "Cancelled".to_string(),
-                            DownloadState::Error(_) => "Error".to_string(),
+                            DownloadState::Error(..) => "Error".to_string(),
+                            DownloadState::ChecksumMismatch(_) => "ChecksumMismatch".to_string(),
                         };
                         let speed = calc_speed(info.history);
                         let glance = DownloadGlance {
@@ -891,8 +2360,13 @@ impl DownloadManager {
                                 .to_string(),
                             total_size: info.total_size,
                             downloaded: info.downloaded,
-                            speed: speed,
+                            speed,
                             state: state_str.clone(),
+                            parts_done: info.parts.map(|(done, _)| done),
+                            parts_total: info.parts.map(|(_, total)| total),
+                            priority: info.options.priority,
+                            queue: info.options.queue_name().to_string(),
+                            error_kind: info.state.error_kind().map(|k| k.to_string()),
                         };
                         download_list.push(glance);
                     }
@@ -905,82 +2379,425 @@ impl DownloadManager {
         }
     }
 
-    pub async fn recalculate_speed_limits(&self, mut interval: Interval) {
+    /// Shares the global limit between the running downloads by weight. A download that
+    /// stays well below its share (slow server, own limit) is capped at what it uses, with
+    /// some headroom to grow, and the rest goes to the others.
+    pub async fn rebalance_bandwidth(&self, mut interval: Interval) {
         loop {
             interval.tick().await;
-            let global_limit = self.settings.read().await.speed_limit;
-            if global_limit == 0 {
-                continue;
-            }
+            let (global_limit, _) = self.effective_limits().await;
 
             let worker_refs = {
                 let active = self.active.lock().await;
                 let workers = self.workers.lock().await;
                 active.iter()
                     .filter_map(|id| workers.get(id).cloned())
+                    .filter(|w| !w.bypass_global_limit.load(Ordering::SeqCst))
                     .collect::<Vec<_>>()
             };
+            if global_limit == 0 {
+                for w in &worker_refs {
+                    w.share_throttle.set_rate(0);
+                }
+                continue;
+            }
 
-            let histories = join_all(worker_refs.iter().map(|w| async {
-                w.history.read().await.clone()
-            })).await;
-
-            let mut worker_speeds = Vec::new();
-            let mut total_speed = 0u64;
+            let mut demands = Vec::with_capacity(worker_refs.len());
+            for w in &worker_refs {
+                let (weight, own_limit) = {
+                    let info = w.info.lock().await;
+                    (info.options.weight, info.options.speed_limit)
+                };
+                let speed = {
+                    let history = w.history.read().await;
+                    let recent = history.len().saturating_sub(SHARE_SPEED_SAMPLES);
+                    calc_speed(history[recent..].to_vec()) as u64
+                };
+                let share = w.share_throttle.rate();
+                let underused = (share > 0 && (speed as f64) < share as f64 * SHARE_UNDERUSE_RATIO)
+                    .then(|| ((speed as f64 * SHARE_HEADROOM) as u64).max(MIN_SHARE));
+                let cap = match (own_limit, underused) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                demands.push(Demand { weight: weight.factor(), cap });
+            }
 
-            for (i, w) in worker_refs.iter().enumerate() {
-                let s = calc_speed(histories[i].clone()) as u64;
-                total_speed += s;
-                worker_speeds.push((w.clone(), s));
+            for (w, share) in worker_refs.iter().zip(allocate(global_limit, &demands)) {
+                w.share_throttle.set_rate(share.max(MIN_SHARE));
             }
+        }
+    }
+
+    /// Speed and concurrency limits in effect right now: those of the active schedule
+    /// window, falling back to the settings. Concurrency is 0 while the queue window
+    /// is closed.
+    async fn effective_limits(&self) -> (u64, u8) {
+        let settings = self.settings.read().await;
+        let (speed_limit, concurrency_limit) = match &*self.active_profile.read().await {
+            Some(rule) => (
+                rule.speed_limit,
+                rule.concurrency_limit.unwrap_or(settings.concurrency_limit),
+            ),
+            None => (settings.speed_limit, settings.concurrency_limit),
+        };
+        if self.queue_open.load(Ordering::SeqCst) {
+            (speed_limit, concurrency_limit)
+        } else {
+            (speed_limit, 0)
+        }
+    }
+
+    /// Speed and concurrency limits of a queue right now. Named queues follow their own
+    /// schedule; the default one, and any queue not in the settings, the global limits.
+    async fn queue_limits(&self, queue: &str) -> (u64, u8) {
+        let config = {
+            let settings = self.settings.read().await;
+            settings.queues.iter().find(|q| q.name == queue).cloned()
+        };
+        let Some(config) = config else {
+            return self.effective_limits().await;
+        };
+
+        let (weekday, minute) = local_weekday_minute();
+        let (speed_limit, concurrency_limit) = match config.schedule.iter().find(|r| r.is_active(weekday, minute)) {
+            Some(rule) => (
+                rule.speed_limit,
+                rule.concurrency_limit.unwrap_or(config.concurrency_limit),
+            ),
+            None => (config.speed_limit, config.concurrency_limit),
+        };
+        if self.queue_open.load(Ordering::SeqCst) {
+            (speed_limit, concurrency_limit)
+        } else {
+            (speed_limit, 0)
+        }
+    }
 
-            if total_speed == 0 {
-                let even_limit = global_limit / worker_speeds.len().max(1) as u64;
-                for (w, _) in worker_speeds {
-                    w.change_speed_limit(even_limit).await;
+    /// Picks the schedule window for the current local time and applies its limits, to
+    /// the default queue and to every named one. The first matching rule wins.
+    pub async fn apply_schedule(&self) {
+        let (weekday, minute) = local_weekday_minute();
+        let (rule, queue_open) = {
+            let settings = self.settings.read().await;
+            let rule = settings.schedule
+                .iter()
+                .find(|rule| rule.is_active(weekday, minute))
+                .cloned();
+            (rule, settings.queue_window.is_none_or(|w| w.is_open(minute)))
+        };
+
+        let changed = {
+            let mut active = self.active_profile.write().await;
+            // both sides are updated every time, a changed profile must not skip the window
+            let profile_changed = *active != rule;
+            let window_changed = self.queue_open.swap(queue_open, Ordering::SeqCst) != queue_open;
+            *active = rule;
+            profile_changed || window_changed
+        };
+        let (speed_limit, concurrency_limit) = self.effective_limits().await;
+        self.throttle.set_rate(speed_limit);
+
+        if changed {
+            let profile = self.active_profile.read().await.as_ref().map(|r| r.name.clone());
+            logger::debug(&format!(
+                "Schedule profile {:?} active: {} B/s, {} concurrent",
+                profile, speed_limit, concurrency_limit
+            ));
+            ScheduleStatus { profile, speed_limit, concurrency_limit }.send_signal_to_dart();
+        }
+
+        let named = {
+            let settings = self.settings.read().await;
+            settings.queues.iter().map(|q| q.name.clone()).collect::<HashSet<_>>()
+        };
+        for queue in &named {
+            self.queue_throttle(queue);
+        }
+        let throttles = match self.queue_throttles.lock() {
+            Ok(throttles) => throttles.iter().map(|(n, t)| (n.clone(), t.clone())).collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        for (queue, throttle) in throttles {
+            // a queue dropped from the settings keeps no limit of its own
+            let rate = if named.contains(&queue) { self.queue_limits(&queue).await.0 } else { 0 };
+            throttle.set_rate(rate);
+        }
+
+        self.process_queue().await;
+    }
+
+    pub async fn follow_schedule(&self, mut interval: Interval) {
+        loop {
+            interval.tick().await;
+            self.apply_schedule().await;
+        }
+    }
+
+    /// Moves scheduled downloads whose start time has come into the queue.
+    pub async fn start_scheduled(&self, mut interval: Interval) {
+        loop {
+            interval.tick().await;
+            let now = now_unix();
+            let workers = { self.workers.lock().await.values().cloned().collect::<Vec<_>>() };
+            let mut due = 0;
+            for worker in workers {
+                let is_due = matches!(worker.info.lock().await.state, DownloadState::Scheduled(at) if at <= now);
+                if is_due {
+                    worker.set_state(DownloadState::Queued).await;
+                    due += 1;
                 }
-                continue;
             }
-
-            for (w, speed) in worker_speeds {
-                let share = ((speed as f64 / total_speed as f64) * global_limit as f64) as u64;
-                w.change_speed_limit(share.max((global_limit as f64 * 0.05) as u64)).await;
+            if due > 0 {
+                logger::debug(&format!("{} scheduled downloads are due", due));
+                self.process_queue().await;
             }
         }
     }
 
     pub async fn updater(self: &Arc<Self>) {
-        let mut interval1 = interval(Duration::from_secs(1));
-        let mut interval2 = interval(Duration::from_secs(1));
+        let interval1 = interval(Duration::from_secs(1));
+        let interval2 = interval(Duration::from_secs(1));
+        let interval3 = interval(Duration::from_secs(SCHEDULE_CHECK_SECS));
+        let interval4 = interval(Duration::from_secs(1));
         let mgr1 = self.clone();
         let mgr2 = self.clone();
+        let mgr3 = self.clone();
+        let mgr4 = self.clone();
+
+        tokio::spawn( async move {
+            mgr4.start_scheduled(interval4).await;
+        });
+
+        tokio::spawn( async move {
+            mgr3.follow_schedule(interval3).await;
+        });
 
         tokio::spawn( async move {
             mgr1.send_list(interval1).await;
         });
 
         tokio::spawn( async move {
-            mgr2.recalculate_speed_limits(interval2).await;
+            mgr2.rebalance_bandwidth(interval2).await;
         });
     }
 
     pub async fn update_settings(&self, new: DMSettings) -> Result<()> {
-        let concurrency_changed;
         {
             let mut settings = self.settings.write().await;
-            concurrency_changed = settings.concurrency_limit != new.concurrency_limit;
-
             settings.speed_limit = new.speed_limit;
+            settings.schedule = new.schedule;
+            settings.queue_window = new.queue_window;
+            settings.queues = new.queues;
+            settings.on_remote_change = new.on_remote_change;
             settings.download_threads = new.download_threads;
             settings.concurrency_limit = new.concurrency_limit;
             settings.download_timeout = new.download_timeout;
             settings.download_retries = new.download_retries;
         }
 
-        if concurrency_changed {
-            self.process_queue().await;
-        }
+        // sets the throttles from whichever limits now apply and rebalances the queues
+        self.apply_schedule().await;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Nothing starts with a concurrency limit of 0, so tests never touch the network
+    async fn manager() -> Arc<DownloadManager> {
+        let settings = DMSettings {
+            speed_limit: 0,
+            concurrency_limit: 0,
+            download_threads: 1,
+            download_timeout: 30,
+            download_retries: 0,
+            schedule: Vec::new(),
+            queue_window: None,
+            queues: Vec::new(),
+            on_remote_change: RemoteChangePolicy::default(),
+        };
+        let storage = Arc::new(Storage::in_memory().await.unwrap());
+        DownloadManager::new(reqwest::Client::new(), settings, storage)
+    }
+
+    async fn add(manager: &DownloadManager, queue: Option<&str>, state: DownloadState) -> Uuid {
+        let options = DownloadOptions { queue: queue.map(str::to_string), ..Default::default() };
+        let dest = PathBuf::from("/nonexistent/file.bin");
+        manager.add_download("http://127.0.0.1:9/file.bin".to_string(), dest, options, state).await.unwrap()
+    }
+
+    async fn order(manager: &DownloadManager) -> Vec<Uuid> {
+        manager.workers.lock().await.keys().copied().collect()
+    }
+
+    async fn state(manager: &DownloadManager, id: Uuid) -> DownloadState {
+        let worker = manager.workers.lock().await.get(&id).cloned().unwrap();
+        worker.info.lock().await.state.clone()
+    }
+
+    /// Queue with `a`, `b` and `c` waiting in the default queue, a finished download
+    /// and one of another queue between them.
+    async fn mixed_queue() -> (Arc<DownloadManager>, [Uuid; 5]) {
+        let manager = manager().await;
+        let a = add(&manager, None, DownloadState::Queued).await;
+        let done = add(&manager, None, DownloadState::Completed).await;
+        let b = add(&manager, None, DownloadState::Scheduled(i64::MAX)).await;
+        let other = add(&manager, Some("other"), DownloadState::Queued).await;
+        let c = add(&manager, None, DownloadState::Queued).await;
+        (manager, [a, done, b, other, c])
+    }
+
+    #[tokio::test]
+    async fn moves_to_the_top_and_bottom_of_its_queue() {
+        let (manager, [a, done, b, other, c]) = mixed_queue().await;
+        manager.move_download(c, QueueMove::Top).await.unwrap();
+        assert_eq!(order(&manager).await, [c, done, a, other, b]);
+
+        manager.move_download(c, QueueMove::Bottom).await.unwrap();
+        assert_eq!(order(&manager).await, [a, done, b, other, c]);
+    }
+
+    #[tokio::test]
+    async fn moves_up_and_down_past_other_queues() {
+        let (manager, [a, done, b, other, c]) = mixed_queue().await;
+        manager.move_download(a, QueueMove::Down).await.unwrap();
+        assert_eq!(order(&manager).await, [b, done, a, other, c]);
+
+        manager.move_download(c, QueueMove::Up).await.unwrap();
+        assert_eq!(order(&manager).await, [b, done, c, other, a]);
+    }
+
+    #[tokio::test]
+    async fn stays_put_at_the_edges_of_its_queue() {
+        let (manager, [a, done, b, other, c]) = mixed_queue().await;
+        manager.move_download(a, QueueMove::Up).await.unwrap();
+        manager.move_download(a, QueueMove::Top).await.unwrap();
+        manager.move_download(c, QueueMove::Down).await.unwrap();
+        manager.move_download(c, QueueMove::Bottom).await.unwrap();
+        manager.move_download(other, QueueMove::Top).await.unwrap();
+        assert_eq!(order(&manager).await, [a, done, b, other, c]);
+
+        assert!(manager.move_download(done, QueueMove::Top).await.is_err());
+        assert!(matches!(state(&manager, b).await, DownloadState::Scheduled(i64::MAX)));
+    }
+
+    #[test]
+    fn overlap_resumes_where_the_data_matches() {
+        let remote = [1, 2, 3, 4];
+        assert_eq!(compare_overlap(&remote, &[1, 2, 3, 4], 100, 0, OVERLAP_WINDOW), Overlap::Matches);
+        assert_eq!(compare_overlap(&remote, &[1, 2, 9, 9], 100, 0, OVERLAP_WINDOW), Overlap::GoodUntil(102));
+    }
+
+    #[test]
+    fn overlap_steps_back_then_falls_back_to_the_start() {
+        let remote = [1, 2, 3, 4];
+        let local = [9, 2, 3, 4];
+        assert_eq!(compare_overlap(&remote, &local, 100, 0, OVERLAP_WINDOW), Overlap::StepBack);
+        // the window already reaches the start of the segment...
+        assert_eq!(compare_overlap(&remote, &local, 100, 100, OVERLAP_WINDOW), Overlap::GoodUntil(100));
+        // ...or cannot grow any further
+        assert_eq!(compare_overlap(&remote, &local, 100, 0, MAX_OVERLAP_WINDOW), Overlap::GoodUntil(0));
+    }
+
+    async fn set_state(manager: &DownloadManager, id: Uuid, state: DownloadState) {
+        let worker = manager.workers.lock().await.get(&id).cloned().unwrap();
+        worker.set_state(state).await;
+    }
+
+    #[tokio::test]
+    async fn rejects_dependency_cycles() {
+        let manager = manager().await;
+        let a = add(&manager, None, DownloadState::Queued).await;
+        let b = add(&manager, None, DownloadState::Queued).await;
+        assert!(manager.set_dependencies(a, vec![a], DependencyPolicy::Fail).await.is_err());
+
+        manager.set_dependencies(a, vec![b], DependencyPolicy::Fail).await.unwrap();
+        assert!(manager.set_dependencies(b, vec![a], DependencyPolicy::Fail).await.is_err());
+        assert!(manager.set_dependencies(b, vec![Uuid::new_v4()], DependencyPolicy::Fail).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn releases_a_dependent_once_all_parents_complete() {
+        let manager = manager().await;
+        let first = add(&manager, None, DownloadState::Queued).await;
+        let second = add(&manager, None, DownloadState::Queued).await;
+        let child = add(&manager, None, DownloadState::Queued).await;
+        manager.set_dependencies(child, vec![first, second], DependencyPolicy::Fail).await.unwrap();
+        assert!(matches!(state(&manager, child).await, DownloadState::Blocked));
+
+        set_state(&manager, first, DownloadState::Completed).await;
+        manager.process_queue().await;
+        assert!(matches!(state(&manager, child).await, DownloadState::Blocked));
+
+        set_state(&manager, second, DownloadState::Completed).await;
+        manager.process_queue().await;
+        assert!(matches!(state(&manager, child).await, DownloadState::Queued));
+    }
+
+    #[tokio::test]
+    async fn fails_or_skips_on_an_errored_dependency() {
+        let manager = manager().await;
+        let parent = add(&manager, None, DownloadState::Queued).await;
+        let failing = add(&manager, None, DownloadState::Queued).await;
+        let skipping = add(&manager, None, DownloadState::Queued).await;
+        let grandchild = add(&manager, None, DownloadState::Queued).await;
+        manager.set_dependencies(failing, vec![parent], DependencyPolicy::Fail).await.unwrap();
+        manager.set_dependencies(skipping, vec![parent], DependencyPolicy::Skip).await.unwrap();
+        manager.set_dependencies(grandchild, vec![failing], DependencyPolicy::Fail).await.unwrap();
+
+        set_state(&manager, parent, DownloadState::Error(ErrorKind::Http, "404".to_string())).await;
+        manager.process_queue().await;
+        assert!(matches!(state(&manager, failing).await, DownloadState::Error(ErrorKind::Dependency, _)));
+        assert!(matches!(state(&manager, skipping).await, DownloadState::Cancelled));
+        assert!(matches!(state(&manager, grandchild).await, DownloadState::Error(ErrorKind::Dependency, _)));
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_missing_dependency() {
+        let manager = manager().await;
+        let parent = add(&manager, None, DownloadState::Queued).await;
+        let child = add(&manager, None, DownloadState::Queued).await;
+        manager.set_dependencies(child, vec![parent], DependencyPolicy::Fail).await.unwrap();
+
+        manager.workers.lock().await.shift_remove(&parent);
+        manager.process_queue().await;
+        assert!(matches!(state(&manager, child).await, DownloadState::Error(ErrorKind::Dependency, _)));
+    }
+
+    fn partial(content_range: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_RANGE, HeaderValue::from_str(content_range).unwrap());
+        headers
+    }
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(parse_content_range("bytes 0-99/1000"), Some((0, 99, Some(1000))));
+        assert_eq!(parse_content_range("bytes 100-199/*"), Some((100, 199, None)));
+        assert_eq!(parse_content_range("bytes */1000"), None);
+        assert_eq!(parse_content_range("bytes 200-100/1000"), None);
+        assert_eq!(parse_content_range("items 0-99/1000"), None);
+    }
+
+    #[test]
+    fn accepts_the_requested_range() {
+        let status = StatusCode::PARTIAL_CONTENT;
+        assert!(check_range(status, &partial("bytes 100-199/1000"), 100, 199, Some(1000)).is_ok());
+        assert!(check_range(status, &partial("bytes 100-999/1000"), 100, u64::MAX, Some(1000)).is_ok());
+        assert!(check_range(status, &partial("bytes 100-149/*"), 100, 199, Some(1000)).is_ok());
+        assert!(check_range(StatusCode::OK, &HeaderMap::new(), 0, 199, Some(1000)).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_mismatched_range() {
+        let status = StatusCode::PARTIAL_CONTENT;
+        assert!(check_range(status, &partial("bytes 0-199/1000"), 100, 199, Some(1000)).is_err());
+        assert!(check_range(status, &partial("bytes 100-299/1000"), 100, 199, Some(1000)).is_err());
+        assert!(check_range(status, &partial("bytes 100-199/2000"), 100, 199, Some(1000)).is_err());
+        assert!(check_range(status, &HeaderMap::new(), 100, 199, Some(1000)).is_err());
+        assert!(check_range(StatusCode::OK, &HeaderMap::new(), 100, 199, Some(1000)).is_err());
+    }
+}
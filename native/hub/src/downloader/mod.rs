@@ -1,39 +1,70 @@
+pub mod dash;
+pub mod error;
+pub mod hls;
 pub mod main;
+pub mod segment;
+pub mod storage;
+pub mod throttle;
 
-use std::{str::from_utf8, sync::Arc, time::Duration};
+use std::{str::FromStr, sync::Arc};
 use reqwest::Client;
 use uuid::Uuid;
 
 use main::{DownloadManager};
+use dash::ContentKind;
+use hls::Playlist;
+use storage::Storage;
 use crate::utils::{
     types::{
-        DMSettings, DownloadState
+        DMSettings, DownloadState, DownloadOptions, BandwidthWeight, QueueMove, DependencyPolicy, RemoteChangePolicy, DEFAULT_QUEUE,
     },
-    url::get_url_info,
-    helper::calc_speed,
+    url::{get_url_info, is_dash_url, is_hls_url},
+    helper::{app_data_dir, calc_speed, now_unix},
+    checksum::ExpectedChecksum,
 };
 
 use crate::utils::logger;
 use rinf::{DartSignal, RustSignal};
 use crate::signals::{
-    UpdateSettings,
-    QueryUrl, UrlQueryOutput, DoDownload, 
-    GetDownloadList, DownloadList, DownloadGlance,
+    QueryUrl, UrlQueryOutput, StreamVariant, StreamRendition, DoDownload,
     GetDownloadDetails, DownloadDetails,
-    PauseDownload, ResumeDownload, CancelDownload,
+    PauseDownload, ResumeDownload, CancelDownload, StopRecording, SetDownloadSpeedLimit, SetDownloadWeight, MoveDownload, SetDownloadPriority, SetDownloadQueue, SetDownloadDependencies,
 };
 
+const DATABASE_FILE: &str = "downloads.db";
+
+async fn open_storage() -> anyhow::Result<Arc<Storage>> {
+    let path = app_data_dir().join(DATABASE_FILE);
+    match Storage::open(&path).await {
+        Ok(storage) => return Ok(Arc::new(storage)),
+        Err(e) => logger::error(&format!(
+            "Failed to open download database at {}: {:?}", path.display(), e
+        )),
+    }
+    let storage = Storage::in_memory().await
+        .map_err(|e| anyhow::anyhow!("Failed to create in-memory download database: {:?}", e))?;
+    Ok(Arc::new(storage))
+}
+
 /// Function to spawn the single global DownloadManager at startup
-pub async fn start_download_manager(client: Client) -> Arc<DownloadManager> {
+pub async fn start_download_manager(client: Client) -> anyhow::Result<Arc<DownloadManager>> {
     let settings = DMSettings {
         speed_limit: 0,
         concurrency_limit: 3,
         download_threads: 8,
         download_timeout: 30,
         download_retries: 5,
+        schedule: Vec::new(),
+        queue_window: None,
+        queues: Vec::new(),
+        on_remote_change: RemoteChangePolicy::default(),
     };
-    let manager = DownloadManager::new(client, settings);
-    manager
+    let storage = open_storage().await?;
+    let manager = DownloadManager::new(client, settings, storage);
+    if let Err(e) = manager.restore().await {
+        logger::error(&format!("Failed to restore downloads: {:?}", e));
+    }
+    Ok(manager)
 }
 
 pub async fn query_url_info(client: Client) {
@@ -52,25 +83,36 @@ pub async fn query_url_info(client: Client) {
                     }
                     None => false,
                 };
+                let (variants, renditions) = if is_hls_url(&info.url, &info.content_type) {
+                    query_hls_variants(&client, &info.url).await
+                } else if is_dash_url(&info.url, &info.content_type) {
+                    query_dash_variants(&client, &info.url).await
+                } else {
+                    (Vec::new(), Vec::new())
+                };
                 UrlQueryOutput {
                     url: info.url,
                     name: info.name,
                     total_size: info.total_size,
                     accept_ranges: info.accept_ranges,
                     content_type: info.content_type,
-                    is_webpage: is_webpage,
+                    is_webpage,
+                    variants,
+                    renditions,
                     error: false,
                 }.send_signal_to_dart();
             }
             Err(e) => {
                 logger::error(&format!("Failed to query info for {}: {:?}", url, e));
                 UrlQueryOutput {
-                    url: url,
+                    url,
                     name: "Error".to_string(),
                     total_size: None,
                     accept_ranges: false,
                     content_type: None,
                     is_webpage: false,
+                    variants: Vec::new(),
+                    renditions: Vec::new(),
                     error: true,
                 }.send_signal_to_dart();
             },
@@ -78,19 +120,82 @@ pub async fn query_url_info(client: Client) {
     }
 }
 
+/// Lists the variants of a master playlist, best first, and its downloadable
+/// alternate renditions. Media playlists have neither.
+async fn query_hls_variants(client: &Client, url: &str) -> (Vec<StreamVariant>, Vec<StreamRendition>) {
+    match hls::fetch_playlist(client.get(url)).await {
+        Ok(Playlist::Master(master)) => {
+            let mut variants = master.variants;
+            variants.sort_by_key(|v| std::cmp::Reverse(v.bandwidth));
+            let variants = variants.into_iter()
+                .map(|v| StreamVariant {
+                    id: v.uri.to_string(),
+                    bandwidth: v.bandwidth,
+                    resolution: v.resolution,
+                    codecs: v.codecs,
+                })
+                .collect();
+            let renditions = master.renditions.into_iter()
+                .filter_map(|r| Some(StreamRendition {
+                    id: r.uri?.to_string(),
+                    kind: r.kind.as_str().to_string(),
+                    group_id: r.group_id,
+                    name: r.name,
+                    language: r.language,
+                    is_default: r.default,
+                }))
+                .collect();
+            (variants, renditions)
+        }
+        Ok(Playlist::Media(_)) => (Vec::new(), Vec::new()),
+        Err(e) => {
+            logger::error(&format!("Failed to read HLS playlist {}: {:?}", url, e));
+            (Vec::new(), Vec::new())
+        }
+    }
+}
+
+/// Lists the video representations of a manifest, best first, as variants and
+/// its audio representations as renditions. Ids repeated across periods are listed once.
+async fn query_dash_variants(client: &Client, url: &str) -> (Vec<StreamVariant>, Vec<StreamRendition>) {
+    let manifest = match dash::fetch_manifest(client.get(url)).await {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            logger::error(&format!("Failed to read DASH manifest {}: {:?}", url, e));
+            return (Vec::new(), Vec::new());
+        }
+    };
 
-async fn wait_for_download(manager: Arc<DownloadManager>, id: Uuid) -> Result<(), String> {
-    loop {
-        match manager.info(id).await {
-            Ok(info) => match info.state {
-                DownloadState::Completed => return Ok(()),
-                DownloadState::Error(e) => return Err(e),
-                _ => (),
-            },
-            Err(e) => return Err(e.to_string()),
+    let mut variants: Vec<StreamVariant> = Vec::new();
+    let mut renditions: Vec<StreamRendition> = Vec::new();
+    let sets = manifest.periods.iter().flat_map(|p| &p.adaptation_sets);
+    for set in sets {
+        for rep in &set.representations {
+            match set.kind {
+                ContentKind::Video if !variants.iter().any(|v| v.id == rep.id) => {
+                    variants.push(StreamVariant {
+                        id: rep.id.clone(),
+                        bandwidth: rep.bandwidth,
+                        resolution: rep.width.zip(rep.height).map(|(w, h)| format!("{}x{}", w, h)),
+                        codecs: rep.codecs.clone(),
+                    });
+                }
+                ContentKind::Audio if !renditions.iter().any(|r| r.id == rep.id) => {
+                    renditions.push(StreamRendition {
+                        id: rep.id.clone(),
+                        kind: "audio".to_string(),
+                        group_id: rep.codecs.clone().unwrap_or_default(),
+                        name: format!("{} ({} kbps)", rep.id, rep.bandwidth / 1000),
+                        language: set.lang.clone(),
+                        is_default: false,
+                    });
+                }
+                _ => {}
+            }
         }
-        tokio::time::sleep(Duration::from_secs(1)).await;
     }
+    variants.sort_by_key(|v| std::cmp::Reverse(v.bandwidth));
+    (variants, renditions)
 }
 
 pub async fn spawn_download_worker(manager: Arc<DownloadManager>) {
@@ -99,118 +204,95 @@ pub async fn spawn_download_worker(manager: Arc<DownloadManager>) {
         let data = signal_pack.message;
         let mut dest = std::path::PathBuf::from(data.dest);
         let manager = Arc::clone(&manager);
+        let weight = match data.weight.as_deref().map(BandwidthWeight::from_str).transpose() {
+            Ok(weight) => weight.unwrap_or_default(),
+            Err(e) => {
+                logger::error(&format!("Ignoring bandwidth weight: {:?}", e));
+                BandwidthWeight::default()
+            }
+        };
+        let (depends_on, on_dependency_failure) = match parse_dependencies(
+            data.depends_on.as_deref().unwrap_or_default(),
+            data.on_dependency_failure.as_deref(),
+        ) {
+            Ok(dependencies) => dependencies,
+            Err(e) => {
+                logger::error(&format!("Invalid dependencies from Dart: {:?}", e));
+                continue;
+            }
+        };
+        let state = initial_state(data.start_at, data.start_paused.unwrap_or(false));
+        let limits = DownloadOptions {
+            speed_limit: data.speed_limit.filter(|l| *l > 0),
+            bypass_global_limit: data.bypass_global_limit.unwrap_or(false),
+            weight,
+            priority: data.priority.unwrap_or_default(),
+            queue: data.queue.filter(|q| !q.is_empty() && q != DEFAULT_QUEUE),
+            depends_on,
+            on_dependency_failure,
+            ..Default::default()
+        };
 
         if data.is_ytdl {
-            tokio::spawn(async move {
-                let video_format = data.video_format;
-                let audio_format = data.audio_format;
-
-                let mut temp_dest_base = dest.clone(); 
-                
-                let mut video_dest: Option<std::path::PathBuf> = None;
-                let mut audio_dest: Option<std::path::PathBuf> = None;
-
-                if audio_format.is_some() && video_format.is_some() {
-
-                    if let Some(mut file_name) = temp_dest_base.file_name()
-                        .and_then(|s| s.to_string_lossy().into_owned().into()) {
-                            file_name.push_str("_part"); 
-                            temp_dest_base.set_file_name(file_name); 
-                    }
-                    if let Some(format) = &video_format {
-                        dest = dest.with_extension(format.ext.clone());
-                    }
-                }
-                
-                let audio_id = if let Some(format) = audio_format {
-                    let path = temp_dest_base.with_extension(format.ext);
-                    audio_dest = Some(path.clone());
-                    match manager.add_download(format.url.clone(), path).await {
-                        Ok(id) => Some(id),
-                        Err(e) => {
-                            logger::error(&format!("Failed to spawn ytdl audio worker: {:?}", e));
-                            None
-                        }
-                    }
-                } else {
-                    None
-                };
+            let video_format = data.video_format;
+            let audio_format = data.audio_format;
+            let merging = audio_format.is_some() && video_format.is_some();
 
-                let video_id = if let Some(format) = video_format {
-                    let path = temp_dest_base.with_extension(format.ext);
-                    video_dest = Some(path.clone());
-                    match manager.add_download(format.url.clone(), path).await {
-                        Ok(id) => Some(id),
-                        Err(e) => {
-                            logger::error(&format!("Failed to spawn ytdl video worker: {:?}", e));
-                            None
-                        }
-                    }
-                } else {
-                    None
-                };
-
-                let mut handles = Vec::new();
-                if let Some(vid) = video_id {
-                    let manager_clone = Arc::clone(&manager);
-                    handles.push(tokio::spawn(async move {
-                        wait_for_download(manager_clone, vid).await
-                    }));
+            let mut temp_dest_base = dest.clone();
+            if merging {
+                if let Some(mut file_name) = temp_dest_base.file_name()
+                    .and_then(|s| s.to_string_lossy().into_owned().into()) {
+                        file_name.push_str("_part");
+                        temp_dest_base.set_file_name(file_name);
                 }
-                if let Some(aid) = audio_id {
-                    let manager_clone = Arc::clone(&manager);
-                    handles.push(tokio::spawn(async move {
-                        wait_for_download(manager_clone, aid).await
-                    }));
+                if let Some(format) = &video_format {
+                    dest = dest.with_extension(format.ext.clone());
                 }
+            }
 
-                for handle in handles {
-                    if let Err(e) = handle.await.unwrap() {
-                        logger::error(&format!("Download failed: {}", e));
-                        return;
-                    }
+            let mut parts = Vec::new();
+            for (kind, format) in [("video", video_format), ("audio", audio_format)] {
+                let Some(format) = format else { continue };
+                let path = temp_dest_base.with_extension(format.ext);
+                match manager.add_download(format.url.clone(), path.clone(), limits.clone(), state.clone()).await {
+                    Ok(id) => parts.push((id, format.url, path)),
+                    Err(e) => logger::error(&format!("Failed to spawn ytdl {} worker: {:?}", kind, e)),
                 }
+            }
 
-                if video_id.is_some() && audio_id.is_some() {
-                    logger::debug("Downloads complete, starting merge");
-                    let mut command = tokio::process::Command::new("ffmpeg");
-                    if let Some(v_path) = video_dest.as_ref() { 
-                        command.arg("-i").arg(v_path);
-                    }
-                    if let Some(a_path) = audio_dest.as_ref() { 
-                        command.arg("-i").arg(a_path);
-                    }
-                    command.arg("-c").arg("copy");
-                    command.arg("-map").arg("0:v:0");
-                    command.arg("-map").arg("1:a:0");
-                    command.arg("-y");
-                    command.arg(&dest);
-    
-                    match command.output().await {
-                        Ok(output) => {
-                            if output.status.success() {
-                                logger::debug("Merge successful");
-                                if let Some(v_path) = video_dest.as_ref() { 
-                                    let _ = tokio::fs::remove_file(v_path).await;
-                                }
-                                if let Some(a_path) = audio_dest.as_ref() { 
-                                    let _ = tokio::fs::remove_file(a_path).await;
-                                }
-                            } else {
-                                logger::error(&format!(
-                                    "ffmpeg error: {}",
-                                    String::from_utf8_lossy(&output.stderr)
-                                ));
-                            }
-                        }
-                        Err(e) => {
-                            logger::error(&format!("ffmpeg execution failed: {}", e));
-                        }
-                    }
+            // The merge waits in the queue, blocked until both parts have completed
+            if merging && let [(video_id, video_url, video_path), (audio_id, _, audio_path)] = &parts[..] {
+                let options = DownloadOptions {
+                    priority: limits.priority,
+                    queue: limits.queue.clone(),
+                    depends_on: vec![*video_id, *audio_id],
+                    on_dependency_failure: DependencyPolicy::Fail,
+                    merge_inputs: vec![video_path.clone(), audio_path.clone()],
+                    ..Default::default()
+                };
+                match manager.add_download(video_url.clone(), dest, options, DownloadState::Queued).await {
+                    Ok(id) => logger::debug(&format!("Merge of {} and {} queued as {}", video_id, audio_id, id)),
+                    Err(e) => logger::error(&format!("Failed to queue ytdl merge: {:?}", e)),
                 }
-            });
+            }
         } else if let Some(url) = data.url {
-            match manager.add_download(url.clone(), dest).await {
+            let checksum = match data.checksum.map(|c| ExpectedChecksum::new(&c.algorithm, &c.digest)).transpose() {
+                Ok(checksum) => checksum,
+                Err(e) => {
+                    logger::error(&format!("Invalid checksum for {}: {:?}", url, e));
+                    continue;
+                }
+            };
+            let options = DownloadOptions {
+                checksum,
+                stream_variant: data.stream_variant,
+                stream_audio: data.stream_audio,
+                stream_subtitles: data.stream_subtitles,
+                live_max_duration: data.live_max_duration,
+                live_max_size: data.live_max_size,
+                ..limits
+            };
+            match manager.add_download(url.clone(), dest, options, state).await {
                 Ok(id) => logger::debug(&format!("Spawned worker for {} with id {}", url, id)),
                 Err(e) => {
                     logger::error(&format!("Failed to spawn worker for {}: {:?}", url, e))
@@ -220,6 +302,26 @@ pub async fn spawn_download_worker(manager: Arc<DownloadManager>) {
     }
 }
 
+fn parse_dependencies(ids: &[String], policy: Option<&str>) -> anyhow::Result<(Vec<Uuid>, DependencyPolicy)> {
+    let depends_on = ids.iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect::<Result<Vec<_>, _>>()?;
+    let policy = policy.map(DependencyPolicy::from_str).transpose()?.unwrap_or_default();
+    Ok((depends_on, policy))
+}
+
+/// State a new download is added in: paused wins over a start time, and a start time
+/// already in the past just queues the download.
+fn initial_state(start_at: Option<i64>, start_paused: bool) -> DownloadState {
+    if start_paused {
+        return DownloadState::Paused;
+    }
+    match start_at {
+        Some(at) if at > now_unix() => DownloadState::Scheduled(at),
+        _ => DownloadState::Queued,
+    }
+}
+
 pub async fn get_download_details(manager: Arc<DownloadManager>) {
     let receiver = GetDownloadDetails::get_dart_signal_receiver();
     while let Some(signal_pack) = receiver.recv().await {
@@ -236,13 +338,18 @@ pub async fn get_download_details(manager: Arc<DownloadManager>) {
         let manager = Arc::clone(&manager);
         match manager.info(id).await {
             Ok(info) => {
+                let log = manager.log(id).await.unwrap_or_default();
                 let state_str = match &info.state {
                     DownloadState::Queued => "Queued".to_string(),
+                    DownloadState::Scheduled(_) => "Scheduled".to_string(),
+                    DownloadState::Blocked => "Blocked".to_string(),
                     DownloadState::Running => "Running".to_string(),
+                    DownloadState::Recording => "Recording".to_string(),
                     DownloadState::Paused => "Paused".to_string(),
                     DownloadState::Completed => "Completed".to_string(),
                     DownloadState::Cancelled => "Cancelled".to_string(),
-                    DownloadState::Error(e) => format!("Error: {}", e),
+                    DownloadState::Error(_, e) => format!("Error: {}", e),
+                    DownloadState::ChecksumMismatch(e) => format!("ChecksumMismatch: {}", e),
                 };
                 let speed = calc_speed(info.history);
                 DownloadDetails {
@@ -255,8 +362,23 @@ pub async fn get_download_details(manager: Arc<DownloadManager>) {
                     dest: info.dest.display().to_string(),
                     total_size: info.total_size,
                     downloaded: info.downloaded,
-                    speed: speed,
+                    speed,
                     state: state_str,
+                    parts_done: info.parts.map(|(done, _)| done),
+                    parts_total: info.parts.map(|(_, total)| total),
+                    speed_limit: info.options.speed_limit,
+                    bypass_global_limit: info.options.bypass_global_limit,
+                    weight: info.options.weight.to_string(),
+                    start_at: match info.state {
+                        DownloadState::Scheduled(at) => Some(at),
+                        _ => None,
+                    },
+                    priority: info.options.priority,
+                    queue: info.options.queue_name().to_string(),
+                    depends_on: info.options.depends_on.iter().map(|d| d.to_string()).collect(),
+                    on_dependency_failure: info.options.on_dependency_failure.to_string(),
+                    error_kind: info.state.error_kind().map(|k| k.to_string()),
+                    log,
                 }.send_signal_to_dart();
             }
             Err(e) => {
@@ -287,6 +409,26 @@ pub async fn pause_download(manager: Arc<DownloadManager>) {
     }
 }
 
+pub async fn stop_recording(manager: Arc<DownloadManager>) {
+    let receiver = StopRecording::get_dart_signal_receiver();
+    while let Some(signal_pack) = receiver.recv().await {
+        let data = signal_pack.message;
+
+        let id = match Uuid::parse_str(&data.id) {
+            Ok(uuid) => uuid,
+            Err(e) => {
+                logger::error(&format!("Invalid UUID from Dart: {:?}", e));
+                continue;
+            }
+        };
+
+        match manager.stop_recording(id).await {
+            Ok(_) => logger::debug(&format!("Stopping recording {}", id)),
+            Err(e) => logger::error(&format!("Failed to stop recording for {:?}", e)),
+        }
+    }
+}
+
 pub async fn resume_download(manager: Arc<DownloadManager>) {
     let receiver = ResumeDownload::get_dart_signal_receiver();
     while let Some(signal_pack) = receiver.recv().await {
@@ -308,6 +450,147 @@ pub async fn resume_download(manager: Arc<DownloadManager>) {
     }
 }
 
+pub async fn set_download_speed_limit(manager: Arc<DownloadManager>) {
+    let receiver = SetDownloadSpeedLimit::get_dart_signal_receiver();
+    while let Some(signal_pack) = receiver.recv().await {
+        let data = signal_pack.message;
+
+        let id = match Uuid::parse_str(&data.id) {
+            Ok(uuid) => uuid,
+            Err(e) => {
+                logger::error(&format!("Invalid UUID from Dart: {:?}", e));
+                continue;
+            }
+        };
+
+        match manager.set_speed_limit(id, data.speed_limit, data.bypass_global_limit).await {
+            Ok(_) => logger::debug(&format!("Speed limit of {} set to {:?}", id, data.speed_limit)),
+            Err(e) => logger::error(&format!("Failed to set speed limit for {:?}", e)),
+        }
+    }
+}
+
+pub async fn set_download_weight(manager: Arc<DownloadManager>) {
+    let receiver = SetDownloadWeight::get_dart_signal_receiver();
+    while let Some(signal_pack) = receiver.recv().await {
+        let data = signal_pack.message;
+
+        let id = match Uuid::parse_str(&data.id) {
+            Ok(uuid) => uuid,
+            Err(e) => {
+                logger::error(&format!("Invalid UUID from Dart: {:?}", e));
+                continue;
+            }
+        };
+        let weight = match BandwidthWeight::from_str(&data.weight) {
+            Ok(weight) => weight,
+            Err(e) => {
+                logger::error(&format!("Invalid weight from Dart: {:?}", e));
+                continue;
+            }
+        };
+
+        match manager.set_weight(id, weight).await {
+            Ok(_) => logger::debug(&format!("Bandwidth weight of {} set to {}", id, weight)),
+            Err(e) => logger::error(&format!("Failed to set bandwidth weight for {:?}", e)),
+        }
+    }
+}
+
+pub async fn move_download(manager: Arc<DownloadManager>) {
+    let receiver = MoveDownload::get_dart_signal_receiver();
+    while let Some(signal_pack) = receiver.recv().await {
+        let data = signal_pack.message;
+
+        let id = match Uuid::parse_str(&data.id) {
+            Ok(uuid) => uuid,
+            Err(e) => {
+                logger::error(&format!("Invalid UUID from Dart: {:?}", e));
+                continue;
+            }
+        };
+        let to = match QueueMove::from_str(&data.direction) {
+            Ok(to) => to,
+            Err(e) => {
+                logger::error(&format!("Invalid queue move from Dart: {:?}", e));
+                continue;
+            }
+        };
+
+        match manager.move_download(id, to).await {
+            Ok(_) => logger::debug(&format!("Moved {} {:?} in the queue", id, to)),
+            Err(e) => logger::error(&format!("Failed to move download for {:?}", e)),
+        }
+    }
+}
+
+pub async fn set_download_dependencies(manager: Arc<DownloadManager>) {
+    let receiver = SetDownloadDependencies::get_dart_signal_receiver();
+    while let Some(signal_pack) = receiver.recv().await {
+        let data = signal_pack.message;
+
+        let id = match Uuid::parse_str(&data.id) {
+            Ok(uuid) => uuid,
+            Err(e) => {
+                logger::error(&format!("Invalid UUID from Dart: {:?}", e));
+                continue;
+            }
+        };
+        let (depends_on, policy) = match parse_dependencies(&data.depends_on, data.on_dependency_failure.as_deref()) {
+            Ok(dependencies) => dependencies,
+            Err(e) => {
+                logger::error(&format!("Invalid dependencies from Dart: {:?}", e));
+                continue;
+            }
+        };
+
+        match manager.set_dependencies(id, depends_on, policy).await {
+            Ok(_) => logger::debug(&format!("Dependencies of {} updated", id)),
+            Err(e) => logger::error(&format!("Failed to set dependencies for {:?}", e)),
+        }
+    }
+}
+
+pub async fn set_download_queue(manager: Arc<DownloadManager>) {
+    let receiver = SetDownloadQueue::get_dart_signal_receiver();
+    while let Some(signal_pack) = receiver.recv().await {
+        let data = signal_pack.message;
+
+        let id = match Uuid::parse_str(&data.id) {
+            Ok(uuid) => uuid,
+            Err(e) => {
+                logger::error(&format!("Invalid UUID from Dart: {:?}", e));
+                continue;
+            }
+        };
+
+        match manager.set_queue(id, &data.queue).await {
+            Ok(_) => logger::debug(&format!("Moved {} to queue {}", id, data.queue)),
+            Err(e) => logger::error(&format!("Failed to change queue for {:?}", e)),
+        }
+    }
+}
+
+pub async fn set_download_priority(manager: Arc<DownloadManager>) {
+    let receiver = SetDownloadPriority::get_dart_signal_receiver();
+    while let Some(signal_pack) = receiver.recv().await {
+        let data = signal_pack.message;
+
+        let id = match Uuid::parse_str(&data.id) {
+            Ok(uuid) => uuid,
+            Err(e) => {
+                logger::error(&format!("Invalid UUID from Dart: {:?}", e));
+                continue;
+            }
+        };
+
+        match manager.set_priority(id, data.priority).await {
+            Ok(_) => logger::debug(&format!("Priority of {} set to {}", id, data.priority)),
+            Err(e) => logger::error(&format!("Failed to set priority for {:?}", e)),
+        }
+    }
+}
+
 pub async fn cancel_download(manager: Arc<DownloadManager>) {
     let receiver = CancelDownload::get_dart_signal_receiver();
     while let Some(signal_pack) = receiver.recv().await {
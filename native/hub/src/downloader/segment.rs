@@ -0,0 +1,283 @@
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use tokio::sync::{Mutex, Notify};
+
+use crate::utils::types::SegmentInfo;
+
+/// Smallest range a split may leave on either side.
+pub const MIN_SPLIT_SIZE: u64 = 1024 * 1024;
+/// A segment slower than this fraction of the average is considered stalled...
+const STALL_SPEED_RATIO: f64 = 0.25;
+/// ...once it has been that slow for this many consecutive samples.
+const STALL_SAMPLES: u32 = 5;
+
+/// One ranged connection's share of the file.
+#[derive(Debug)]
+pub struct Segment {
+    pub range: Mutex<SegmentInfo>,
+    // how much of `written` has been flushed to the file, and so may be persisted
+    pub flushed: AtomicU64,
+    // wakes the owning task so it drops its connection and opens a new one
+    pub reconnect: Notify,
+}
+
+/// A segment shared between its download task and the worker that schedules and persists it.
+pub type SharedSegment = Arc<Segment>;
+
+/// Cuts `0..size` into `parts` contiguous ranges, the last one taking the remainder.
+/// An empty file has nothing to fetch and gets no ranges at all.
+pub fn split_even(size: u64, parts: u64) -> Vec<SegmentInfo> {
+    if size == 0 {
+        return Vec::new();
+    }
+    let parts = parts.clamp(1, size);
+    let part_size = size / parts;
+    (0..parts)
+        .map(|i| {
+            let start = i * part_size;
+            let end = if i == parts - 1 { size.saturating_sub(1) } else { start + part_size - 1 };
+            SegmentInfo::new(start, end)
+        })
+        .collect()
+}
+
+pub fn share_one(segment: SegmentInfo) -> SharedSegment {
+    Arc::new(Segment {
+        flushed: AtomicU64::new(segment.written),
+        range: Mutex::new(segment),
+        reconnect: Notify::new(),
+    })
+}
+
+pub fn share(segments: Vec<SegmentInfo>) -> Vec<SharedSegment> {
+    segments.into_iter().map(share_one).collect()
+}
+
+pub async fn snapshot(segments: &[SharedSegment]) -> Vec<SegmentInfo> {
+    let mut out = Vec::with_capacity(segments.len());
+    for seg in segments {
+        out.push(*seg.range.lock().await);
+    }
+    out
+}
+
+/// Like `snapshot`, but counting only the bytes known to be in the file, which is what
+/// a resume may rely on.
+pub async fn flushed_snapshot(segments: &[SharedSegment]) -> Vec<SegmentInfo> {
+    let mut out = Vec::with_capacity(segments.len());
+    for seg in segments {
+        let mut range = *seg.range.lock().await;
+        range.written = range.written.min(seg.flushed.load(Ordering::SeqCst));
+        out.push(range);
+    }
+    out
+}
+
+/// Takes the upper half of the unfinished segment with the most bytes left.
+/// The donor keeps the lower half; `None` when nothing is worth splitting.
+pub async fn split_largest(segments: &[SharedSegment]) -> Option<SegmentInfo> {
+    let mut largest: Option<(&SharedSegment, u64)> = None;
+    for seg in segments {
+        let remaining = seg.range.lock().await.remaining();
+        if largest.is_none_or(|(_, r)| remaining > r) {
+            largest = Some((seg, remaining));
+        }
+    }
+
+    let (donor, _) = largest?;
+    // Re-check under the lock, the donor may have advanced meanwhile.
+    let mut range = donor.range.lock().await;
+    let remaining = range.remaining();
+    if range.end == u64::MAX || remaining < 2 * MIN_SPLIT_SIZE {
+        return None;
+    }
+    let mid = range.position() + remaining / 2;
+    let stolen = SegmentInfo::new(mid, range.end);
+    range.end = mid - 1;
+    Some(stolen)
+}
+
+/// Tracks per-segment throughput between samples to find connections
+/// that crawl far below their siblings.
+#[derive(Debug, Default)]
+pub struct StallDetector {
+    last_written: Vec<u64>,
+    slow_samples: Vec<u32>,
+}
+
+impl StallDetector {
+    /// Feeds one sample and returns the indexes of segments that should reconnect.
+    pub fn sample(&mut self, segments: &[SegmentInfo]) -> Vec<usize> {
+        self.last_written.resize(segments.len(), 0);
+        self.slow_samples.resize(segments.len(), 0);
+
+        let speeds = segments
+            .iter()
+            .zip(&self.last_written)
+            .map(|(s, last)| s.written.saturating_sub(*last))
+            .collect::<Vec<_>>();
+        for (last, seg) in self.last_written.iter_mut().zip(segments) {
+            *last = seg.written;
+        }
+
+        let active = segments
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| !s.is_complete())
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if active.len() < 2 {
+            return Vec::new();
+        }
+        let average = active.iter().map(|i| speeds[*i]).sum::<u64>() as f64 / active.len() as f64;
+        if average <= 0.0 {
+            return Vec::new();
+        }
+
+        let mut stalled = Vec::new();
+        for i in active {
+            if (speeds[i] as f64) < average * STALL_SPEED_RATIO && segments[i].remaining() >= MIN_SPLIT_SIZE {
+                self.slow_samples[i] += 1;
+                if self.slow_samples[i] >= STALL_SAMPLES {
+                    self.slow_samples[i] = 0;
+                    stalled.push(i);
+                }
+            } else {
+                self.slow_samples[i] = 0;
+            }
+        }
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_even_covers_the_whole_file() {
+        let parts = split_even(10, 3);
+        assert_eq!(
+            parts.iter().map(|s| (s.start, s.end)).collect::<Vec<_>>(),
+            vec![(0, 2), (3, 5), (6, 9)]
+        );
+    }
+
+    #[test]
+    fn split_even_never_makes_more_parts_than_bytes() {
+        let parts = split_even(2, 8);
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|s| s.remaining() == 1));
+    }
+
+    #[test]
+    fn split_even_of_an_empty_file_is_empty() {
+        assert!(split_even(0, 1).is_empty());
+        assert!(split_even(0, 8).is_empty());
+    }
+
+    fn progressed(start: u64, end: u64, written: u64) -> SegmentInfo {
+        SegmentInfo { start, end, written }
+    }
+
+    #[tokio::test]
+    async fn split_largest_halves_what_is_left_of_the_biggest_segment() {
+        let segments = share(vec![
+            progressed(0, 4 * MIN_SPLIT_SIZE - 1, 0),
+            progressed(4 * MIN_SPLIT_SIZE, 12 * MIN_SPLIT_SIZE - 1, 2 * MIN_SPLIT_SIZE),
+        ]);
+        let stolen = split_largest(&segments).await;
+
+        // 6 MiB left from offset 6 MiB: the donor keeps 6..9, the new range takes 9..12
+        assert_eq!(stolen.map(|s| (s.start, s.end)), Some((9 * MIN_SPLIT_SIZE, 12 * MIN_SPLIT_SIZE - 1)));
+        assert_eq!(segments[1].range.lock().await.end, 9 * MIN_SPLIT_SIZE - 1);
+        assert_eq!(segments[0].range.lock().await.end, 4 * MIN_SPLIT_SIZE - 1);
+    }
+
+    #[tokio::test]
+    async fn split_largest_leaves_small_segments_alone() {
+        let segments = share(vec![
+            progressed(0, 1, 1),
+            progressed(2, 2, 0),
+            progressed(3, 2 * MIN_SPLIT_SIZE + 1, 0),
+        ]);
+        assert!(split_largest(&segments).await.is_none());
+        assert!(split_largest(&[]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn split_largest_never_splits_an_open_ended_range() {
+        let segments = share(vec![SegmentInfo::new(0, u64::MAX)]);
+        assert!(split_largest(&segments).await.is_none());
+    }
+
+    /// Advances every segment by the given number of bytes.
+    fn advance(segments: &mut [SegmentInfo], bytes: &[u64]) {
+        for (seg, b) in segments.iter_mut().zip(bytes) {
+            seg.written += b;
+        }
+    }
+
+    #[test]
+    fn stall_detector_flags_a_crawling_segment_after_enough_samples() {
+        let size = 100 * MIN_SPLIT_SIZE;
+        let mut segments = vec![SegmentInfo::new(0, size - 1), SegmentInfo::new(size, 2 * size - 1)];
+        let mut detector = StallDetector::default();
+        for _ in 0..STALL_SAMPLES - 1 {
+            advance(&mut segments, &[MIN_SPLIT_SIZE, 1]);
+            assert!(detector.sample(&segments).is_empty());
+        }
+        advance(&mut segments, &[MIN_SPLIT_SIZE, 1]);
+        assert_eq!(detector.sample(&segments), vec![1]);
+        // the count starts over once the segment has been flagged
+        advance(&mut segments, &[MIN_SPLIT_SIZE, 1]);
+        assert!(detector.sample(&segments).is_empty());
+    }
+
+    #[test]
+    fn stall_detector_forgets_a_segment_that_catches_up() {
+        let size = 100 * MIN_SPLIT_SIZE;
+        let mut segments = vec![SegmentInfo::new(0, size - 1), SegmentInfo::new(size, 2 * size - 1)];
+        let mut detector = StallDetector::default();
+        for _ in 0..STALL_SAMPLES - 1 {
+            advance(&mut segments, &[MIN_SPLIT_SIZE, 1]);
+            detector.sample(&segments);
+        }
+        advance(&mut segments, &[MIN_SPLIT_SIZE, MIN_SPLIT_SIZE]);
+        assert!(detector.sample(&segments).is_empty());
+        advance(&mut segments, &[MIN_SPLIT_SIZE, 1]);
+        assert!(detector.sample(&segments).is_empty());
+    }
+
+    #[test]
+    fn stall_detector_ignores_a_lone_or_nearly_done_segment() {
+        let size = 100 * MIN_SPLIT_SIZE;
+        let mut detector = StallDetector::default();
+        let mut single = vec![SegmentInfo::new(0, size - 1)];
+        for _ in 0..2 * STALL_SAMPLES {
+            advance(&mut single, &[1]);
+            assert!(detector.sample(&single).is_empty());
+        }
+
+        // the slow one has less than MIN_SPLIT_SIZE left, reconnecting would not pay off
+        let mut detector = StallDetector::default();
+        let mut segments = vec![SegmentInfo::new(0, size - 1), progressed(size, size + 10, 0)];
+        for _ in 0..2 * STALL_SAMPLES {
+            advance(&mut segments, &[MIN_SPLIT_SIZE, 0]);
+            assert!(detector.sample(&segments).is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn flushed_snapshot_leaves_out_unflushed_bytes() {
+        let mut stored = SegmentInfo::new(0, 99);
+        stored.written = 40;
+        let segments = share(vec![stored, SegmentInfo::new(100, 199)]);
+        segments[0].range.lock().await.written = 70;
+        segments[1].range.lock().await.written = 30;
+        segments[1].flushed.store(30, Ordering::SeqCst);
+
+        let written = |s: Vec<SegmentInfo>| s.iter().map(|s| s.written).collect::<Vec<_>>();
+        assert_eq!(written(snapshot(&segments).await), vec![70, 30]);
+        assert_eq!(written(flushed_snapshot(&segments).await), vec![40, 30]);
+    }
+}
@@ -0,0 +1,414 @@
+use anyhow::Result;
+use sqlx::{
+    Row,
+    sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow},
+};
+use std::{collections::HashMap, path::{Path, PathBuf}, str::FromStr};
+use uuid::Uuid;
+
+use crate::utils::{
+    helper::now_unix,
+    types::{DownloadInfo, DownloadState, ErrorKind, RemoteValidator, SegmentInfo},
+};
+
+/// Schema migrations, applied in order. `PRAGMA user_version` records
+/// how many of them have already run on a given database.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS downloads (
+        id TEXT PRIMARY KEY NOT NULL,
+        url TEXT NOT NULL,
+        dest TEXT NOT NULL,
+        total_size INTEGER,
+        downloaded INTEGER NOT NULL DEFAULT 0,
+        state TEXT NOT NULL,
+        error TEXT,
+        created_at INTEGER NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS segments (
+        download_id TEXT NOT NULL REFERENCES downloads(id) ON DELETE CASCADE,
+        idx INTEGER NOT NULL,
+        range_start INTEGER NOT NULL,
+        range_end INTEGER NOT NULL,
+        written INTEGER NOT NULL,
+        PRIMARY KEY (download_id, idx)
+    )",
+    "ALTER TABLE downloads ADD COLUMN options TEXT NOT NULL DEFAULT '{}'",
+    "CREATE TABLE IF NOT EXISTS hls_parts (
+        download_id TEXT NOT NULL REFERENCES downloads(id) ON DELETE CASCADE,
+        idx INTEGER NOT NULL,
+        uri TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        PRIMARY KEY (download_id, idx)
+    )",
+    "ALTER TABLE downloads ADD COLUMN start_at INTEGER",
+    "ALTER TABLE downloads ADD COLUMN position INTEGER",
+    "ALTER TABLE downloads ADD COLUMN etag TEXT",
+    "ALTER TABLE downloads ADD COLUMN last_modified TEXT",
+    "ALTER TABLE downloads ADD COLUMN error_kind TEXT",
+];
+
+/// SQLite backed store for the download queue.
+#[derive(Debug)]
+pub struct Storage {
+    pool: SqlitePool,
+}
+
+impl Storage {
+    pub async fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await?;
+        Self::with_pool(pool).await
+    }
+
+    /// Non-persistent fallback used when the database file cannot be opened.
+    pub async fn in_memory() -> Result<Self> {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")?;
+        // Every connection to `:memory:` is a separate database, so keep one.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?;
+        Self::with_pool(pool).await
+    }
+
+    async fn with_pool(pool: SqlitePool) -> Result<Self> {
+        let storage = Self { pool };
+        storage.migrate().await?;
+        Ok(storage)
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&self.pool)
+            .await?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version.max(0) as usize) {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query(migration).execute(&mut *tx).await?;
+            // PRAGMA does not accept bound parameters.
+            sqlx::query(&format!("PRAGMA user_version = {}", i + 1))
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Inserts or updates the row for `info`, replacing its segment rows.
+    pub async fn save(&self, info: &DownloadInfo) -> Result<()> {
+        let (state, error) = state_to_columns(&info.state);
+        let id = info.id.to_string();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO downloads (id, url, dest, total_size, downloaded, state, error, created_at, options, start_at, etag, last_modified, error_kind)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+             ON CONFLICT(id) DO UPDATE SET
+                url = excluded.url,
+                dest = excluded.dest,
+                total_size = excluded.total_size,
+                downloaded = excluded.downloaded,
+                state = excluded.state,
+                error = excluded.error,
+                options = excluded.options,
+                start_at = excluded.start_at,
+                etag = excluded.etag,
+                last_modified = excluded.last_modified,
+                error_kind = excluded.error_kind",
+        )
+        .bind(&id)
+        .bind(&info.url)
+        .bind(info.dest.to_string_lossy().into_owned())
+        .bind(info.total_size.map(|s| s as i64))
+        .bind(info.downloaded as i64)
+        .bind(state)
+        .bind(error)
+        .bind(now_unix())
+        .bind(serde_json::to_string(&info.options)?)
+        .bind(match info.state {
+            DownloadState::Scheduled(at) => Some(at),
+            _ => None,
+        })
+        .bind(&info.validator.etag)
+        .bind(&info.validator.last_modified)
+        .bind(info.state.error_kind().map(|k| k.to_string()))
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM segments WHERE download_id = ?1")
+            .bind(&id)
+            .execute(&mut *tx)
+            .await?;
+        for (idx, seg) in info.segments.iter().enumerate() {
+            sqlx::query(
+                "INSERT INTO segments (download_id, idx, range_start, range_end, written)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .bind(&id)
+            .bind(idx as i64)
+            .bind(seg.start as i64)
+            .bind(seg.end as i64)
+            .bind(seg.written as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Loads every stored download in the order they were added.
+    pub async fn load_all(&self) -> Result<Vec<DownloadInfo>> {
+        let rows = sqlx::query(
+            "SELECT id, url, dest, total_size, downloaded, state, error, options, start_at, etag, last_modified, error_kind
+             FROM downloads ORDER BY position IS NULL, position, created_at, rowid",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut segments = self.load_segments().await?;
+
+        rows.iter()
+            .map(|row| {
+                let mut info = row_to_info(row)?;
+                info.segments = segments.remove(&info.id).unwrap_or_default();
+                Ok(info)
+            })
+            .collect()
+    }
+
+    async fn load_segments(&self) -> Result<HashMap<Uuid, Vec<SegmentInfo>>> {
+        let rows = sqlx::query(
+            "SELECT download_id, range_start, range_end, written
+             FROM segments ORDER BY download_id, idx",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut out: HashMap<Uuid, Vec<SegmentInfo>> = HashMap::new();
+        for row in rows {
+            let id: String = row.try_get("download_id")?;
+            let start: i64 = row.try_get("range_start")?;
+            let end: i64 = row.try_get("range_end")?;
+            let written: i64 = row.try_get("written")?;
+            out.entry(Uuid::parse_str(&id)?).or_default().push(SegmentInfo {
+                start: start as u64,
+                end: end as u64,
+                written: written as u64,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Records playlist segment `idx` of `id` as stored on disk with `size` bytes.
+    pub async fn save_part(&self, id: Uuid, idx: usize, uri: &str, size: u64) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO hls_parts (download_id, idx, uri, size)
+             VALUES (?1, ?2, ?3, ?4)",
+        )
+        .bind(id.to_string())
+        .bind(idx as i64)
+        .bind(uri)
+        .bind(size as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Finished playlist segments of `id`, keyed by index, with their URI and size.
+    pub async fn load_parts(&self, id: Uuid) -> Result<HashMap<usize, (String, u64)>> {
+        let rows = sqlx::query("SELECT idx, uri, size FROM hls_parts WHERE download_id = ?1")
+            .bind(id.to_string())
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut out = HashMap::new();
+        for row in rows {
+            let idx: i64 = row.try_get("idx")?;
+            let uri: String = row.try_get("uri")?;
+            let size: i64 = row.try_get("size")?;
+            out.insert(idx as usize, (uri, size as u64));
+        }
+        Ok(out)
+    }
+
+    /// Records the queue order, `ids` being the whole queue from first to last.
+    pub async fn save_order(&self, ids: &[Uuid]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for (position, id) in ids.iter().enumerate() {
+            sqlx::query("UPDATE downloads SET position = ?1 WHERE id = ?2")
+                .bind(position as i64)
+                .bind(id.to_string())
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn clear_parts(&self, id: Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM hls_parts WHERE download_id = ?1")
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+fn row_to_info(row: &SqliteRow) -> Result<DownloadInfo> {
+    let id: String = row.try_get("id")?;
+    let dest: String = row.try_get("dest")?;
+    let total_size: Option<i64> = row.try_get("total_size")?;
+    let downloaded: i64 = row.try_get("downloaded")?;
+    let state: String = row.try_get("state")?;
+    let error: Option<String> = row.try_get("error")?;
+    let error_kind: Option<String> = row.try_get("error_kind")?;
+    let options: String = row.try_get("options")?;
+    let start_at: Option<i64> = row.try_get("start_at")?;
+
+    Ok(DownloadInfo {
+        id: Uuid::parse_str(&id)?,
+        url: row.try_get("url")?,
+        dest: PathBuf::from(dest),
+        total_size: total_size.map(|s| s as u64),
+        downloaded: downloaded as u64,
+        state: state_from_columns(&state, error, error_kind.and_then(|k| k.parse().ok()), start_at),
+        history: Vec::new(),
+        segments: Vec::new(),
+        parts: None,
+        options: serde_json::from_str(&options).unwrap_or_default(),
+        validator: RemoteValidator {
+            etag: row.try_get("etag")?,
+            last_modified: row.try_get("last_modified")?,
+        },
+    })
+}
+
+fn state_to_columns(state: &DownloadState) -> (&'static str, Option<String>) {
+    match state {
+        DownloadState::Queued => ("Queued", None),
+        DownloadState::Scheduled(_) => ("Scheduled", None),
+        DownloadState::Blocked => ("Blocked", None),
+        DownloadState::Running => ("Running", None),
+        DownloadState::Recording => ("Recording", None),
+        DownloadState::Paused => ("Paused", None),
+        DownloadState::Completed => ("Completed", None),
+        DownloadState::Cancelled => ("Cancelled", None),
+        DownloadState::Error(_, e) => ("Error", Some(e.clone())),
+        DownloadState::ChecksumMismatch(e) => ("ChecksumMismatch", Some(e.clone())),
+    }
+}
+
+fn state_from_columns(state: &str, error: Option<String>, error_kind: Option<ErrorKind>, start_at: Option<i64>) -> DownloadState {
+    match state {
+        "Queued" => DownloadState::Queued,
+        "Scheduled" => DownloadState::Scheduled(start_at.unwrap_or_default()),
+        "Blocked" => DownloadState::Blocked,
+        "Running" => DownloadState::Running,
+        "Recording" => DownloadState::Recording,
+        "Paused" => DownloadState::Paused,
+        "Completed" => DownloadState::Completed,
+        "Cancelled" => DownloadState::Cancelled,
+        "ChecksumMismatch" => DownloadState::ChecksumMismatch(error.unwrap_or_default()),
+        _ => DownloadState::Error(
+            error_kind.unwrap_or_default(),
+            error.unwrap_or_else(|| format!("Unknown state {}", state)),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::types::DownloadOptions;
+
+    async fn memory_pool() -> SqlitePool {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:").unwrap();
+        SqlitePoolOptions::new().max_connections(1).connect_with(options).await.unwrap()
+    }
+
+    async fn user_version(storage: &Storage) -> i64 {
+        sqlx::query_scalar("PRAGMA user_version").fetch_one(&storage.pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trips_a_download_with_its_parts() {
+        let storage = Storage::in_memory().await.unwrap();
+        let options = DownloadOptions { priority: 3, queue: Some("night".to_string()), ..Default::default() };
+        let mut info = DownloadInfo::new(Uuid::new_v4(), "https://example.com/a.iso".to_string(), PathBuf::from("/tmp/a.iso"), options);
+        info.total_size = Some(1000);
+        info.downloaded = 300;
+        info.state = DownloadState::Error(ErrorKind::Network, "reset".to_string());
+        info.segments = vec![
+            SegmentInfo { start: 0, end: 499, written: 200 },
+            SegmentInfo { start: 500, end: 999, written: 100 },
+        ];
+        info.validator = RemoteValidator { etag: Some("\"v1\"".to_string()), last_modified: None };
+        storage.save(&info).await.unwrap();
+        // saving again replaces the segments instead of adding to them
+        storage.save(&info).await.unwrap();
+        storage.save_part(info.id, 0, "seg0.ts", 10).await.unwrap();
+        storage.save_part(info.id, 1, "seg1.ts", 20).await.unwrap();
+
+        let loaded = storage.load_all().await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        let loaded = &loaded[0];
+        assert_eq!(loaded.id, info.id);
+        assert_eq!(loaded.url, info.url);
+        assert_eq!(loaded.dest, info.dest);
+        assert_eq!(loaded.total_size, Some(1000));
+        assert_eq!(loaded.downloaded, 300);
+        assert!(matches!(&loaded.state, DownloadState::Error(ErrorKind::Network, e) if e == "reset"));
+        let ranges = |segments: &[SegmentInfo]| segments.iter().map(|s| (s.start, s.end, s.written)).collect::<Vec<_>>();
+        assert_eq!(ranges(&loaded.segments), ranges(&info.segments));
+        assert_eq!(loaded.validator, info.validator);
+        assert_eq!(loaded.options.priority, 3);
+        assert_eq!(loaded.options.queue_name(), "night");
+
+        let parts = storage.load_parts(info.id).await.unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[&1], ("seg1.ts".to_string(), 20));
+        storage.clear_parts(info.id).await.unwrap();
+        assert!(storage.load_parts(info.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn migrates_from_an_empty_database() {
+        let storage = Storage::with_pool(memory_pool().await).await.unwrap();
+        assert_eq!(user_version(&storage).await, MIGRATIONS.len() as i64);
+        // running again over an up to date database changes nothing
+        storage.migrate().await.unwrap();
+        assert_eq!(user_version(&storage).await, MIGRATIONS.len() as i64);
+    }
+
+    #[tokio::test]
+    async fn migrates_rows_of_the_first_schema() {
+        let pool = memory_pool().await;
+        sqlx::query(MIGRATIONS[0]).execute(&pool).await.unwrap();
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO downloads (id, url, dest, total_size, downloaded, state, error, created_at)
+             VALUES (?1, 'https://example.com/b.zip', '/tmp/b.zip', 50, 20, 'Paused', NULL, 0)",
+        )
+        .bind(id.to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("PRAGMA user_version = 1").execute(&pool).await.unwrap();
+
+        let storage = Storage::with_pool(pool).await.unwrap();
+        assert_eq!(user_version(&storage).await, MIGRATIONS.len() as i64);
+        let loaded = storage.load_all().await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].id, id);
+        assert_eq!(loaded[0].downloaded, 20);
+        assert!(matches!(loaded[0].state, DownloadState::Paused));
+        assert_eq!(loaded[0].validator, RemoteValidator::default());
+    }
+}
@@ -0,0 +1,188 @@
+//! Byte-rate limiting shared by every connection that draws from the same budget.
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, RwLock},
+};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+
+/// Smallest burst the bucket allows, so a single network chunk never waits on itself.
+const MIN_BURST: u32 = 16 * 1024;
+/// Rate changes smaller than this fraction keep the current bucket, rebuilding it refills the burst.
+const RATE_TOLERANCE: f64 = 0.05;
+
+/// A token bucket holding one token per byte. Connections charge it for every chunk they
+/// receive and wait when it runs dry, which keeps the long-run rate at the configured limit.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    // `None` while unlimited
+    limiter: RwLock<Option<(u64, Arc<DefaultDirectRateLimiter>)>>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let throttle = Self::default();
+        throttle.set_rate(bytes_per_sec);
+        throttle
+    }
+
+    /// Changes the rate, `0` lifts the limit. Waiting connections pick it up on their next chunk.
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        let Ok(mut limiter) = self.limiter.write() else {
+            return;
+        };
+        if let Some((rate, _)) = limiter.as_ref()
+            && bytes_per_sec > 0
+            && (*rate as f64 - bytes_per_sec as f64).abs() <= *rate as f64 * RATE_TOLERANCE
+        {
+            return;
+        }
+        if limiter.is_none() && bytes_per_sec == 0 {
+            return;
+        }
+        *limiter = NonZeroU32::new(bytes_per_sec.min(u32::MAX as u64) as u32).map(|rate| {
+            // A quarter second of traffic smooths over chunk sizes without letting bursts through
+            let burst = NonZeroU32::new((rate.get() / 4).max(MIN_BURST)).unwrap_or(rate);
+            let quota = Quota::per_second(rate).allow_burst(burst);
+            (bytes_per_sec, Arc::new(RateLimiter::direct(quota)))
+        });
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.limiter
+            .read()
+            .ok()
+            .and_then(|l| l.as_ref().map(|(rate, _)| *rate))
+            .unwrap_or(0)
+    }
+
+    /// Takes `bytes` tokens, waiting until the bucket holds enough.
+    pub async fn consume(&self, bytes: usize) {
+        let Some((_, limiter)) = self.limiter.read().ok().and_then(|l| l.clone()) else {
+            return;
+        };
+        let mut left = bytes as u64;
+        while left > 0 {
+            let n = left.min(MIN_BURST as u64) as u32;
+            left -= n as u64;
+            if let Some(n) = NonZeroU32::new(n) {
+                // Only fails when `n` exceeds the burst, which MIN_BURST rules out
+                let _ = limiter.until_n_ready(n).await;
+            }
+        }
+    }
+}
+
+/// What the allocator knows about one download competing for the global limit.
+#[derive(Debug, Clone, Copy)]
+pub struct Demand {
+    pub weight: u64,
+    // the most it can use right now; `None` when it would take whatever it is given
+    pub cap: Option<u64>,
+}
+
+/// Splits `budget` by weight. Downloads capped below their fair share get their cap and
+/// the rest is shared again among the others, until every byte is handed out.
+pub fn allocate(budget: u64, demands: &[Demand]) -> Vec<u64> {
+    let mut shares = vec![0; demands.len()];
+    let mut open = (0..demands.len()).filter(|i| demands[*i].weight > 0).collect::<Vec<_>>();
+    let mut budget = budget;
+
+    while !open.is_empty() {
+        let total_weight = open.iter().map(|i| demands[*i].weight).sum::<u64>();
+        let fair = |i: usize| (budget as u128 * demands[i].weight as u128 / total_weight as u128) as u64;
+        let (capped, uncapped): (Vec<usize>, Vec<usize>) = open
+            .iter()
+            .partition(|i| demands[**i].cap.is_some_and(|cap| cap <= fair(**i)));
+        if capped.is_empty() {
+            for i in uncapped {
+                shares[i] = fair(i);
+            }
+            break;
+        }
+        for i in capped {
+            let cap = demands[i].cap.unwrap_or_default();
+            shares[i] = cap;
+            budget = budget.saturating_sub(cap);
+        }
+        open = uncapped;
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn zero_rate_means_unlimited() {
+        assert_eq!(Throttle::new(0).rate(), 0);
+        let throttle = Throttle::new(1000);
+        assert_eq!(throttle.rate(), 1000);
+        throttle.set_rate(0);
+        assert_eq!(throttle.rate(), 0);
+    }
+
+    #[test]
+    fn small_rate_changes_keep_the_bucket() {
+        let throttle = Throttle::new(1_000_000);
+        throttle.set_rate(1_040_000);
+        assert_eq!(throttle.rate(), 1_000_000);
+        throttle.set_rate(1_100_000);
+        assert_eq!(throttle.rate(), 1_100_000);
+    }
+
+    #[tokio::test]
+    async fn unlimited_consume_does_not_wait() {
+        let started = Instant::now();
+        Throttle::default().consume(100 * 1024 * 1024).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn consume_holds_the_rate_past_the_burst() {
+        let throttle = Throttle::new(64 * 1024);
+        // the first MIN_BURST bytes are free, the next 48 KiB take 0.75 s at 64 KiB/s
+        throttle.consume(MIN_BURST as usize).await;
+        let started = Instant::now();
+        throttle.consume(48 * 1024).await;
+        assert!(started.elapsed() >= Duration::from_millis(600));
+    }
+
+    fn demand(weight: u64, cap: Option<u64>) -> Demand {
+        Demand { weight, cap }
+    }
+
+    #[test]
+    fn allocate_splits_by_weight() {
+        assert_eq!(allocate(900, &[demand(1, None), demand(2, None)]), vec![300, 600]);
+        assert_eq!(allocate(1000, &[demand(1, None); 4]), vec![250; 4]);
+        assert!(allocate(1000, &[]).is_empty());
+    }
+
+    #[test]
+    fn allocate_hands_unused_share_to_the_others() {
+        // the first one only needs 100 of its 500, the rest goes by weight to the other two
+        let shares = allocate(1500, &[demand(1, Some(100)), demand(1, None), demand(1, Some(2000))]);
+        assert_eq!(shares, vec![100, 700, 700]);
+        // freeing budget can push another download under its cap on the next round
+        // (fair shares of 400 leave 1100, the second's 550 is then above its cap of 450)
+        let shares = allocate(1200, &[demand(1, Some(100)), demand(1, Some(450)), demand(1, None)]);
+        assert_eq!(shares, vec![100, 450, 650]);
+    }
+
+    #[test]
+    fn allocate_stays_within_a_limit_smaller_than_the_caps() {
+        // per-download limits add up to 6000 but only 1000 is available
+        let demands = [demand(1, Some(2000)), demand(3, Some(4000))];
+        let shares = allocate(1000, &demands);
+        assert_eq!(shares, vec![250, 750]);
+        assert!(shares.iter().sum::<u64>() <= 1000);
+    }
+
+    #[test]
+    fn allocate_gives_nothing_to_zero_weight() {
+        assert_eq!(allocate(1000, &[demand(0, None), demand(1, None)]), vec![0, 1000]);
+        assert_eq!(allocate(1000, &[demand(0, None)]), vec![0]);
+    }
+}
@@ -2,16 +2,19 @@
 //! entry point of the Rust logic.
 mod signals;
 mod downloader;
+mod server;
 mod utils;
 
 use downloader::{
     start_download_manager, spawn_download_worker,
     query_url_info, get_download_details,
-    pause_download, resume_download, cancel_download
+    pause_download, resume_download, cancel_download, stop_recording,
+    set_download_speed_limit, set_download_weight,
+    move_download, set_download_priority, set_download_queue, set_download_dependencies,
 };
 use rinf::{dart_shutdown, write_interface};
-use tokio::spawn;
-use utils::ytdlp::handle_ytdl_query;
+use tokio::{spawn, sync::watch};
+use utils::{types::ServerSettings, ytdlp::handle_ytdl_query};
 
 
 
@@ -28,15 +31,36 @@ async fn main() {
     // If you must use blocking code, use `tokio::task::spawn_blocking`
     // or the equivalent provided by your async library.
 
-    let rclient = utils::url::build_browser_client().await;
-    let dm = start_download_manager(rclient.clone()).await;
-    spawn(utils::settings::update_settings(dm.clone()));
+    let rclient = match utils::url::build_browser_client().await {
+        Ok(client) => client,
+        Err(e) => {
+            utils::logger::error(&format!("Failed to build HTTP client: {:?}", e));
+            return;
+        }
+    };
+    let dm = match start_download_manager(rclient.clone()).await {
+        Ok(dm) => dm,
+        Err(e) => {
+            utils::logger::error(&format!("Failed to start download manager: {:?}", e));
+            return;
+        }
+    };
+    let (server_tx, server_rx) = watch::channel(ServerSettings::default());
+    spawn(utils::settings::update_settings(dm.clone(), server_tx));
+    spawn(server::run_server(dm.clone(), rclient.clone(), server_rx));
     spawn(query_url_info(rclient.clone()));
     spawn(spawn_download_worker(dm.clone()));
     spawn(get_download_details(dm.clone()));
     spawn(pause_download(dm.clone()));
     spawn(resume_download(dm.clone()));
     spawn(cancel_download(dm.clone()));
+    spawn(stop_recording(dm.clone()));
+    spawn(set_download_speed_limit(dm.clone()));
+    spawn(set_download_weight(dm.clone()));
+    spawn(move_download(dm.clone()));
+    spawn(set_download_priority(dm.clone()));
+    spawn(set_download_queue(dm.clone()));
+    spawn(set_download_dependencies(dm.clone()));
     spawn(handle_ytdl_query());
 
     // Keep the main function running until Dart shutdown.
@@ -0,0 +1,268 @@
+//! Local HTTP endpoint used by the browser extension to hand
+//! captured downloads over to the `DownloadManager`.
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use axum::{
+    Json, Router,
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode, header::HOST},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::downloader::main::DownloadManager;
+use crate::utils::{
+    logger,
+    types::{DownloadOptions, DownloadState, ServerSettings},
+    url::{header_map, probe_url},
+};
+
+/// Header the browser extension puts the shared capture token in.
+const TOKEN_HEADER: &str = "x-nadekodon-token";
+
+#[derive(Clone)]
+struct AppState {
+    manager: Arc<DownloadManager>,
+    client: Client,
+    settings: watch::Receiver<ServerSettings>,
+    // port the listener is bound to, the only one a valid `Host` may name
+    port: u16,
+}
+
+#[derive(Deserialize)]
+struct CaptureRequest {
+    url: String,
+    filename: Option<String>,
+    referer: Option<String>,
+    cookies: Option<String>,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Serialize)]
+struct CaptureResponse {
+    id: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PingResponse {
+    app: &'static str,
+    version: &'static str,
+}
+
+type ApiResult = (StatusCode, Json<CaptureResponse>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiResult {
+    (status, Json(CaptureResponse { id: None, error: Some(message.into()) }))
+}
+
+/// Serves the capture API on the configured port, rebinding whenever
+/// the port in `settings` changes. A `None` port keeps the server off.
+pub async fn run_server(
+    manager: Arc<DownloadManager>,
+    client: Client,
+    mut settings: watch::Receiver<ServerSettings>,
+) {
+    loop {
+        let port = settings.borrow_and_update().port;
+        if let Some(port) = port {
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+            match TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    logger::debug(&format!("Capture server listening on {}", addr));
+                    let state = AppState {
+                        manager: manager.clone(),
+                        client: client.clone(),
+                        settings: settings.clone(),
+                        port,
+                    };
+                    let shutdown = port_changed(settings.clone(), port);
+                    if let Err(e) = axum::serve(listener, router(state))
+                        .with_graceful_shutdown(shutdown)
+                        .await
+                    {
+                        logger::error(&format!("Capture server on {} stopped: {:?}", addr, e));
+                    }
+                    logger::debug(&format!("Capture server on {} closed", addr));
+                    continue;
+                }
+                Err(e) => {
+                    logger::error(&format!("Failed to bind capture server on {}: {:?}", addr, e));
+                }
+            }
+        }
+        if settings.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Resolves once the configured port differs from `port`.
+async fn port_changed(mut settings: watch::Receiver<ServerSettings>, port: u16) {
+    loop {
+        if settings.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        if settings.borrow_and_update().port != Some(port) {
+            return;
+        }
+    }
+}
+
+fn router(state: AppState) -> Router {
+    let capture = post(capture_download)
+        .layer(middleware::from_fn_with_state(state.clone(), require_token));
+    Router::new()
+        .route("/ping", get(ping))
+        .route("/download", capture)
+        .layer(middleware::from_fn_with_state(state.clone(), require_local_host))
+        .with_state(state)
+}
+
+/// Refuses requests addressed to any other host name, so a web page that rebinds
+/// its own domain to 127.0.0.1 cannot reach the API.
+async fn require_local_host(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let host = req.headers().get(HOST).and_then(|h| h.to_str().ok());
+    if !is_local_host(host, state.port) {
+        return api_error(StatusCode::FORBIDDEN, "Invalid Host header").into_response();
+    }
+    next.run(req).await
+}
+
+/// Refuses requests that do not carry the capture token shared with the browser extension.
+async fn require_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let expected = state.settings.borrow().token.clone();
+    match expected {
+        None => api_error(StatusCode::SERVICE_UNAVAILABLE, "Capture token is not configured").into_response(),
+        Some(expected) if token_matches(&expected, req.headers()) => next.run(req).await,
+        Some(_) => api_error(StatusCode::UNAUTHORIZED, "Missing or invalid capture token").into_response(),
+    }
+}
+
+fn is_local_host(host: Option<&str>, port: u16) -> bool {
+    let Some(host) = host else {
+        return false;
+    };
+    host == format!("127.0.0.1:{}", port) || host.eq_ignore_ascii_case(&format!("localhost:{}", port))
+}
+
+/// Compares without bailing out at the first differing byte, so response
+/// timing does not leak how much of a guess was right.
+fn token_matches(expected: &str, headers: &HeaderMap) -> bool {
+    let Some(given) = headers.get(TOKEN_HEADER).map(|v| v.as_bytes()) else {
+        return false;
+    };
+    let expected = expected.as_bytes();
+    given.len() == expected.len()
+        && given.iter().zip(expected).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn ping() -> Json<PingResponse> {
+    Json(PingResponse {
+        app: "nadekodon",
+        version: env!("CARGO_PKG_VERSION"),
+    })
+}
+
+async fn capture_download(
+    State(state): State<AppState>,
+    Json(req): Json<CaptureRequest>,
+) -> ApiResult {
+    let folder = match state.settings.borrow().download_folder.clone() {
+        Some(folder) => folder,
+        None => return api_error(StatusCode::SERVICE_UNAVAILABLE, "Download folder is not configured"),
+    };
+
+    let mut headers = req.headers;
+    if let Some(referer) = req.referer {
+        headers.push(("Referer".to_string(), referer));
+    }
+    if let Some(cookies) = req.cookies {
+        headers.push(("Cookie".to_string(), cookies));
+    }
+    let header_values = match header_map(&headers) {
+        Ok(map) => map,
+        Err(e) => return api_error(StatusCode::BAD_REQUEST, format!("Invalid header: {}", e)),
+    };
+
+    let name = match req.filename.as_deref().and_then(sanitize_filename) {
+        Some(name) => name,
+        // probe with the page's cookies, a signed link may refuse requests without them
+        None => match probe_url(&state.client, &req.url, &header_values).await {
+            Ok(info) => sanitize_filename(&info.name).unwrap_or_else(|| "download.bin".to_string()),
+            Err(e) => return api_error(StatusCode::BAD_GATEWAY, format!("Failed to query url: {}", e)),
+        },
+    };
+    let dest = unique_dest(&folder, &name);
+
+    let options = DownloadOptions { headers, ..Default::default() };
+    match state.manager.add_download(req.url.clone(), dest, options, DownloadState::Queued).await {
+        Ok(id) => {
+            logger::debug(&format!("Captured {} from browser as {}", req.url, id));
+            (StatusCode::OK, Json(CaptureResponse { id: Some(id.to_string()), error: None }))
+        }
+        Err(e) => api_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// Keeps only the final path component so a filename cannot escape the download folder.
+fn sanitize_filename(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Appends ` (n)` to the file stem until the path does not exist yet.
+fn unique_dest(folder: &Path, name: &str) -> PathBuf {
+    let candidate = folder.join(name);
+    if !candidate.exists() {
+        return candidate;
+    }
+    let path = Path::new(name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let ext = path.extension().and_then(|s| s.to_str());
+    (1..)
+        .map(|n| match ext {
+            Some(ext) => folder.join(format!("{} ({}).{}", stem, n, ext)),
+            None => folder.join(format!("{} ({})", stem, n)),
+        })
+        .find(|p| !p.exists())
+        .unwrap_or(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn only_loopback_hosts_on_our_port_are_accepted() {
+        assert!(is_local_host(Some("127.0.0.1:6412"), 6412));
+        assert!(is_local_host(Some("localhost:6412"), 6412));
+        assert!(!is_local_host(Some("localhost:80"), 6412));
+        assert!(!is_local_host(Some("evil.example:6412"), 6412));
+        assert!(!is_local_host(None, 6412));
+    }
+
+    #[test]
+    fn token_must_match_exactly() {
+        let mut headers = HeaderMap::new();
+        assert!(!token_matches("secret", &headers));
+        headers.insert(TOKEN_HEADER, HeaderValue::from_static("secre"));
+        assert!(!token_matches("secret", &headers));
+        headers.insert(TOKEN_HEADER, HeaderValue::from_static("secrex"));
+        assert!(!token_matches("secret", &headers));
+        headers.insert(TOKEN_HEADER, HeaderValue::from_static("secret"));
+        assert!(token_matches("secret", &headers));
+    }
+}
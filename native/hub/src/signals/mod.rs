@@ -6,11 +6,59 @@ use serde::{Deserialize, Serialize};
 #[derive(Deserialize, DartSignal)]
 pub struct UpdateSettings {
     pub server_port: Option<u16>,
+    // shared secret the browser extension sends to the capture server
+    pub server_token: Option<String>,
+    pub download_folder: Option<String>,
     pub speed_limit: Option<u64>,
     pub download_threads: Option<u8>,
     pub concurrency_limit: Option<u8>,
     pub download_timeout: Option<u64>,
     pub download_retries: Option<u8>,
+    pub schedule: Option<Vec<BandwidthSchedule>>,
+    pub queue_window: Option<QueueWindowSettings>,
+    pub queues: Option<Vec<QueueConfig>>,
+    // `Restart` or `Fail` when a resumed download finds its remote file changed
+    pub on_remote_change: Option<String>,
+}
+
+/// A named queue; `speed_limit` 0 means only the global limit applies, and the
+/// schedule overrides both limits like the global one does.
+#[derive(Deserialize, SignalPiece)]
+pub struct QueueConfig {
+    pub name: String,
+    pub concurrency_limit: u8,
+    pub speed_limit: u64,
+    pub schedule: Vec<BandwidthSchedule>,
+}
+
+/// Daily start/stop times for the whole queue, in minutes from local midnight.
+#[derive(Deserialize, SignalPiece)]
+pub struct QueueWindowSettings {
+    pub enabled: bool,
+    pub start_minute: u16,
+    pub stop_minute: u16,
+}
+
+/// One entry of the bandwidth schedule. `days` are 0 (Monday) to 6, empty for every
+/// day; `start_minute` and `end_minute` count from local midnight.
+#[derive(Deserialize, SignalPiece)]
+pub struct BandwidthSchedule {
+    pub name: String,
+    pub days: Vec<u8>,
+    pub start_minute: u16,
+    pub end_minute: u16,
+    pub speed_limit: u64,
+    pub concurrency_limit: Option<u8>,
+}
+
+/// Sent whenever the schedule switches profile or the queue window opens or closes;
+/// `profile` is None outside every window, when the limits from the settings apply.
+/// A `concurrency_limit` of 0 means the queue window is closed.
+#[derive(Serialize, RustSignal)]
+pub struct ScheduleStatus {
+    pub profile: Option<String>,
+    pub speed_limit: u64,
+    pub concurrency_limit: u8,
 }
 
 #[derive(Deserialize, DartSignal)]
@@ -32,9 +80,30 @@ pub struct UrlQueryOutput {
     pub accept_ranges: bool,
     pub content_type: Option<String>,
     pub is_webpage: bool,
+    pub variants: Vec<StreamVariant>,
+    pub renditions: Vec<StreamRendition>,
     pub error: bool,
 }
 
+#[derive(Serialize, SignalPiece)]
+pub struct StreamVariant {
+    pub id: String,
+    pub bandwidth: u64,
+    pub resolution: Option<String>,
+    pub codecs: Option<String>,
+}
+
+/// An alternate audio or subtitle track of an HLS stream.
+#[derive(Serialize, SignalPiece)]
+pub struct StreamRendition {
+    pub id: String,
+    pub kind: String,
+    pub group_id: String,
+    pub name: String,
+    pub language: Option<String>,
+    pub is_default: bool,
+}
+
 #[derive(Serialize, RustSignal)]
 pub struct YtdlQueryOutput {
     pub name: String,
@@ -55,6 +124,12 @@ pub struct YtdlFormat {
     pub note: String,
 }
 
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub struct Checksum {
+    pub algorithm: String,
+    pub digest: String,
+}
+
 #[derive(Deserialize, DartSignal)]
 pub struct DoDownload {
     pub url: Option<String>,
@@ -62,6 +137,25 @@ pub struct DoDownload {
     pub video_format: Option<YtdlFormat>,
     pub audio_format: Option<YtdlFormat>,
     pub is_ytdl: bool,
+    pub checksum: Option<Checksum>,
+    pub stream_variant: Option<String>,
+    pub stream_audio: Option<String>,
+    pub stream_subtitles: Option<String>,
+    pub live_max_duration: Option<u64>,
+    pub live_max_size: Option<u64>,
+    pub speed_limit: Option<u64>,
+    pub bypass_global_limit: Option<bool>,
+    pub weight: Option<String>,
+    // unix time to start at; the download waits as Scheduled until then
+    pub start_at: Option<i64>,
+    // add the download paused, to be started by hand
+    pub start_paused: Option<bool>,
+    pub priority: Option<i32>,
+    pub queue: Option<String>,
+    // ids of downloads that have to complete first
+    pub depends_on: Option<Vec<String>>,
+    // `Fail` (default) or `Skip` when one of them fails
+    pub on_dependency_failure: Option<String>,
 }
 
 #[derive(Deserialize, DartSignal)]
@@ -79,6 +173,13 @@ pub struct DownloadGlance {
     pub downloaded: u64,
     pub speed: f64,
     pub state: String,
+    pub parts_done: Option<u64>,
+    pub parts_total: Option<u64>,
+    pub priority: i32,
+    pub queue: String,
+    // `Network`, `Http`, `Disk`, `Protocol`, `Verification`, `Dependency` or `Other`
+    // when the download failed
+    pub error_kind: Option<String>,
 }
 
 #[derive(Deserialize, DartSignal)]
@@ -96,6 +197,64 @@ pub struct DownloadDetails {
     pub downloaded: u64,
     pub speed: f64,
     pub state: String,
+    pub parts_done: Option<u64>,
+    pub parts_total: Option<u64>,
+    pub speed_limit: Option<u64>,
+    pub bypass_global_limit: bool,
+    pub weight: String,
+    pub start_at: Option<i64>,
+    pub priority: i32,
+    pub queue: String,
+    pub depends_on: Vec<String>,
+    pub on_dependency_failure: String,
+    // what kind of failure the download stopped with, as in `DownloadGlance`
+    pub error_kind: Option<String>,
+    // timestamped lines about what happened to the download, oldest first
+    pub log: Vec<String>,
+}
+
+/// Changes the limit of one download; `None` or `0` removes it.
+#[derive(Deserialize, DartSignal)]
+pub struct SetDownloadSpeedLimit {
+    pub id: String,
+    pub speed_limit: Option<u64>,
+    pub bypass_global_limit: bool,
+}
+
+/// Moves a download in the queue: `direction` is `Top`, `Up`, `Down` or `Bottom`.
+#[derive(Deserialize, DartSignal)]
+pub struct MoveDownload {
+    pub id: String,
+    pub direction: String,
+}
+
+/// Replaces the downloads a download waits for. Rejected when it would make a cycle.
+#[derive(Deserialize, DartSignal)]
+pub struct SetDownloadDependencies {
+    pub id: String,
+    pub depends_on: Vec<String>,
+    pub on_dependency_failure: Option<String>,
+}
+
+/// Moves a download to another named queue, `default` for the default one.
+#[derive(Deserialize, DartSignal)]
+pub struct SetDownloadQueue {
+    pub id: String,
+    pub queue: String,
+}
+
+/// Queued downloads with a higher priority start first.
+#[derive(Deserialize, DartSignal)]
+pub struct SetDownloadPriority {
+    pub id: String,
+    pub priority: i32,
+}
+
+/// Sets the bandwidth weight of one download: `High`, `Normal` or `Low`.
+#[derive(Deserialize, DartSignal)]
+pub struct SetDownloadWeight {
+    pub id: String,
+    pub weight: String,
 }
 
 #[derive(Deserialize, DartSignal)]
@@ -113,6 +272,12 @@ pub struct CancelDownload {
     pub id: String
 }
 
+/// Ends a live recording and keeps what was recorded so far.
+#[derive(Deserialize, DartSignal)]
+pub struct StopRecording {
+    pub id: String
+}
+
 #[derive(Serialize, RustSignal)]
 pub struct LogSignal {
     pub level: String,
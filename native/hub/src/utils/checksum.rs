@@ -0,0 +1,225 @@
+use std::{fmt, io::Read, path::Path, str::FromStr};
+use anyhow::Result;
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha512};
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "md5" => Ok(Self::Md5),
+            "sha1" => Ok(Self::Sha1),
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            other => Err(anyhow::anyhow!("Unsupported checksum algorithm {}", other)),
+        }
+    }
+}
+
+impl ChecksumAlgorithm {
+    /// Length of a digest in hex digits.
+    fn hex_len(self) -> usize {
+        match self {
+            Self::Md5 => 32,
+            Self::Sha1 => 40,
+            Self::Sha256 => 64,
+            Self::Sha512 => 128,
+        }
+    }
+
+    /// The algorithm whose digests are `len` hex digits long.
+    fn from_hex_len(len: usize) -> Result<Self> {
+        [Self::Md5, Self::Sha1, Self::Sha256, Self::Sha512]
+            .into_iter()
+            .find(|a| a.hex_len() == len)
+            .ok_or_else(|| anyhow::anyhow!("No checksum algorithm has {}-digit digests", len))
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Md5 => "MD5",
+            Self::Sha1 => "SHA-1",
+            Self::Sha256 => "SHA-256",
+            Self::Sha512 => "SHA-512",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Digest the finished file is expected to have, as lowercase hex.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpectedChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: String,
+}
+
+impl ExpectedChecksum {
+    /// Checks `digest` against `algorithm`. Without an algorithm it is taken from an
+    /// `sha256:` style prefix of the digest, or else from the digest's length.
+    pub fn new(algorithm: &str, digest: &str) -> Result<Self> {
+        let (prefix, digest) = match digest.trim().split_once(':') {
+            Some((prefix, digest)) => (Some(prefix.parse::<ChecksumAlgorithm>()?), digest),
+            None => (None, digest),
+        };
+        let digest = digest.trim().to_ascii_lowercase();
+        if digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow::anyhow!("Checksum {:?} is not a hex digest", digest));
+        }
+        let algorithm = match (algorithm.trim(), prefix) {
+            ("", Some(prefix)) => prefix,
+            ("", None) => ChecksumAlgorithm::from_hex_len(digest.len())?,
+            (name, prefix) => {
+                let algorithm = name.parse()?;
+                if prefix.is_some_and(|p| p != algorithm) {
+                    return Err(anyhow::anyhow!("Checksum {:?} is not a {} digest", digest, algorithm));
+                }
+                algorithm
+            }
+        };
+        if digest.len() != algorithm.hex_len() {
+            return Err(anyhow::anyhow!(
+                "{} digest has {} hex digits instead of {}",
+                algorithm, digest.len(), algorithm.hex_len()
+            ));
+        }
+        Ok(Self { algorithm, digest })
+    }
+
+    pub fn matches(&self, actual: &str) -> bool {
+        self.digest.eq_ignore_ascii_case(actual)
+    }
+}
+
+/// Incremental hasher over any of the supported algorithms.
+#[derive(Debug, Clone)]
+pub enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => Self::Md5(Md5::new()),
+            ChecksumAlgorithm::Sha1 => Self::Sha1(Sha1::new()),
+            ChecksumAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Sha512 => Self::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(h) => h.update(data),
+            Self::Sha1(h) => h.update(data),
+            Self::Sha256(h) => h.update(data),
+            Self::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        match self {
+            Self::Md5(h) => format!("{:x}", h.finalize()),
+            Self::Sha1(h) => format!("{:x}", h.finalize()),
+            Self::Sha256(h) => format!("{:x}", h.finalize()),
+            Self::Sha512(h) => format!("{:x}", h.finalize()),
+        }
+    }
+}
+
+/// Hashes a whole file on the blocking pool.
+pub async fn hash_file(path: &Path, algorithm: ChecksumAlgorithm) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let mut hasher = Hasher::new(algorithm);
+        let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize_hex())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MD5_ABC: &str = "900150983cd24fb0d6963f7d28e17f72";
+    const SHA1_ABC: &str = "a9993e364706816aba3e25717850c26c9cd0d89d";
+    const SHA256_ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn digest_of(algorithm: ChecksumAlgorithm, chunks: &[&[u8]]) -> String {
+        let mut hasher = Hasher::new(algorithm);
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+        hasher.finalize_hex()
+    }
+
+    #[test]
+    fn hashes_known_answers() {
+        for (algorithm, expected) in [
+            (ChecksumAlgorithm::Md5, MD5_ABC),
+            (ChecksumAlgorithm::Sha1, SHA1_ABC),
+            (ChecksumAlgorithm::Sha256, SHA256_ABC),
+        ] {
+            assert_eq!(digest_of(algorithm, &[b"abc"]), expected);
+            assert_eq!(digest_of(algorithm, &[b"a", b"", b"bc"]), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn hashes_a_file() {
+        let path = std::env::temp_dir().join(format!("checksum_{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, b"abc").unwrap();
+        let digest = hash_file(&path, ChecksumAlgorithm::Sha256).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(digest.unwrap(), SHA256_ABC);
+
+        assert!(hash_file(&path, ChecksumAlgorithm::Md5).await.is_err());
+    }
+
+    #[test]
+    fn detects_the_algorithm() {
+        assert_eq!(ExpectedChecksum::new("", MD5_ABC).unwrap().algorithm, ChecksumAlgorithm::Md5);
+        assert_eq!(ExpectedChecksum::new("", SHA1_ABC).unwrap().algorithm, ChecksumAlgorithm::Sha1);
+        let prefixed = ExpectedChecksum::new("", &format!("SHA-256:{}", SHA256_ABC.to_uppercase())).unwrap();
+        assert_eq!(prefixed.algorithm, ChecksumAlgorithm::Sha256);
+        assert_eq!(prefixed.digest, SHA256_ABC);
+        assert!(prefixed.matches(&SHA256_ABC.to_uppercase()));
+        assert_eq!(ExpectedChecksum::new("sha1", SHA1_ABC).unwrap().algorithm, ChecksumAlgorithm::Sha1);
+    }
+
+    #[test]
+    fn rejects_malformed_digests() {
+        assert!(ExpectedChecksum::new("md5", "").is_err());
+        assert!(ExpectedChecksum::new("md5", &MD5_ABC.replace('0', "g")).is_err());
+        assert!(ExpectedChecksum::new("md5", &MD5_ABC[1..]).is_err());
+        assert!(ExpectedChecksum::new("sha256", MD5_ABC).is_err());
+        assert!(ExpectedChecksum::new("", "abcde").is_err());
+        assert!(ExpectedChecksum::new("sha1", &format!("md5:{}", MD5_ABC)).is_err());
+        assert!(ExpectedChecksum::new("crc32", "cafebabe").is_err());
+    }
+}
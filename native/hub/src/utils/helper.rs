@@ -1,19 +1,36 @@
+use std::path::PathBuf;
 use time::{OffsetDateTime};
 
+const APP_DIR_NAME: &str = "nadekodon";
 
 #[inline]
 pub fn now_unix() -> i64 {
     OffsetDateTime::now_utc().unix_timestamp()
 }
 
+/// Per-user directory for data owned by the Rust side (e.g. the download database).
+pub fn app_data_dir() -> PathBuf {
+    let env_dir = |key: &str| std::env::var_os(key).filter(|v| !v.is_empty()).map(PathBuf::from);
+
+    let base = if cfg!(target_os = "windows") {
+        env_dir("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_dir("HOME").map(|h| h.join("Library").join("Application Support"))
+    } else {
+        env_dir("XDG_DATA_HOME").or_else(|| env_dir("HOME").map(|h| h.join(".local").join("share")))
+    };
+
+    base.unwrap_or_else(std::env::temp_dir).join(APP_DIR_NAME)
+}
+
 pub fn calc_speed(hist: Vec<(u128, u64)>) -> f64 {
+    let (Some((old_time, old_bytes)), Some((new_time, new_bytes))) = (hist.first(), hist.last()) else {
+        return 0.0;
+    };
     if hist.len() < 2 {
         return 0.0;
     }
 
-    let (old_time, old_bytes) = hist.first().unwrap();
-    let (new_time, new_bytes) = hist.last().unwrap();
-
     // elapsed time in seconds (timestamps are in milliseconds)
     let elapsed_secs = (*new_time - *old_time) as f64 / 1000.0;
 
@@ -1,4 +1,4 @@
-use rinf::{DartSignal, RustSignal, SignalPiece};
+use rinf::RustSignal;
 use crate::signals::LogSignal;
 
 fn _log(level: &str, message: &str) {
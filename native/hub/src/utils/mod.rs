@@ -1,3 +1,4 @@
+pub mod checksum;
 pub mod helper;
 pub mod logger;
 pub mod settings;
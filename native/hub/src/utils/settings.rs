@@ -1,15 +1,50 @@
-use std::{fs, path::PathBuf, sync::Arc};
-use rinf::{DartSignal, RustSignal, SignalPiece, debug_print};
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+use rinf::DartSignal;
+use tokio::sync::watch;
 
-use crate::signals::UpdateSettings;
+use crate::signals::{BandwidthSchedule, QueueConfig, UpdateSettings};
 use crate::utils::types::{
-    DMSettings, ServerSettings,
+    DMSettings, QueueSettings, QueueWindow, RemoteChangePolicy, ScheduleRule, ServerSettings,
+    DEFAULT_QUEUE,
 };
 use crate::downloader::main::DownloadManager;
 
 use crate::utils::logger;
 
-pub async fn update_settings(dm: Arc<DownloadManager>) {
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+fn schedule_rule(entry: &BandwidthSchedule) -> Option<ScheduleRule> {
+    if entry.start_minute >= MINUTES_PER_DAY
+        || entry.end_minute >= MINUTES_PER_DAY
+        || entry.days.iter().any(|d| *d > 6)
+    {
+        logger::error(&format!("Ignoring invalid schedule entry {}", entry.name));
+        return None;
+    }
+    Some(ScheduleRule {
+        name: entry.name.clone(),
+        days: entry.days.clone(),
+        start: entry.start_minute,
+        end: entry.end_minute,
+        speed_limit: entry.speed_limit,
+        concurrency_limit: entry.concurrency_limit.filter(|l| *l > 0),
+    })
+}
+
+fn queue_settings(queue: &QueueConfig) -> Option<QueueSettings> {
+    if queue.name.is_empty() || queue.name == DEFAULT_QUEUE {
+        logger::error(&format!("Ignoring queue with reserved name {:?}", queue.name));
+        return None;
+    }
+    Some(QueueSettings {
+        name: queue.name.clone(),
+        concurrency_limit: queue.concurrency_limit,
+        speed_limit: queue.speed_limit,
+        schedule: queue.schedule.iter().filter_map(schedule_rule).collect(),
+    })
+}
+
+pub async fn update_settings(dm: Arc<DownloadManager>, server: watch::Sender<ServerSettings>) {
     let receiver = UpdateSettings::get_dart_signal_receiver();
 
     while let Some(signal_pack) = receiver.recv().await {
@@ -23,18 +58,52 @@ pub async fn update_settings(dm: Arc<DownloadManager>) {
             download_threads: data_clone.download_threads.unwrap_or(dm_old.download_threads),
             download_timeout: data_clone.download_timeout.unwrap_or(dm_old.download_timeout),
             download_retries: data_clone.download_retries.unwrap_or(dm_old.download_retries),
+            schedule: match &data_clone.schedule {
+                Some(entries) => entries.iter().filter_map(schedule_rule).collect(),
+                None => dm_old.schedule.clone(),
+            },
+            queue_window: match &data_clone.queue_window {
+                Some(w) if !w.enabled => None,
+                Some(w) if w.start_minute >= MINUTES_PER_DAY || w.stop_minute >= MINUTES_PER_DAY => {
+                    logger::error("Ignoring invalid queue window");
+                    dm_old.queue_window
+                }
+                Some(w) => Some(QueueWindow { start: w.start_minute, stop: w.stop_minute }),
+                None => dm_old.queue_window,
+            },
+            queues: match &data_clone.queues {
+                Some(queues) => queues.iter().filter_map(queue_settings).collect(),
+                None => dm_old.queues.clone(),
+            },
+            on_remote_change: match data_clone.on_remote_change.as_deref().map(RemoteChangePolicy::from_str) {
+                Some(Ok(policy)) => policy,
+                Some(Err(e)) => {
+                    logger::error(&format!("Ignoring remote change policy: {:?}", e));
+                    dm_old.on_remote_change
+                }
+                None => dm_old.on_remote_change,
+            },
         };
         drop(dm_old);
 
         logger::debug(&format!("Updated dm settings to {:?}", &dm_new));
         let _ = dm.update_settings(dm_new).await;
-        match data_clone.server_port {
-            Some(p) => {
-                let server_settings = ServerSettings {
-                    port: data_clone.server_port,
-                };
-            }
-            None => {}
+        if data_clone.server_port.is_some() || data_clone.server_token.is_some() || data_clone.download_folder.is_some() {
+            server.send_modify(|server_settings| {
+                if let Some(port) = data_clone.server_port {
+                    server_settings.port = Some(port);
+                }
+                if let Some(token) = &data_clone.server_token {
+                    server_settings.token = (!token.is_empty()).then(|| token.clone());
+                }
+                if let Some(folder) = &data_clone.download_folder {
+                    server_settings.download_folder = (!folder.is_empty()).then(|| PathBuf::from(folder));
+                }
+                logger::debug(&format!(
+                    "Updated server settings to port {:?}, folder {:?}",
+                    server_settings.port, server_settings.download_folder
+                ));
+            });
         }
     }
 }
\ No newline at end of file
@@ -1,18 +1,9 @@
-use serde::Deserialize;
-use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
-    sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc,
-    },
-};
-use tokio::{
-    sync::{mpsc, Mutex, Notify, Semaphore},
-    task::JoinHandle,
-};
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::PathBuf, str::FromStr};
 use uuid::Uuid;
 
+use crate::utils::checksum::ExpectedChecksum;
+
 #[derive(Debug, Clone)]
 pub struct DMSettings {
     pub speed_limit: u64,
@@ -20,11 +11,82 @@ pub struct DMSettings {
     pub concurrency_limit: u8,
     pub download_timeout: u64,
     pub download_retries: u8,
+    // time windows that override the limits above while they are active
+    pub schedule: Vec<ScheduleRule>,
+    // hours during which queued downloads may run at all
+    pub queue_window: Option<QueueWindow>,
+    // named queues next to the default one, which uses the limits above
+    pub queues: Vec<QueueSettings>,
+    pub on_remote_change: RemoteChangePolicy,
 }
 
-#[derive(Debug, Clone)]
+/// Name of the queue downloads go to unless told otherwise.
+pub const DEFAULT_QUEUE: &str = "default";
+
+/// A named queue with its own concurrency limit and, optionally, its own speed limit
+/// (0 for none besides the global one) and schedule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueSettings {
+    pub name: String,
+    pub concurrency_limit: u8,
+    pub speed_limit: u64,
+    pub schedule: Vec<ScheduleRule>,
+}
+
+/// Daily window for the whole queue: downloads start at `start` and are sent back to the
+/// queue at `stop`, both in minutes from local midnight. A `stop` before `start` runs past
+/// midnight; equal times keep the queue open all day.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueWindow {
+    pub start: u16,
+    pub stop: u16,
+}
+
+impl QueueWindow {
+    pub fn is_open(&self, minute: u16) -> bool {
+        match self.start.cmp(&self.stop) {
+            std::cmp::Ordering::Equal => true,
+            std::cmp::Ordering::Less => (self.start..self.stop).contains(&minute),
+            std::cmp::Ordering::Greater => minute >= self.start || minute < self.stop,
+        }
+    }
+}
+
+/// A weekly time window with its own speed limit and, optionally, its own concurrency
+/// limit. Minutes count from midnight local time; a window whose end comes before its
+/// start runs past midnight into the next day, and one whose end equals its start lasts
+/// the whole day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleRule {
+    pub name: String,
+    // days the window starts on, 0 = Monday; empty means every day
+    pub days: Vec<u8>,
+    pub start: u16,
+    pub end: u16,
+    pub speed_limit: u64,
+    pub concurrency_limit: Option<u8>,
+}
+
+impl ScheduleRule {
+    pub fn is_active(&self, weekday: u8, minute: u16) -> bool {
+        let starts_on = |day: u8| self.days.is_empty() || self.days.contains(&day);
+        if self.start == self.end {
+            starts_on(weekday)
+        } else if self.start < self.end {
+            starts_on(weekday) && (self.start..self.end).contains(&minute)
+        } else {
+            (starts_on(weekday) && minute >= self.start)
+                || (starts_on((weekday + 6) % 7) && minute < self.end)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ServerSettings {
     pub port: Option<u16>,
+    pub download_folder: Option<PathBuf>,
+    // secret the browser extension must send with every captured download
+    pub token: Option<String>,
 }
 
 #[derive(Debug)]
@@ -32,16 +94,306 @@ pub struct HeadData {
     pub total_size: Option<u64>,
     pub accept_ranges: bool,
     pub content_type: Option<String>,
+    pub validator: RemoteValidator,
+}
+
+/// `ETag` and `Last-Modified` of a remote file, used to tell whether it is still the
+/// same file when a download continues.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RemoteValidator {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl RemoteValidator {
+    /// True when both sides carry the same kind of validator and it differs. The ETag
+    /// is preferred as Last-Modified only has a resolution of a second.
+    pub fn differs_from(&self, other: &RemoteValidator) -> bool {
+        match (&self.etag, &other.etag) {
+            (Some(a), Some(b)) => a != b,
+            _ => match (&self.last_modified, &other.last_modified) {
+                (Some(a), Some(b)) => a != b,
+                _ => false,
+            },
+        }
+    }
+
+    /// Value for `If-Range`: a strong ETag, or else the Last-Modified date.
+    pub fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
+
+/// What to do with a download whose remote file changed since it started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RemoteChangePolicy {
+    // throw away what was downloaded and start over
+    #[default]
+    Restart,
+    // stop with an error
+    Fail,
+}
+
+impl FromStr for RemoteChangePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "restart" => Ok(Self::Restart),
+            "fail" => Ok(Self::Fail),
+            other => Err(anyhow::anyhow!("Unknown remote change policy {}", other)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum DownloadState {
     Queued,
+    // waits until the unix time given, then joins the queue
+    Scheduled(i64),
+    // queued, but waiting for the downloads it depends on to complete
+    Blocked,
     Running,
+    // running, and following a live stream until it ends or is stopped
+    Recording,
     Paused,
     Completed,
     Cancelled,
-    Error(String),
+    Error(ErrorKind, String),
+    // finished, but the file does not match the expected digest
+    ChecksumMismatch(String),
+}
+
+impl DownloadState {
+    /// Kind of failure a failed download stopped with.
+    pub fn error_kind(&self) -> Option<ErrorKind> {
+        match self {
+            Self::Error(kind, _) => Some(*kind),
+            Self::ChecksumMismatch(_) => Some(ErrorKind::Verification),
+            _ => None,
+        }
+    }
+}
+
+/// Broad cause of a failed download, stored and sent to Dart with its message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorKind {
+    // connection failures, timeouts and transfers cut short
+    Network,
+    // the server answered with an error status
+    Http,
+    // reading or writing the local file
+    Disk,
+    // the server sent something other than what was asked for
+    Protocol,
+    // the finished file has the wrong size or digest
+    Verification,
+    // a download it depends on did not complete
+    Dependency,
+    #[default]
+    Other,
+}
+
+impl FromStr for ErrorKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "network" => Ok(Self::Network),
+            "http" => Ok(Self::Http),
+            "disk" => Ok(Self::Disk),
+            "protocol" => Ok(Self::Protocol),
+            "verification" => Ok(Self::Verification),
+            "dependency" => Ok(Self::Dependency),
+            "other" => Ok(Self::Other),
+            other => Err(anyhow::anyhow!("Unknown error kind {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Network => "Network",
+            Self::Http => "Http",
+            Self::Disk => "Disk",
+            Self::Protocol => "Protocol",
+            Self::Verification => "Verification",
+            Self::Dependency => "Dependency",
+            Self::Other => "Other",
+        })
+    }
+}
+
+/// Inclusive byte range `start..=end` handled by one connection,
+/// with `written` bytes of it already on disk.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentInfo {
+    pub start: u64,
+    pub end: u64,
+    pub written: u64,
+}
+
+impl SegmentInfo {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self { start, end, written: 0 }
+    }
+
+    /// Absolute offset of the next byte to fetch.
+    pub fn position(&self) -> u64 {
+        self.start + self.written
+    }
+
+    pub fn remaining(&self) -> u64 {
+        // an open-ended range (`end == u64::MAX`) never runs out
+        self.end.saturating_add(1).saturating_sub(self.position())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+
+/// Per-download options given when the download is added.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadOptions {
+    // extra request headers (referer, cookies, ...) sent with every request
+    pub headers: Vec<(String, String)>,
+    pub checksum: Option<ExpectedChecksum>,
+    // URI of the HLS variant (or id of the DASH video representation) to download,
+    // the best one when unset
+    pub stream_variant: Option<String>,
+    // URIs of the alternate audio and subtitle renditions (DASH: audio representation id);
+    // audio falls back to the default one, subtitles are left out when unset
+    pub stream_audio: Option<String>,
+    pub stream_subtitles: Option<String>,
+    // live recordings stop after this many seconds of media...
+    pub live_max_duration: Option<u64>,
+    // ...or this many bytes, whichever comes first
+    pub live_max_size: Option<u64>,
+    // bytes per second for this download alone, unlimited when unset
+    pub speed_limit: Option<u64>,
+    // ignore the global limit, only `speed_limit` applies
+    pub bypass_global_limit: bool,
+    pub weight: BandwidthWeight,
+    // queued downloads with a higher priority start first; equal ones go in queue order
+    pub priority: i32,
+    // named queue the download runs in, the default one when unset
+    pub queue: Option<String>,
+    // downloads that have to complete before this one may start...
+    pub depends_on: Vec<Uuid>,
+    // ...and what happens to it when one of them fails or is cancelled
+    pub on_dependency_failure: DependencyPolicy,
+    // video then audio file muxed into `dest` with ffmpeg instead of downloading the url,
+    // the last step of a yt-dlp download; the inputs are removed afterwards
+    pub merge_inputs: Vec<PathBuf>,
+}
+
+/// What a download does when a download it depends on fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DependencyPolicy {
+    // fail as well
+    #[default]
+    Fail,
+    // give up quietly, ending up cancelled
+    Skip,
+}
+
+impl FromStr for DependencyPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            other => Err(anyhow::anyhow!("Unknown dependency policy {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for DependencyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fail => "Fail",
+            Self::Skip => "Skip",
+        })
+    }
+}
+
+impl DownloadOptions {
+    pub fn queue_name(&self) -> &str {
+        self.queue.as_deref().unwrap_or(DEFAULT_QUEUE)
+    }
+}
+
+/// Where to move a download within the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueMove {
+    Top,
+    Up,
+    Down,
+    Bottom,
+}
+
+impl FromStr for QueueMove {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "top" => Ok(Self::Top),
+            "up" => Ok(Self::Up),
+            "down" => Ok(Self::Down),
+            "bottom" => Ok(Self::Bottom),
+            other => Err(anyhow::anyhow!("Unknown queue move {}", other)),
+        }
+    }
+}
+
+/// How large a share of the global limit a download gets next to the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BandwidthWeight {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl BandwidthWeight {
+    pub fn factor(&self) -> u64 {
+        match self {
+            Self::High => 4,
+            Self::Normal => 2,
+            Self::Low => 1,
+        }
+    }
+}
+
+impl FromStr for BandwidthWeight {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "high" => Ok(Self::High),
+            "normal" => Ok(Self::Normal),
+            "low" => Ok(Self::Low),
+            other => Err(anyhow::anyhow!("Unknown bandwidth weight {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for BandwidthWeight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::High => "High",
+            Self::Normal => "Normal",
+            Self::Low => "Low",
+        };
+        f.write_str(name)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -54,6 +406,31 @@ pub struct DownloadInfo {
     pub state: DownloadState,
     // history is a list of (timestamp_millis, downloaded_bytes) samples
     pub history: Vec<(u128, u64)>,
+    // ranged segments of the current transfer, used to resume after a restart
+    pub segments: Vec<SegmentInfo>,
+    // (done, total) playlist segments of a stream download
+    pub parts: Option<(u64, u64)>,
+    pub options: DownloadOptions,
+    // the remote file as it was when the transfer started
+    pub validator: RemoteValidator,
+}
+
+impl DownloadInfo {
+    pub fn new(id: Uuid, url: String, dest: PathBuf, options: DownloadOptions) -> Self {
+        Self {
+            id,
+            url,
+            dest,
+            total_size: None,
+            downloaded: 0,
+            state: DownloadState::Queued,
+            history: Vec::new(),
+            segments: Vec::new(),
+            parts: None,
+            options,
+            validator: RemoteValidator::default(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -61,4 +438,121 @@ pub enum WorkerEvent {
     Completed(Uuid),
     Error(Uuid, String),
     Cancelled(Uuid),
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONDAY: u8 = 0;
+    const FRIDAY: u8 = 4;
+    const SATURDAY: u8 = 5;
+    const SUNDAY: u8 = 6;
+
+    fn rule(days: &[u8], start: u16, end: u16) -> ScheduleRule {
+        ScheduleRule {
+            name: "test".to_string(),
+            days: days.to_vec(),
+            start,
+            end,
+            speed_limit: 0,
+            concurrency_limit: None,
+        }
+    }
+
+    #[test]
+    fn schedule_rule_within_one_day() {
+        let office = rule(&[MONDAY, FRIDAY], 9 * 60, 17 * 60);
+        assert!(office.is_active(MONDAY, 9 * 60));
+        assert!(office.is_active(FRIDAY, 16 * 60 + 59));
+        assert!(!office.is_active(FRIDAY, 17 * 60));
+        assert!(!office.is_active(MONDAY, 8 * 60));
+        assert!(!office.is_active(SATURDAY, 12 * 60));
+        assert!(rule(&[], 0, 60).is_active(SUNDAY, 30));
+    }
+
+    #[test]
+    fn schedule_rule_wrapping_past_midnight_belongs_to_its_start_day() {
+        let night = rule(&[FRIDAY], 22 * 60, 2 * 60);
+        assert!(night.is_active(FRIDAY, 23 * 60));
+        assert!(night.is_active(SATURDAY, 60));
+        assert!(!night.is_active(SATURDAY, 2 * 60));
+        assert!(!night.is_active(SATURDAY, 23 * 60));
+        assert!(!night.is_active(FRIDAY, 60));
+
+        // Sunday night carries over into Monday morning
+        let sunday = rule(&[SUNDAY], 23 * 60, 30);
+        assert!(sunday.is_active(MONDAY, 10));
+        assert!(!sunday.is_active(SUNDAY, 10));
+    }
+
+    #[test]
+    fn schedule_rule_with_equal_times_lasts_all_day() {
+        let saturday = rule(&[SATURDAY], 0, 0);
+        assert!(saturday.is_active(SATURDAY, 0));
+        assert!(saturday.is_active(SATURDAY, 24 * 60 - 1));
+        assert!(!saturday.is_active(SUNDAY, 0));
+    }
+
+    #[test]
+    fn queue_window_within_one_day() {
+        let window = QueueWindow { start: 8 * 60, stop: 18 * 60 };
+        assert!(window.is_open(8 * 60));
+        assert!(window.is_open(17 * 60 + 59));
+        assert!(!window.is_open(18 * 60));
+        assert!(!window.is_open(0));
+    }
+
+    #[test]
+    fn queue_window_wrapping_past_midnight() {
+        let night = QueueWindow { start: 23 * 60, stop: 6 * 60 };
+        assert!(night.is_open(23 * 60));
+        assert!(night.is_open(0));
+        assert!(night.is_open(6 * 60 - 1));
+        assert!(!night.is_open(6 * 60));
+        assert!(!night.is_open(12 * 60));
+    }
+
+    #[test]
+    fn queue_window_with_equal_times_is_always_open() {
+        let window = QueueWindow { start: 300, stop: 300 };
+        assert!((0..24 * 60).all(|m| window.is_open(m)));
+    }
+
+    fn validator(etag: Option<&str>, last_modified: Option<&str>) -> RemoteValidator {
+        RemoteValidator {
+            etag: etag.map(str::to_string),
+            last_modified: last_modified.map(str::to_string),
+        }
+    }
+
+    const MONDAY_NOON: &str = "Mon, 06 Oct 2025 12:00:00 GMT";
+    const MONDAY_EVENING: &str = "Mon, 06 Oct 2025 18:00:00 GMT";
+
+    #[test]
+    fn validator_prefers_the_etag() {
+        let old = validator(Some("\"a\""), Some(MONDAY_NOON));
+        assert!(!old.differs_from(&validator(Some("\"a\""), Some(MONDAY_EVENING))));
+        assert!(old.differs_from(&validator(Some("\"b\""), Some(MONDAY_NOON))));
+    }
+
+    #[test]
+    fn validator_falls_back_to_last_modified() {
+        let old = validator(Some("W/\"a\""), Some(MONDAY_NOON));
+        assert!(old.differs_from(&validator(None, Some(MONDAY_EVENING))));
+        assert!(!old.differs_from(&validator(None, Some(MONDAY_NOON))));
+    }
+
+    #[test]
+    fn validator_without_common_ground_does_not_differ() {
+        assert!(!validator(Some("\"a\""), None).differs_from(&validator(None, Some(MONDAY_NOON))));
+        assert!(!validator(None, None).differs_from(&validator(Some("\"a\""), Some(MONDAY_NOON))));
+    }
+
+    #[test]
+    fn if_range_skips_weak_etags() {
+        assert_eq!(validator(Some("\"a\""), Some(MONDAY_NOON)).if_range(), Some("\"a\""));
+        assert_eq!(validator(Some("W/\"a\""), Some(MONDAY_NOON)).if_range(), Some(MONDAY_NOON));
+        assert_eq!(validator(Some("W/\"a\""), None).if_range(), None);
+        assert_eq!(validator(None, None).if_range(), None);
+    }
+}
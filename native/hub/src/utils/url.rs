@@ -1,11 +1,13 @@
 use std::time::Duration;
 use anyhow::Result;
 use reqwest::{
-    Client, Url,
-    header
+    Client, StatusCode, Url,
+    header::{self, HeaderMap, HeaderName, HeaderValue}
 };
 
-pub async fn build_browser_client() -> Client {
+use crate::utils::{logger, types::RemoteValidator};
+
+pub async fn build_browser_client() -> reqwest::Result<Client> {
     let mut headers = header::HeaderMap::new();
 
     headers.insert(
@@ -35,7 +37,6 @@ pub async fn build_browser_client() -> Client {
         .pool_idle_timeout(None)
         .tcp_keepalive(Duration::from_secs(60))
         .build()
-        .expect("Failed to build reqwest client")
 }
 
 
@@ -46,37 +47,99 @@ pub struct UrlInfo {
     pub total_size: Option<u64>,
     pub accept_ranges: bool,
     pub content_type: Option<String>,
+    pub validator: RemoteValidator,
 }
 
 pub async fn get_url_info(client: Client, url: &str) -> Result<UrlInfo> {
-    // Send HEAD request
-    let response = client.head(url).send().await?;
+    probe_url(&client, url, &HeaderMap::new()).await
+}
 
-    // Extract total size
-    let total_size = response
-        .headers()
-        .get(header::CONTENT_LENGTH)
-        .and_then(|hv| hv.to_str().ok())
-        .and_then(|s| s.parse::<u64>().ok());
+/// Finds out size, range support, type and name of `url`. Asks with `HEAD` first; when
+/// that is rejected, or leaves out the size or range support, a `GET` for the first byte
+/// fills in what is missing.
+pub async fn probe_url(client: &Client, url: &str, headers: &HeaderMap) -> Result<UrlInfo> {
+    let head = client
+        .head(url)
+        .headers(headers.clone())
+        .header(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
 
-    // Extract accept-ranges header
-    let accept_ranges = response
-        .headers()
-        .get(header::ACCEPT_RANGES)
-        .and_then(|hv| hv.to_str().ok())
-        .map(|s| s.to_ascii_lowercase().contains("bytes"))
-        .unwrap_or(false);
+    let (head_info, head_name) = match head {
+        Ok(response) => {
+            let info = info_from_headers(url, response.headers());
+            if info.total_size.is_some() && info.accept_ranges {
+                return Ok(info);
+            }
+            (Some(info), disposition_name(response.headers()))
+        }
+        Err(e) => {
+            logger::debug(&format!("HEAD {} failed, probing with a ranged GET: {:?}", url, e));
+            (None, None)
+        }
+    };
 
-    // Extract content-type
-    let content_type = response
-        .headers()
-        .get(header::CONTENT_TYPE)
-        .and_then(|hv| hv.to_str().ok())
-        .map(|s| s.to_string());
+    let probe = client
+        .get(url)
+        .headers(headers.clone())
+        .header(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"))
+        .header(header::RANGE, HeaderValue::from_static("bytes=0-0"))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+    let response = match probe {
+        Ok(response) => response,
+        Err(e) => match head_info {
+            // HEAD answered, so go with what it said
+            Some(info) => {
+                logger::debug(&format!("Ranged GET {} failed: {:?}", url, e));
+                return Ok(info);
+            }
+            None => return Err(e.into()),
+        },
+    };
 
-    // Extract filename from Content-Disposition or URL path
-    let name = response
-        .headers()
+    let mut info = info_from_headers(url, response.headers());
+    if response.status() == StatusCode::PARTIAL_CONTENT {
+        info.accept_ranges = true;
+        // "bytes 0-0/<size>", the size being "*" when unknown
+        info.total_size = response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|hv| hv.to_str().ok())
+            .and_then(|cr| cr.rsplit('/').next())
+            .and_then(|s| s.trim().parse::<u64>().ok());
+    } else {
+        // the whole body is on its way; only its headers are wanted
+        info.accept_ranges = false;
+    }
+    // dropping the response closes the connection rather than reading the body
+    drop(response);
+
+    if let Some(head_info) = head_info {
+        merge_head_info(&mut info, head_info, head_name);
+    }
+    Ok(info)
+}
+
+/// Fills in what the ranged GET left out from the `HEAD` answer. Its name only wins when
+/// it came from a `Content-Disposition`, not when it was made up from the URL.
+fn merge_head_info(info: &mut UrlInfo, head_info: UrlInfo, head_name: Option<String>) {
+    info.total_size = info.total_size.or(head_info.total_size);
+    info.content_type = head_info.content_type.or(info.content_type.take());
+    if let Some(name) = head_name {
+        info.name = name;
+    }
+    info.validator = RemoteValidator {
+        etag: head_info.validator.etag.or(info.validator.etag.take()),
+        last_modified: head_info.validator.last_modified.or(info.validator.last_modified.take()),
+    };
+}
+
+/// File name given by the `Content-Disposition` header, if any.
+fn disposition_name(headers: &HeaderMap) -> Option<String> {
+    headers
         .get(header::CONTENT_DISPOSITION)
         .and_then(|hv| hv.to_str().ok())
         .and_then(|cd| {
@@ -90,6 +153,28 @@ pub async fn get_url_info(client: Client, url: &str) -> Result<UrlInfo> {
                     }
                 })
         })
+        .filter(|name| !name.is_empty())
+}
+
+fn info_from_headers(url: &str, headers: &HeaderMap) -> UrlInfo {
+    let total_size = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok());
+
+    let accept_ranges = headers
+        .get(header::ACCEPT_RANGES)
+        .and_then(|hv| hv.to_str().ok())
+        .map(|s| s.to_ascii_lowercase().contains("bytes"))
+        .unwrap_or(false);
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|hv| hv.to_str().ok())
+        .map(|s| s.to_string());
+
+    // Extract filename from Content-Disposition or URL path
+    let name = disposition_name(headers)
         .unwrap_or_else(|| {
             // fallback: extract from URL
             let parsed = Url::parse(url).ok();
@@ -97,23 +182,41 @@ pub async fn get_url_info(client: Client, url: &str) -> Result<UrlInfo> {
                 .as_ref()
                 .and_then(|u| {
                     u.path_segments()
-                        .and_then(|segments| segments.last())
+                        .and_then(|mut segments| segments.next_back())
                         .map(|s| s.to_string())
                 })
                 .unwrap_or_else(|| "download.bin".to_string())
         });
 
-    Ok(UrlInfo {
+    let header_string = |name| headers.get(name).and_then(|hv: &HeaderValue| hv.to_str().ok()).map(str::to_string);
+    let validator = RemoteValidator {
+        etag: header_string(header::ETAG),
+        last_modified: header_string(header::LAST_MODIFIED),
+    };
+
+    UrlInfo {
         url: url.to_string(),
         name,
         total_size,
         accept_ranges,
         content_type,
-    })
+        validator,
+    }
+}
+
+/// Converts `(name, value)` pairs into a `HeaderMap`, rejecting invalid entries.
+pub fn header_map(headers: &[(String, String)]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.trim().as_bytes())?;
+        let value = HeaderValue::from_str(value.trim())?;
+        map.append(name, value);
+    }
+    Ok(map)
 }
 
 pub fn is_hls_url(url: &str, content_type: &Option<String>) -> bool {
-    url.ends_with(".m3u8") || match content_type {
+    url.split(['?', '#']).next().unwrap_or(url).ends_with(".m3u8") || match content_type {
         Some(ct) => {
             let ct_lower = ct.to_ascii_lowercase();
             ct_lower.contains("application/vnd.apple.mpegurl") || ct_lower.contains("application/x-mpegurl")
@@ -121,3 +224,57 @@ pub fn is_hls_url(url: &str, content_type: &Option<String>) -> bool {
         None => false,
     }
 }
+
+pub fn is_dash_url(url: &str, content_type: &Option<String>) -> bool {
+    url.split(['?', '#']).next().unwrap_or(url).ends_with(".mpd") || match content_type {
+        Some(ct) => ct.to_ascii_lowercase().contains("application/dash+xml"),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_manifests_behind_query_strings() {
+        assert!(is_hls_url("https://cdn.example.com/live/index.m3u8?token=abc", &None));
+        assert!(is_hls_url("https://cdn.example.com/live/index.m3u8#t=10", &None));
+        assert!(!is_hls_url("https://example.com/watch?list=index.m3u8x", &None));
+        assert!(is_dash_url("https://cdn.example.com/vod/manifest.mpd?token=abc", &None));
+    }
+
+    fn disposition(name: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_DISPOSITION, HeaderValue::from_str(&format!("attachment; filename=\"{}\"", name)).unwrap());
+        headers
+    }
+
+    #[test]
+    fn names_the_file_after_the_disposition_or_the_url() {
+        let url = "https://example.com/files/get?id=7";
+        assert_eq!(info_from_headers(url, &disposition("report.pdf")).name, "report.pdf");
+        assert_eq!(info_from_headers(url, &disposition("")).name, "get");
+        assert_eq!(info_from_headers(url, &HeaderMap::new()).name, "get");
+    }
+
+    #[test]
+    fn keeps_the_get_name_when_head_has_none() {
+        let url = "https://example.com/files/get?id=7";
+        let mut info = info_from_headers(url, &disposition("report.pdf"));
+        let head_headers = HeaderMap::new();
+        merge_head_info(&mut info, info_from_headers(url, &head_headers), disposition_name(&head_headers));
+        assert_eq!(info.name, "report.pdf");
+
+        let head_headers = disposition("annual.pdf");
+        merge_head_info(&mut info, info_from_headers(url, &head_headers), disposition_name(&head_headers));
+        assert_eq!(info.name, "annual.pdf");
+    }
+
+    #[test]
+    fn detects_manifests_by_content_type() {
+        assert!(is_hls_url("https://example.com/play", &Some("Application/X-MpegURL".to_string())));
+        assert!(is_dash_url("https://example.com/play", &Some("application/dash+xml".to_string())));
+        assert!(!is_hls_url("https://example.com/play", &Some("video/mp4".to_string())));
+    }
+}